@@ -0,0 +1,72 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use anti_virus::signatures::read_signatures;
+use anti_virus::signing::Verification;
+use anti_virus::{FileCompare, RecFileSearch};
+
+const FILE_SIZE: usize = 64 * 1024;
+const FILES_PER_DIR: usize = 10;
+
+// Builds a tree shaped like Test_env/TestDir (8 dirs of 5 subdirs each) with larger
+// files, a few of which carry a known signature somewhere in the middle.
+fn generate_tree(root: &Path, signature: &[u8]) -> usize {
+    let mut seed = 0x2545f4914f6cdd1du64;
+    let mut files = 0;
+    for a in 1..=8 {
+        for b in 1..=5 {
+            let dir = root.join(format!("SubDir{}", a)).join(format!("SubSubDir{}{}", a, b));
+            fs::create_dir_all(&dir).unwrap();
+            for f in 0..FILES_PER_DIR {
+                let mut data = Vec::with_capacity(FILE_SIZE);
+                while data.len() < FILE_SIZE {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    data.extend_from_slice(&seed.to_le_bytes());
+                }
+                if (a + b + f) % 7 == 0 {
+                    let at = FILE_SIZE / 2;
+                    data[at..at + signature.len()].copy_from_slice(signature);
+                }
+                fs::write(dir.join(format!("fil{}", f)), &data).unwrap();
+                files += 1;
+            }
+        }
+    }
+    files
+}
+
+fn scan_scaling(c: &mut Criterion) {
+    let db_path = concat!(env!("CARGO_MANIFEST_DIR"), "/Test_env/signatures.db");
+    let (signatures, _) = read_signatures(db_path, &Verification::Skipped).unwrap();
+    let signature = signatures.signatures().next().unwrap().pattern.literal_bytes().unwrap();
+    let signatures = Arc::new(signatures);
+
+    let root: PathBuf = std::env::temp_dir().join(format!("anti_virus_bench_{}", std::process::id()));
+    let files = generate_tree(&root, &signature);
+
+    let mut group = c.benchmark_group("scan_tree");
+    group.sample_size(20);
+    group.throughput(Throughput::Bytes((files * FILE_SIZE) as u64));
+    let max_threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let mut threads = 1;
+    while threads <= max_threads {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |bench, _| {
+            bench.iter(|| {
+                let tester = FileCompare::with_signatures(Arc::clone(&signatures));
+                let mut search = RecFileSearch::new(root.to_string_lossy().into_owned(), tester);
+                pool.install(|| search.start()).unwrap();
+                assert!(!search.get_tester().get_risk_files().is_empty());
+            });
+        });
+        threads *= 2;
+    }
+    group.finish();
+    fs::remove_dir_all(&root).unwrap();
+}
+
+criterion_group!(benches, scan_scaling);
+criterion_main!(benches);
@@ -0,0 +1,167 @@
+use std::io::{self, Read};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+
+// One buffer's worth of a file. The first `seen` bytes were already part of the
+// previous window and are only kept so matches spanning the boundary are found.
+pub struct Window<'a> {
+    pub data: &'a [u8],
+    pub offset: u64,
+    pub seen: usize,
+}
+
+// Reads a file in fixed-size chunks, carrying `overlap` bytes from the end of each
+// window into the next one. Memory use is bounded by `chunk_size + overlap`.
+pub struct ChunkedReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    chunk_size: usize,
+    overlap: usize,
+    offset: u64,
+    started: bool,
+}
+
+impl<R: Read> ChunkedReader<R> {
+    pub fn new(reader: R, chunk_size: usize, overlap: usize) -> ChunkedReader<R> {
+        ChunkedReader {
+            reader,
+            buffer: Vec::new(),
+            chunk_size: chunk_size.max(1),
+            overlap,
+            offset: 0,
+            started: false,
+        }
+    }
+
+    pub fn next_window(&mut self) -> io::Result<Option<Window<'_>>> {
+        if self.started {
+            let keep = self.overlap.min(self.buffer.len());
+            let drop = self.buffer.len() - keep;
+            self.buffer.drain(..drop);
+            self.offset += drop as u64;
+        }
+        self.started = true;
+        let seen = self.buffer.len();
+        let read = read_up_to(&mut self.reader, &mut self.buffer, self.chunk_size)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        Ok(Some(Window { data: &self.buffer, offset: self.offset, seen }))
+    }
+}
+
+// Appends up to `limit` bytes to `buffer`, stopping early only at end of file.
+pub fn read_up_to<R: Read>(reader: &mut R, buffer: &mut Vec<u8>, limit: usize) -> io::Result<usize> {
+    reader.take(limit as u64).read_to_end(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use super::ChunkedReader;
+    use crate::file_compare::{FileCompare, ScanOptions};
+    use crate::signatures::parse_signatures;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("anti_virus-chunked-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn scanner(database: &str, chunk_size: usize) -> FileCompare {
+        let (signatures, errors) = parse_signatures(database.as_bytes()).unwrap();
+        assert!(errors.is_empty());
+        let mut file_compare = FileCompare::with_signatures(Arc::new(signatures));
+        file_compare.set_options(ScanOptions { chunk_size, mmap_threshold: None });
+        file_compare
+    }
+
+    fn scan(file_compare: &FileCompare, path: &Path, data: &[u8]) -> Vec<(u64, String, usize)> {
+        fs::write(path, data).unwrap();
+        let detections = file_compare.scan_file(path.to_str().unwrap()).unwrap();
+        detections.into_iter().map(|d| (d.offset, d.signature, d.length)).collect()
+    }
+
+    #[test]
+    fn windows_overlap_and_cover_the_file() {
+        let data: Vec<u8> = (0..100).collect();
+        for chunk_size in [1, 3, 7, 100, 1000] {
+            for overlap in [0, 1, 5, 20] {
+                let mut reader = ChunkedReader::new(&data[..], chunk_size, overlap);
+                let mut fresh = Vec::new();
+                while let Some(window) = reader.next_window().unwrap() {
+                    let offset = window.offset as usize;
+                    assert_eq!(window.data, &data[offset..offset + window.data.len()]);
+                    assert_eq!(offset + window.seen, fresh.len());
+                    assert!(window.seen <= overlap);
+                    assert!(window.data.len() - window.seen <= chunk_size);
+                    fresh.extend_from_slice(&window.data[window.seen..]);
+                }
+                assert_eq!(fresh, data);
+            }
+        }
+        assert!(ChunkedReader::new(&[][..], 4, 4).next_window().unwrap().is_none());
+    }
+
+    #[test]
+    fn matches_across_every_boundary_are_found_once() {
+        let dir = temp_dir("boundary");
+        let path = dir.join("sample");
+        let cases: [(&str, &[u8]); 2] =
+            [("Test.Literal", b"\xde\xad\xbe\xef\xca\xfe"), ("Test.Jump", b"\x11\x22\x55\x66\x33\x44")];
+        for chunk_size in [1, 2, 3, 4, 5] {
+            let file_compare = scanner("Test.Literal=deadbeefcafe\nTest.Jump=1122{1-3}3344\n", chunk_size);
+            for (name, bytes) in cases {
+                for start in 0..=40 - bytes.len() {
+                    let mut data = vec![0u8; 40];
+                    data[start..start + bytes.len()].copy_from_slice(bytes);
+                    let found = scan(&file_compare, &path, &data);
+                    assert_eq!(found, [(start as u64, name.to_string(), 6)], "chunk size {}", chunk_size);
+                }
+            }
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn anchored_databases_only_read_both_ends() {
+        let dir = temp_dir("anchored");
+        let path = dir.join("sample");
+        let database = "Test.Head=0:4d5a9000\nTest.Shifted=8,4:c0ffee\nTest.Tail=EOF-4:aabbccdd\n";
+        let file_compare = scanner(database, 16);
+        let signatures = file_compare.get_signatures();
+        assert!(!signatures.has_floating());
+        assert_eq!((signatures.header_len(), signatures.trailer_len()), (15, 4));
+
+        let mut data = vec![0u8; 4096];
+        data[..4].copy_from_slice(b"\x4d\x5a\x90\x00");
+        data[11..14].copy_from_slice(b"\xc0\xff\xee");
+        data[4092..].copy_from_slice(b"\xaa\xbb\xcc\xdd");
+        // The same bytes away from their offsets don't count.
+        data[2000..2004].copy_from_slice(b"\x4d\x5a\x90\x00");
+        data[2010..2013].copy_from_slice(b"\xc0\xff\xee");
+        data[2020..2024].copy_from_slice(b"\xaa\xbb\xcc\xdd");
+        let expected = [
+            (0, "Test.Head".to_string(), 4),
+            (11, "Test.Shifted".to_string(), 3),
+            (4092, "Test.Tail".to_string(), 4),
+        ];
+        assert_eq!(scan(&file_compare, &path, &data), expected);
+
+        // Past its shift, or not quite at the end, an anchored match is missed.
+        data[11..14].fill(0);
+        data[13..16].copy_from_slice(b"\xc0\xff\xee");
+        data[4091..4095].copy_from_slice(b"\xaa\xbb\xcc\xdd");
+        assert_eq!(scan(&file_compare, &path, &data), expected[..1]);
+
+        // Files no longer than header and trailer together are scanned whole.
+        let small = b"\x4d\x5a\x90\x00\xaa\xbb\xcc\xdd";
+        assert_eq!(scan(&file_compare, &path, small), [(0, "Test.Head".to_string(), 4), (4, "Test.Tail".to_string(), 4)]);
+        assert!(scan(&file_compare, &path, b"\xaa\xbb\xcc").is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
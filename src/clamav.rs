@@ -0,0 +1,401 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use crate::hash_signatures::{parse_hash_signatures, HashSignature};
+use crate::logical::{Expression, LogicalSignature};
+use crate::pattern::Pattern;
+use crate::signatures::{Anchor, DbError, Signature};
+
+// The plain-text ClamAV database formats, told apart by file extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClamavFormat {
+    // `.hdb` and `.hsb`: `hash:size:name`.
+    Hash,
+    // `.ndb`: `name:target:offset:hex`.
+    Body,
+    // `.ldb`: `name;target block;expression;sub0;sub1;...`.
+    Logical,
+}
+
+impl ClamavFormat {
+    pub fn from_path(path: &str) -> Option<ClamavFormat> {
+        match Path::new(path).extension()?.to_str()? {
+            "hdb" | "hsb" => Some(ClamavFormat::Hash),
+            "ndb" => Some(ClamavFormat::Body),
+            "ldb" => Some(ClamavFormat::Logical),
+            _ => None,
+        }
+    }
+}
+
+// A ClamAV database translated into the scanner's own signatures.
+#[derive(Default)]
+pub struct ClamavDatabase {
+    pub signatures: Vec<Signature>,
+    pub logical: Vec<LogicalSignature>,
+    pub hashes: Vec<HashSignature>,
+    // Lines left out because they use constructs the scanner can't match.
+    pub skipped: Vec<DbError>,
+    // Lines loaded in a looser form than written, e.g. without their file type
+    // check, so they may match more than ClamAV would.
+    pub downgraded: Vec<DbError>,
+}
+
+impl ClamavDatabase {
+    // Signatures loaded, downgraded ones included.
+    pub fn loaded(&self) -> usize {
+        self.signatures.len() + self.logical.len() + self.hashes.len()
+    }
+
+    // Writes the translated signatures as signature database lines, logical
+    // ones as `name=expression;sub0;sub1;...`. Hash signatures are left out:
+    // `--hash-db` reads the ClamAV file as it is.
+    pub fn write_signatures<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for signature in &self.signatures {
+            writeln!(writer, "{}", signature)?;
+        }
+        for signature in &self.logical {
+            let parts: Vec<String> = signature.subsignatures.iter().map(Signature::definition).collect();
+            writeln!(writer, "{}={};{}", signature.name, signature.condition, parts.join(";"))?;
+        }
+        Ok(())
+    }
+}
+
+enum Entry {
+    Plain(Signature),
+    Logical(LogicalSignature),
+}
+
+// File types are only known to ClamAV's parsers, so a signature for one is
+// checked against every file.
+fn check_target(text: &str, notes: &mut Vec<String>) -> Result<(), String> {
+    match text.trim() {
+        "0" | "*" => Ok(()),
+        target if target.parse::<u32>().is_ok() => {
+            notes.push(format!("target type {} is not checked", target));
+            Ok(())
+        }
+        target => Err(format!("invalid target type {}", target)),
+    }
+}
+
+// Offsets relative to the entry point or to sections need an executable
+// parser; such signatures are matched anywhere instead.
+fn parse_offset(text: &str, notes: &mut Vec<String>) -> Result<Anchor, String> {
+    let text = text.trim();
+    match Anchor::parse(text) {
+        Ok(anchor) => Ok(anchor),
+        Err(_) if text.starts_with("EP") || text.starts_with('S') || text == "VI" => {
+            notes.push(format!("offset {} is matched anywhere", text));
+            Ok(Anchor::Anywhere)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Splits a body at its unbounded jumps, `*` and `{n-}`, into patterns that must
+// match in this order. `{n-}` loses its minimum distance, and word and line
+// boundary markers are dropped.
+fn split_body(body: &str, notes: &mut Vec<String>) -> Result<Vec<Pattern>, String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut i = 0;
+    while i < body.len() {
+        match body.as_bytes()[i] {
+            b'*' => {
+                parts.push(std::mem::take(&mut current));
+                i += 1;
+            }
+            open @ (b'{' | b'[' | b'(') => {
+                let close = match open {
+                    b'{' => '}',
+                    b'[' => ']',
+                    _ => ')',
+                };
+                let end = body[i..].find(close).ok_or_else(|| format!("missing '{}'", close))? + i;
+                let inner = &body[i + 1..end];
+                match open {
+                    b'{' if inner.ends_with('-') => {
+                        notes.push(format!("jump {{{}}} is matched as `*`", inner));
+                        parts.push(std::mem::take(&mut current));
+                    }
+                    // ClamAV's anchored byte ranges behave like bounded jumps here.
+                    b'{' | b'[' => current.push_str(&format!("{{{}}}", inner)),
+                    _ if matches!(inner, "B" | "L" | "W") => notes.push(format!("boundary ({}) is dropped", inner)),
+                    _ => current.push_str(&body[i..=end]),
+                }
+                i = end + 1;
+            }
+            b'!' => return Err("negated alternatives are not supported".to_string()),
+            _ => {
+                let len = body[i..].chars().next().map_or(1, char::len_utf8);
+                current.push_str(&body[i..i + len]);
+                i += len;
+            }
+        }
+    }
+    parts.push(current);
+    parts.iter().map(|part| Pattern::parse(part).map_err(|e| format!("invalid pattern: {}", e))).collect()
+}
+
+// One signature, or a logical one whose parts must match in order when the
+// body had unbounded jumps. Only the first part keeps the offset.
+fn build(name: &str, anchor: Anchor, parts: Vec<Pattern>) -> Result<Entry, String> {
+    let mut subsignatures: Vec<Signature> = parts
+        .into_iter()
+        .map(|pattern| Signature { name: name.to_string(), pattern, anchor: Anchor::Anywhere })
+        .collect();
+    subsignatures[0].anchor = anchor;
+    if subsignatures.len() == 1 {
+        return Ok(Entry::Plain(subsignatures.remove(0)));
+    }
+    let expression = Expression::Ordered((0..subsignatures.len()).collect());
+    LogicalSignature::new(name.to_string(), expression, subsignatures).map(Entry::Logical)
+}
+
+fn parse_ndb_line(line: &str, notes: &mut Vec<String>) -> Result<Entry, String> {
+    let fields: Vec<&str> = line.split(':').collect();
+    let [name, target, offset, body, ..] = fields[..] else {
+        return Err("expected name:target:offset:hex".to_string());
+    };
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("missing signature name".to_string());
+    }
+    check_target(target, notes)?;
+    let anchor = parse_offset(offset, notes)?;
+    let parts = split_body(body.trim(), notes)?;
+    build(name, anchor, parts)
+}
+
+// `[offset:]hex[::modifiers]`; two patterns for `::wa`, which matches either form.
+fn parse_subsignature(text: &str, notes: &mut Vec<String>) -> Result<(Anchor, Vec<Pattern>), String> {
+    let (body, modifiers) = text.trim().split_once("::").unwrap_or((text.trim(), ""));
+    if body.contains('/') {
+        return Err("PCRE sub-signatures are not supported".to_string());
+    }
+    if body.starts_with('$') {
+        return Err("macro sub-signatures are not supported".to_string());
+    }
+    if body.contains('#') {
+        return Err("byte compare sub-signatures are not supported".to_string());
+    }
+    let (anchor, hex) = match body.rsplit_once(':') {
+        Some((offset, hex)) => (parse_offset(offset, notes)?, hex),
+        None => (Anchor::Anywhere, body),
+    };
+    let mut parts = split_body(hex, notes)?;
+    if parts.len() > 1 {
+        return Err("unbounded jumps are not supported in sub-signatures".to_string());
+    }
+    let mut pattern = parts.remove(0);
+    let (mut wide, mut ascii) = (false, false);
+    for modifier in modifiers.chars() {
+        match modifier {
+            'i' => pattern = pattern.ignore_case(),
+            'w' => wide = true,
+            'a' => ascii = true,
+            'f' => notes.push("fullword is not checked".to_string()),
+            other => return Err(format!("unknown modifier {}", other)),
+        }
+    }
+    let mut patterns = Vec::new();
+    if wide {
+        patterns.push(pattern.wide()?);
+    }
+    if ascii || !wide {
+        patterns.push(pattern);
+    }
+    Ok((anchor, patterns))
+}
+
+fn check_target_block(text: &str, notes: &mut Vec<String>) -> Result<(), String> {
+    for entry in text.split(',') {
+        let (key, value) = entry.split_once(':').ok_or_else(|| format!("invalid target description {}", entry))?;
+        match key.trim() {
+            "Target" => check_target(value, notes)?,
+            // Functionality levels only gate old ClamAV engines.
+            "Engine" => {}
+            key => notes.push(format!("{} is not checked", key)),
+        }
+    }
+    Ok(())
+}
+
+fn parse_ldb_line(line: &str, notes: &mut Vec<String>) -> Result<Entry, String> {
+    let fields: Vec<&str> = line.split(';').collect();
+    let [name, target_block, expression, ref parts @ ..] = fields[..] else {
+        return Err("expected name;target block;expression;sub-signatures".to_string());
+    };
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("missing signature name".to_string());
+    }
+    if parts.is_empty() {
+        return Err("no sub-signatures".to_string());
+    }
+    check_target_block(target_block, notes)?;
+    let mut expression = Expression::parse(expression).map_err(|e| format!("invalid expression: {}", e))?;
+    let mut subsignatures = Vec::new();
+    let mut extra = Vec::new();
+    for (index, part) in parts.iter().enumerate() {
+        let (anchor, patterns) = parse_subsignature(part, notes).map_err(|e| format!("sub-signature {}: {}", index, e))?;
+        let mut patterns = patterns.into_iter();
+        let pattern = patterns.next().expect("at least one pattern");
+        subsignatures.push(Signature { name: name.to_string(), pattern, anchor });
+        extra.extend(patterns.map(|pattern| (index, Signature { name: name.to_string(), pattern, anchor })));
+    }
+    // Second forms go after the written sub-signatures so their indices stay put.
+    for (index, signature) in extra {
+        expression = expression
+            .merge_part(index, subsignatures.len())
+            .ok_or_else(|| format!("sub-signature {}: wide and ascii can't be ordered", index))?;
+        subsignatures.push(signature);
+    }
+    LogicalSignature::new(name.to_string(), expression, subsignatures).map(Entry::Logical)
+}
+
+// Translates a ClamAV database. Blank lines and `#` comments are ignored; every
+// other line is loaded, loaded with a note in `downgraded`, or left out with
+// the reason in `skipped`.
+pub fn parse_clamav_database<R: BufRead>(reader: R, format: ClamavFormat) -> io::Result<ClamavDatabase> {
+    let mut database = ClamavDatabase::default();
+    if format == ClamavFormat::Hash {
+        let (hashes, errors) = parse_hash_signatures(reader)?;
+        database.hashes = hashes;
+        database.skipped = errors;
+        return Ok(database);
+    }
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let mut notes = Vec::new();
+        let entry = match format {
+            ClamavFormat::Body => parse_ndb_line(trimmed, &mut notes),
+            _ => parse_ldb_line(trimmed, &mut notes),
+        };
+        let line = index + 1;
+        match entry {
+            Ok(entry) => {
+                if !notes.is_empty() {
+                    let name = match &entry {
+                        Entry::Plain(signature) => &signature.name,
+                        Entry::Logical(signature) => &signature.name,
+                    };
+                    database.downgraded.push(DbError { line, message: format!("{}: {}", name, notes.join(", ")) });
+                }
+                match entry {
+                    Entry::Plain(signature) => database.signatures.push(signature),
+                    Entry::Logical(signature) => database.logical.push(signature),
+                }
+            }
+            Err(message) => {
+                let name = trimmed.split([':', ';']).next().unwrap_or("");
+                database.skipped.push(DbError { line, message: format!("{}: {}", name, message) });
+            }
+        }
+    }
+    Ok(database)
+}
+
+pub fn read_clamav_database(path: &str) -> io::Result<ClamavDatabase> {
+    let format = ClamavFormat::from_path(path).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "not a ClamAV .hdb, .hsb, .ndb or .ldb database")
+    })?;
+    let file = File::open(path)?;
+    parse_clamav_database(BufReader::new(file), format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_clamav_database, ClamavDatabase, ClamavFormat};
+    use crate::logical::{Condition, Expression};
+    use crate::signatures::{parse_signatures, Anchor};
+
+    fn parse(text: &str, format: ClamavFormat) -> ClamavDatabase {
+        parse_clamav_database(text.as_bytes(), format).unwrap()
+    }
+
+    #[test]
+    fn formats_by_extension() {
+        assert_eq!(ClamavFormat::from_path("main.hsb"), Some(ClamavFormat::Hash));
+        assert_eq!(ClamavFormat::from_path("dir/daily.ndb"), Some(ClamavFormat::Body));
+        assert_eq!(ClamavFormat::from_path("x.ldb"), Some(ClamavFormat::Logical));
+        assert_eq!(ClamavFormat::from_path("x.cvd"), None);
+    }
+
+    #[test]
+    fn body_signatures() {
+        let database = parse(
+            "# comment\n\
+             Plain:0:*:dead\n\
+             Anchored:0:EOF-10:beef\n\
+             Split:1:*:dead*beef{5-}cafe\n\
+             Entry:0:EP+0:dead\n\
+             Negated:0:*:de!(ad)\n\
+             Short:0\n",
+            ClamavFormat::Body,
+        );
+        let names: Vec<&str> = database.signatures.iter().map(|signature| signature.name.as_str()).collect();
+        assert_eq!(names, ["Plain", "Anchored", "Entry"]);
+        assert_eq!(database.signatures[1].anchor, Anchor::FromEnd { distance: 10, shift: 0 });
+        assert_eq!(database.signatures[2].anchor, Anchor::Anywhere);
+        // Unbounded jumps become sub-signatures that must match in order.
+        let split = &database.logical[0];
+        assert_eq!(split.subsignatures.len(), 3);
+        assert!(matches!(&split.condition, Condition::Expression(Expression::Ordered(parts)) if parts == &[0, 1, 2]));
+        let downgraded: Vec<usize> = database.downgraded.iter().map(|error| error.line).collect();
+        assert_eq!(downgraded, [4, 5]);
+        let skipped: Vec<usize> = database.skipped.iter().map(|error| error.line).collect();
+        assert_eq!(skipped, [6, 7]);
+        assert_eq!(database.loaded(), 4);
+    }
+
+    #[test]
+    fn logical_signatures() {
+        let database = parse(
+            "Both;Engine:51-255,Target:0;0&1;dead;6869::wa\n\
+             Sized;Target:0,FileSize:0-100;0;beef\n\
+             Pcre;Target:0;0;/abc/\n\
+             Missing;Target:0;0&1;dead\n",
+            ClamavFormat::Logical,
+        );
+        let names: Vec<&str> = database.logical.iter().map(|signature| signature.name.as_str()).collect();
+        assert_eq!(names, ["Both", "Sized"]);
+        // `::wa` adds the wide form as an extra sub-signature counted with the first.
+        let both = &database.logical[0];
+        assert_eq!(both.subsignatures.len(), 3);
+        assert_eq!(both.condition.to_string(), "0&(1|2)");
+        assert_eq!(database.downgraded.len(), 1);
+        assert_eq!(database.skipped.len(), 2);
+    }
+
+    #[test]
+    fn hash_signatures() {
+        let database = parse(
+            "44d88612fea8a8f36de82e1278abb02f:68:Eicar\nnot a line\n",
+            ClamavFormat::Hash,
+        );
+        assert_eq!(database.hashes.len(), 1);
+        assert_eq!(database.hashes[0].size, Some(68));
+        assert_eq!(database.skipped.len(), 1);
+    }
+
+    #[test]
+    fn written_signatures_load_back() {
+        let database = parse("Plain:0:10:dead??ef\nSplit:0:*:dead*beef\n", ClamavFormat::Body);
+        let mut written = Vec::new();
+        database.write_signatures(&mut written).unwrap();
+        let (set, errors) = parse_signatures(&written[..]).unwrap();
+        assert!(errors.is_empty(), "{:?}", errors);
+        let plain: Vec<String> = set.signatures().map(ToString::to_string).collect();
+        assert_eq!(plain, ["Plain=10:dead??ef"]);
+        assert_eq!(set.logical().len(), 1);
+        assert_eq!(set.logical()[0].condition.to_string(), "0->1");
+    }
+}
@@ -0,0 +1,504 @@
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{self, BufWriter, Read, Write};
+use std::mem;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::UNIX_EPOCH;
+
+use fnv::FnvHasher;
+use memmap2::Mmap;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::signatures::{parse_line, read_source_signatures, DbError, Entry, Signature, SignatureSet};
+use crate::signing::Verification;
+
+const MAGIC: &[u8; 8] = b"AVDBBIN\0";
+// Newest compiled format this scanner reads and the one it writes.
+pub const COMPILED_FORMAT: u32 = 1;
+// Magic, format, section count and the checksum of the section table.
+const HEADER_LEN: usize = 24;
+// Offset, length and checksum of a section.
+const ENTRY_LEN: usize = 24;
+// Sections start on this boundary so mapped tables are aligned.
+const ALIGN: usize = 8;
+// Decoded plain signatures are cached in chunks of this many, allocated on
+// first use.
+const DECODE_CHUNK: usize = 1024;
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+// Element types of tables in a compiled database: integers stored little-endian,
+// valid for any bit pattern.
+pub(crate) trait Plain: Copy + Send + Sync + 'static {
+    fn read_le(bytes: &[u8]) -> Self;
+    fn write_le(self, out: &mut Vec<u8>);
+}
+
+macro_rules! plain {
+    ($($ty:ty),*) => {$(
+        impl Plain for $ty {
+            fn read_le(bytes: &[u8]) -> $ty {
+                <$ty>::from_le_bytes(bytes.try_into().expect("element sized slice"))
+            }
+
+            fn write_le(self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+        }
+    )*};
+}
+
+plain!(u8, u32, u64);
+
+// A read-only array that either owns its elements or points straight into a
+// mapped compiled database, which it keeps mapped.
+pub(crate) struct Table<T: Plain> {
+    ptr: *const T,
+    len: usize,
+    _values: Vec<T>,
+    _map: Option<Arc<Mmap>>,
+}
+
+// SAFETY: the elements are never written through `ptr`, and what they live in
+// moves with the table.
+unsafe impl<T: Plain> Send for Table<T> {}
+unsafe impl<T: Plain> Sync for Table<T> {}
+
+impl<T: Plain> From<Vec<T>> for Table<T> {
+    fn from(values: Vec<T>) -> Table<T> {
+        Table { ptr: values.as_ptr(), len: values.len(), _values: values, _map: None }
+    }
+}
+
+impl<T: Plain> Deref for Table<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        // SAFETY: `ptr` and `len` describe either the buffer of `_values` or an
+        // aligned, bounds-checked range of `_map`.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+// Collects the sections of a compiled database in the order they are read back.
+#[derive(Default)]
+pub(crate) struct SectionWriter {
+    sections: Vec<Vec<u8>>,
+}
+
+impl SectionWriter {
+    pub(crate) fn table<T: Plain>(&mut self, values: &[T]) {
+        let mut bytes = Vec::with_capacity(mem::size_of_val(values));
+        for &value in values {
+            value.write_le(&mut bytes);
+        }
+        self.sections.push(bytes);
+    }
+
+    pub(crate) fn bytes(&mut self, bytes: Vec<u8>) {
+        self.sections.push(bytes);
+    }
+
+    pub(crate) fn json<S: Serialize>(&mut self, value: &S) -> io::Result<()> {
+        self.sections.push(serde_json::to_vec(value)?);
+        Ok(())
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let table_len = self.sections.len() * ENTRY_LEN;
+        let mut offset = (HEADER_LEN + table_len).next_multiple_of(ALIGN);
+        let mut table = Vec::with_capacity(table_len);
+        for section in &self.sections {
+            table.extend_from_slice(&(offset as u64).to_le_bytes());
+            table.extend_from_slice(&(section.len() as u64).to_le_bytes());
+            table.extend_from_slice(&checksum(section).to_le_bytes());
+            offset = (offset + section.len()).next_multiple_of(ALIGN);
+        }
+        writer.write_all(MAGIC)?;
+        writer.write_all(&COMPILED_FORMAT.to_le_bytes())?;
+        writer.write_all(&(self.sections.len() as u32).to_le_bytes())?;
+        writer.write_all(&checksum(&table).to_le_bytes())?;
+        writer.write_all(&table)?;
+        let mut written = HEADER_LEN + table_len;
+        for section in &self.sections {
+            let padding = written.next_multiple_of(ALIGN) - written;
+            writer.write_all(&[0; ALIGN][..padding])?;
+            writer.write_all(section)?;
+            written += padding + section.len();
+        }
+        Ok(())
+    }
+}
+
+struct Section {
+    offset: usize,
+    len: usize,
+    checksum: u64,
+}
+
+// Reads back the sections of a mapped compiled database in order. Loading
+// checks every section against its checksum first with `verify`, so a damaged
+// database is refused instead of failing in the middle of a scan.
+pub(crate) struct SectionReader {
+    map: Arc<Mmap>,
+    sections: Vec<Section>,
+    next: usize,
+}
+
+impl SectionReader {
+    fn open(path: &str) -> io::Result<SectionReader> {
+        let file = File::open(path)?;
+        // SAFETY: the map is read-only. A database rewritten in place while
+        // mapped is the usual mmap caveat; `db compile` replaces it instead.
+        let map = unsafe { Mmap::map(&file)? };
+        if map.len() < HEADER_LEN || &map[..8] != MAGIC {
+            return Err(invalid("not a compiled signature database"));
+        }
+        let word = |at: usize| u32::from_le_bytes(map[at..at + 4].try_into().unwrap());
+        let format = word(8);
+        if format != COMPILED_FORMAT {
+            return Err(invalid(format!(
+                "compiled database format {} is not supported, expected {}; recompile it",
+                format, COMPILED_FORMAT
+            )));
+        }
+        let count = word(12) as usize;
+        let table_end = count
+            .checked_mul(ENTRY_LEN)
+            .and_then(|len| len.checked_add(HEADER_LEN))
+            .filter(|&end| end <= map.len())
+            .ok_or_else(|| invalid("truncated section table"))?;
+        let table = &map[HEADER_LEN..table_end];
+        if checksum(table) != u64::from_le_bytes(map[16..24].try_into().unwrap()) {
+            return Err(invalid("section table checksum mismatch"));
+        }
+        let mut sections = Vec::with_capacity(count);
+        for entry in table.chunks_exact(ENTRY_LEN) {
+            let field = |at: usize| u64::from_le_bytes(entry[at..at + 8].try_into().unwrap());
+            let (offset, len) = (field(0) as usize, field(8) as usize);
+            if offset % ALIGN != 0 || offset.checked_add(len).is_none_or(|end| end > map.len()) {
+                return Err(invalid("section out of bounds"));
+            }
+            sections.push(Section { offset, len, checksum: field(16) });
+        }
+        Ok(SectionReader { map: Arc::new(map), sections, next: 0 })
+    }
+
+    fn next_section(&mut self) -> io::Result<&Section> {
+        let section = self.sections.get(self.next).ok_or_else(|| invalid("missing section"))?;
+        self.next += 1;
+        Ok(section)
+    }
+
+    pub(crate) fn table<T: Plain>(&mut self) -> io::Result<Table<T>> {
+        let map = Arc::clone(&self.map);
+        let section = self.next_section()?;
+        let size = mem::size_of::<T>();
+        if section.len % size != 0 {
+            return Err(invalid("table length is not a whole number of elements"));
+        }
+        let bytes = &map[section.offset..section.offset + section.len];
+        let aligned = bytes.as_ptr().align_offset(mem::align_of::<T>()) == 0;
+        if cfg!(target_endian = "little") && aligned {
+            let ptr = bytes.as_ptr().cast();
+            Ok(Table { ptr, len: section.len / size, _values: Vec::new(), _map: Some(map) })
+        } else {
+            Ok(bytes.chunks_exact(size).map(T::read_le).collect::<Vec<T>>().into())
+        }
+    }
+
+    pub(crate) fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let map = Arc::clone(&self.map);
+        let section = self.next_section()?;
+        let bytes = &map[section.offset..section.offset + section.len];
+        if checksum(bytes) != section.checksum {
+            return Err(invalid("section checksum mismatch"));
+        }
+        Ok(bytes.to_vec())
+    }
+
+    pub(crate) fn json<D: DeserializeOwned>(&mut self) -> io::Result<D> {
+        serde_json::from_slice(&self.bytes()?).map_err(|e| invalid(e.to_string()))
+    }
+
+    fn verify(&self) -> io::Result<()> {
+        for (index, section) in self.sections.iter().enumerate() {
+            if checksum(&self.map[section.offset..section.offset + section.len]) != section.checksum {
+                return Err(invalid(format!("section {} checksum mismatch", index)));
+            }
+        }
+        Ok(())
+    }
+}
+
+// A decoded record, `None` if it doesn't parse.
+type Decoded = OnceLock<Option<Signature>>;
+
+// Plain signatures of a compiled database, kept as their `name=...` lines and
+// parsed the first time a scan needs one.
+pub(crate) struct CompiledSignatures {
+    // Start of each record in `records`, plus the end of the last.
+    offsets: Table<u64>,
+    records: Table<u8>,
+    decoded: Vec<OnceLock<Box<[Decoded]>>>,
+}
+
+impl CompiledSignatures {
+    pub(crate) fn write<'a>(signatures: impl Iterator<Item = &'a Signature>, writer: &mut SectionWriter) {
+        let mut offsets = vec![0u64];
+        let mut records = Vec::new();
+        for signature in signatures {
+            records.extend_from_slice(signature.to_string().as_bytes());
+            offsets.push(records.len() as u64);
+        }
+        writer.table(&offsets);
+        writer.bytes(records);
+    }
+
+    pub(crate) fn read(reader: &mut SectionReader) -> io::Result<CompiledSignatures> {
+        let offsets: Table<u64> = reader.table()?;
+        let records: Table<u8> = reader.table()?;
+        let ordered = offsets.windows(2).all(|pair| pair[0] <= pair[1]);
+        if !ordered || offsets.first() != Some(&0) || offsets.last().is_none_or(|&end| end as usize != records.len()) {
+            return Err(invalid("signature records out of bounds"));
+        }
+        let len = offsets.len() - 1;
+        let decoded = (0..len.div_ceil(DECODE_CHUNK)).map(|_| OnceLock::new()).collect();
+        Ok(CompiledSignatures { offsets, records, decoded })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    // A record that passed the checksums but doesn't parse could only come from
+    // a crafted database; its signature is left out rather than failing a scan.
+    pub(crate) fn get(&self, id: usize) -> Option<&Signature> {
+        let chunk = self.decoded[id / DECODE_CHUNK]
+            .get_or_init(|| (0..DECODE_CHUNK).map(|_| OnceLock::new()).collect());
+        chunk[id % DECODE_CHUNK]
+            .get_or_init(|| {
+                let record = &self.records[self.offsets[id] as usize..self.offsets[id + 1] as usize];
+                let entry = std::str::from_utf8(record).map_err(|e| e.to_string()).and_then(parse_line);
+                match entry {
+                    Ok(Entry::Plain(signature)) => Some(signature),
+                    _ => None,
+                }
+            })
+            .as_ref()
+    }
+}
+
+// When and from what a database was compiled, to notice a source edited since.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct SourceStamp {
+    len: u64,
+    modified_ns: u128,
+}
+
+impl SourceStamp {
+    fn of(path: &Path) -> io::Result<SourceStamp> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Ok(SourceStamp { len: metadata.len(), modified_ns: modified.as_nanos() })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompiledInfo {
+    source: Option<SourceStamp>,
+    // Lines of the source that didn't load, as `(line, message)`.
+    errors: Vec<(usize, String)>,
+    // Lines of the source that replaced an earlier definition, the same way.
+    #[serde(default)]
+    redefined: Vec<(usize, String)>,
+}
+
+// Where `db compile` puts the compiled form of `source` by default, and where
+// the scanner looks for it.
+pub fn compiled_path(source: &str) -> PathBuf {
+    Path::new(source).with_extension("avdb")
+}
+
+pub fn is_compiled(path: &str) -> bool {
+    let mut magic = [0; 8];
+    File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && &magic == MAGIC
+}
+
+// Compiles the database at `source` into `output`, replacing it atomically.
+// Returns the number of signatures and the lines that didn't load, which are
+// also kept in the compiled database.
+pub fn compile_database(source: &str, output: &Path) -> io::Result<(usize, Vec<DbError>)> {
+    if is_compiled(source) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "already compiled"));
+    }
+    let (signatures, errors) = read_source_signatures(source)?;
+    let info = CompiledInfo {
+        source: Some(SourceStamp::of(Path::new(source))?),
+        errors: errors.iter().map(|error| (error.line, error.message.clone())).collect(),
+        redefined: signatures.redefined().iter().map(|error| (error.line, error.message.clone())).collect(),
+    };
+    let mut writer = SectionWriter::default();
+    writer.json(&info)?;
+    signatures.write_sections(&mut writer)?;
+
+    let mut temporary = output.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let mut file = BufWriter::new(File::create(&temporary)?);
+    writer.write_to(&mut file)?;
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&temporary, output)?;
+    Ok((signatures.len(), errors))
+}
+
+// Loads a compiled database, refusing it if `source` is given and has changed
+// since it was compiled, or if any section fails its checksum. The signature
+// check covers the mapped bytes the database is then read from.
+fn load(path: &str, source: Option<&Path>, verification: &Verification) -> io::Result<(SignatureSet, Vec<DbError>)> {
+    let mut reader = SectionReader::open(path)?;
+    verification.check(path, &reader.map)?;
+    reader.verify()?;
+    let info: CompiledInfo = reader.json()?;
+    if let Some(source) = source {
+        if info.source != Some(SourceStamp::of(source)?) {
+            return Err(invalid("compiled from an older version of the source"));
+        }
+    }
+    let redefined = info.redefined.into_iter().map(|(line, message)| DbError { line, message }).collect();
+    let signatures = SignatureSet::read_sections(&mut reader)?.with_redefined(redefined);
+    let errors = info.errors.into_iter().map(|(line, message)| DbError { line, message }).collect();
+    Ok((signatures, errors))
+}
+
+pub fn read_compiled(path: &str, verification: &Verification) -> io::Result<(SignatureSet, Vec<DbError>)> {
+    load(path, None, verification)
+}
+
+// Checks every section of a compiled database against its checksum, including
+// the tables a scan maps without reading.
+pub fn verify_compiled(path: &str) -> io::Result<()> {
+    SectionReader::open(path)?.verify()
+}
+
+// The compiled form next to `source`, if there is one compiled from the source
+// as it is now. Anything else, including a compiled database that doesn't
+// load or isn't signed, means falling back to the source.
+pub(crate) fn read_fresh_compiled(source: &str, verification: &Verification) -> Option<(SignatureSet, Vec<DbError>)> {
+    let path = compiled_path(source);
+    if !path.is_file() {
+        return None;
+    }
+    load(path.to_str()?, Some(Path::new(source)), verification).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::{compile_database, compiled_path, read_compiled, read_fresh_compiled, verify_compiled};
+    use crate::signatures::{read_source_signatures, SignatureSet};
+    use crate::signing::Verification;
+
+    const SOURCE: &str = "\
+Plain=deadbeef
+Anchored=EOF-4:cafe??ba
+Masked=4d5a{2-4}(9090|cccc)
+Both=0&1;6869;7468657265
+Broken=zz
+";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("anti_virus-compiled-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn compile(dir: &Path) -> (String, String) {
+        let source = dir.join("signatures.db");
+        fs::write(&source, SOURCE).unwrap();
+        let source = source.to_str().unwrap().to_string();
+        let output = compiled_path(&source);
+        compile_database(&source, &output).unwrap();
+        (source, output.to_str().unwrap().to_string())
+    }
+
+    fn matches(set: &SignatureSet, data: &[u8]) -> Vec<String> {
+        let size = data.len() as u64;
+        let mut logical = set.logical_matches(size);
+        let mut found = Vec::new();
+        set.scan(data, 0, size, &mut logical, |signature, start, _| found.push(format!("{}@{}", signature.name, start)));
+        let mut detections = Vec::new();
+        logical.finish(&mut detections);
+        found.extend(detections.iter().map(|detection| format!("{}@{}", detection.signature, detection.offset)));
+        found.sort();
+        found
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = temp_dir("round-trip");
+        let (source, compiled) = compile(&dir);
+        let (expected, expected_errors) = read_source_signatures(&source).unwrap();
+        let (loaded, errors) = read_compiled(&compiled, &Verification::Skipped).unwrap();
+        assert_eq!(loaded.len(), expected.len());
+        let lines: Vec<(usize, &str)> = errors.iter().map(|error| (error.line, error.message.as_str())).collect();
+        let expected_lines: Vec<(usize, &str)> =
+            expected_errors.iter().map(|error| (error.line, error.message.as_str())).collect();
+        assert_eq!(lines, expected_lines);
+        let data = b"\xde\xad\xbe\xef MZ\0\0\x90\x90 hi there \xca\xfe\x00\xba";
+        assert_eq!(matches(&loaded, data), matches(&expected, data));
+        assert_eq!(matches(&loaded, data).len(), 4);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_bytes_are_refused() {
+        let dir = temp_dir("damaged");
+        let (_, compiled) = compile(&dir);
+        let bytes = fs::read(&compiled).unwrap();
+        let (original, _) = read_compiled(&compiled, &Verification::Skipped).unwrap();
+        let data = b"\xde\xad\xbe\xef MZ\0\0\xcc\xcc hi there";
+        // Every byte is either covered by a checksum or is padding that is never read.
+        for at in 0..bytes.len() {
+            let mut damaged = bytes.clone();
+            damaged[at] ^= 0x41;
+            fs::write(&compiled, &damaged).unwrap();
+            match read_compiled(&compiled, &Verification::Skipped) {
+                Ok((set, _)) => {
+                    assert!(verify_compiled(&compiled).is_ok(), "byte {}", at);
+                    assert_eq!(matches(&set, data), matches(&original, data), "byte {}", at);
+                }
+                Err(error) => {
+                    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData, "byte {}", at);
+                    assert!(verify_compiled(&compiled).is_err(), "byte {}", at);
+                }
+            }
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stale_compiled_database_is_ignored() {
+        let dir = temp_dir("stale");
+        let (source, _) = compile(&dir);
+        assert!(read_fresh_compiled(&source, &Verification::Skipped).is_some());
+        fs::write(&source, format!("{}Added=0102\n", SOURCE)).unwrap();
+        assert!(read_fresh_compiled(&source, &Verification::Skipped).is_none());
+        assert!(compile_database(&compiled_path(&source).to_string_lossy(), &dir.join("again.avdb")).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+
+use fnv::{FnvHashMap, FnvHashSet};
+
+use crate::clamav::{parse_clamav_database, ClamavFormat};
+use crate::fuzzy::{parse_fuzzy_signatures, FuzzySignature};
+use crate::hash_signatures::{parse_hash_signatures, HashSignature};
+use crate::signatures::{read_signatures, redefinition, DbError, Entries, Entry, SignatureSet};
+use crate::signing::{is_untrusted, untrusted, Verification};
+use crate::yara::parse_yara_rules;
+
+// What a file in a database directory holds, by extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    // `.db`, `.toml`, `.json` and compiled `.avdb`.
+    Signatures,
+    // `.hdb` and `.hsb`.
+    Hashes,
+    // `.fdb`.
+    Fuzzy,
+    // `.yar` and `.yara`.
+    Yara,
+    // `.ndb` and `.ldb`.
+    Clamav(ClamavFormat),
+    // `.ign`: names of signatures not to load, one per line.
+    Disabled,
+}
+
+fn kind_of(path: &Path) -> Option<Kind> {
+    match path.extension()?.to_str()? {
+        "db" | "toml" | "json" | "avdb" => Some(Kind::Signatures),
+        "hdb" | "hsb" => Some(Kind::Hashes),
+        "fdb" => Some(Kind::Fuzzy),
+        "yar" | "yara" => Some(Kind::Yara),
+        "ndb" => Some(Kind::Clamav(ClamavFormat::Body)),
+        "ldb" => Some(Kind::Clamav(ClamavFormat::Logical)),
+        "ign" => Some(Kind::Disabled),
+        _ => None,
+    }
+}
+
+// Local overrides, `local.<ext>` or `<name>.local.<ext>`, load after
+// everything else.
+fn is_local(path: &Path) -> bool {
+    path.file_stem().and_then(|stem| stem.to_str()).is_some_and(|stem| stem == "local" || stem.ends_with(".local"))
+}
+
+// The databases in `dir` in the order they load: by file name, local overrides
+// last. Hidden files are left out, and so are compiled databases with their
+// source next to them, which `read_signatures` picks up through the source.
+fn load_order(dir: &Path) -> io::Result<Vec<(PathBuf, Kind)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path.file_name().and_then(|name| name.to_str()).is_none_or(|name| name.starts_with('.'));
+        if hidden || !path.is_file() {
+            continue;
+        }
+        let Some(kind) = kind_of(&path) else {
+            continue;
+        };
+        let compiled_source = path.extension().is_some_and(|ext| ext == "avdb")
+            && ["db", "toml", "json"].iter().any(|ext| path.with_extension(ext).is_file());
+        if !compiled_source {
+            files.push((path, kind));
+        }
+    }
+    files.sort_by(|(a, _), (b, _)| (is_local(a), a.file_name()).cmp(&(is_local(b), b.file_name())));
+    Ok(files)
+}
+
+// Everything loaded from a database directory.
+pub struct DatabaseDir {
+    pub signatures: SignatureSet,
+    pub hashes: Vec<HashSignature>,
+    pub fuzzy: Vec<FuzzySignature>,
+    // Lines that didn't load, with the file they are in.
+    pub errors: Vec<(String, DbError)>,
+    // Names defined again, by a later file or further down the same one, which
+    // replaces the earlier definition, and disabled names nothing defines.
+    pub warnings: Vec<(String, String)>,
+}
+
+// Prefixes an error with the file it is about, keeping signature check
+// failures recognizable.
+fn in_file(file: &str, e: io::Error) -> io::Error {
+    if is_untrusted(&e) {
+        return untrusted(format!("{}: {}", file, e));
+    }
+    io::Error::new(e.kind(), format!("{}: {}", file, e))
+}
+
+// Loads every database in `dir` into one. Byte pattern, logical and YARA
+// signatures share one namespace: a name defined by more than one file takes
+// the definition from the file loaded last and is reported. Hash and fuzzy
+// hash signatures may repeat names, since one threat often has several
+// samples. Names listed in `.ign` files are left out of all of them. Each file
+// has to pass `verification`.
+pub fn read_database_dir(dir: &str, verification: &Verification) -> io::Result<DatabaseDir> {
+    let mut entries = Entries::default();
+    let mut metadata = FnvHashMap::default();
+    let mut hashes = Vec::new();
+    let mut fuzzy = Vec::new();
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+    // File that defined each name in `entries`.
+    let mut owners: FnvHashMap<String, String> = FnvHashMap::default();
+    let mut versions = Vec::new();
+    let mut disabled: Vec<(String, String)> = Vec::new();
+
+    for (path, kind) in load_order(Path::new(dir))? {
+        let file = path.to_string_lossy().into_owned();
+        let context = |e: io::Error| in_file(&file, e);
+        let read = || verification.read(&file).map_err(context);
+        let (file_entries, file_metadata, file_errors) = match kind {
+            Kind::Signatures => {
+                let (set, file_errors) = read_signatures(&file, verification).map_err(context)?;
+                versions.push(format!("{}={}", path.file_name().unwrap_or_default().to_string_lossy(), set.version()));
+                warnings.extend(set.redefined().iter().map(|error| (file.clone(), error.to_string())));
+                let (file_entries, file_metadata) = set.into_entries();
+                (file_entries, file_metadata, file_errors)
+            }
+            Kind::Yara => {
+                let source = String::from_utf8(read()?)
+                    .map_err(|e| context(io::Error::new(io::ErrorKind::InvalidData, e.utf8_error())))?;
+                let (rules, file_errors) = parse_yara_rules(&source);
+                (rules.into_iter().map(Entry::Logical).collect(), FnvHashMap::default(), file_errors)
+            }
+            Kind::Clamav(format) => {
+                let database = parse_clamav_database(&read()?[..], format).map_err(context)?;
+                hashes.extend(database.hashes);
+                let mut file_entries: Vec<Entry> = database.signatures.into_iter().map(Entry::Plain).collect();
+                file_entries.extend(database.logical.into_iter().map(Entry::Logical));
+                (file_entries, FnvHashMap::default(), database.skipped)
+            }
+            Kind::Hashes => {
+                let (file_hashes, file_errors) = parse_hash_signatures(&read()?[..]).map_err(context)?;
+                hashes.extend(file_hashes);
+                (Vec::new(), FnvHashMap::default(), file_errors)
+            }
+            Kind::Fuzzy => {
+                let (file_fuzzy, file_errors) = parse_fuzzy_signatures(&read()?[..]).map_err(context)?;
+                fuzzy.extend(file_fuzzy);
+                (Vec::new(), FnvHashMap::default(), file_errors)
+            }
+            Kind::Disabled => {
+                for line in read()?.lines() {
+                    let line = line.map_err(context)?;
+                    let name = line.trim();
+                    if !name.is_empty() && !name.starts_with('#') {
+                        disabled.push((file.clone(), name.to_string()));
+                    }
+                }
+                (Vec::new(), FnvHashMap::default(), Vec::new())
+            }
+        };
+        errors.extend(file_errors.into_iter().map(|error| (file.clone(), error)));
+        for entry in file_entries {
+            let name = entry.name().to_string();
+            entries.insert(entry);
+            metadata.remove(&name);
+            match owners.insert(name.clone(), file.clone()) {
+                Some(previous) if previous != file => {
+                    warnings.push((file.clone(), format!("{} replaces the one in {}", name, previous)));
+                }
+                // Signature databases report their own repeats, with line numbers.
+                Some(_) if kind != Kind::Signatures => warnings.push((file.clone(), redefinition(&name))),
+                _ => {}
+            }
+        }
+        metadata.extend(file_metadata);
+    }
+
+    let disabled_names: FnvHashSet<&str> = disabled.iter().map(|(_, name)| name.as_str()).collect();
+    for (file, name) in &disabled {
+        let defined = owners.contains_key(name)
+            || hashes.iter().any(|signature: &HashSignature| &signature.name == name)
+            || fuzzy.iter().any(|signature: &FuzzySignature| &signature.name == name);
+        if !defined {
+            warnings.push((file.clone(), format!("{} is disabled but no database defines it", name)));
+        }
+    }
+    let (mut signatures, mut logical) = entries.into_parts();
+    signatures.retain(|signature| !disabled_names.contains(signature.name.as_str()));
+    logical.retain(|signature| !disabled_names.contains(signature.name.as_str()));
+    metadata.retain(|name, _| !disabled_names.contains(name.as_str()));
+    hashes.retain(|signature| !disabled_names.contains(signature.name.as_str()));
+    fuzzy.retain(|signature| !disabled_names.contains(signature.name.as_str()));
+    Ok(DatabaseDir {
+        signatures: SignatureSet::new(signatures, logical).with_version(versions.join(",")).with_metadata(metadata),
+        hashes,
+        fuzzy,
+        errors,
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::{load_order, read_database_dir};
+    use crate::signing::Verification;
+
+    fn temp_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("anti_virus-database-dir-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+        dir
+    }
+
+    fn name(path: &Path) -> String {
+        path.file_name().unwrap().to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn local_overrides_load_last() {
+        let dir = temp_dir(
+            "order",
+            &[
+                ("z.db", ""),
+                ("local.db", ""),
+                ("a.local.yar", ""),
+                ("b.ndb", ""),
+                ("c.db", ""),
+                ("c.avdb", ""),
+                ("d.avdb", ""),
+                (".hidden.db", ""),
+                ("notes.txt", ""),
+            ],
+        );
+        let order: Vec<String> = load_order(&dir).unwrap().iter().map(|(path, _)| name(path)).collect();
+        assert_eq!(order, ["b.ndb", "c.db", "d.avdb", "z.db", "a.local.yar", "local.db"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn later_files_replace_earlier_definitions() {
+        let dir = temp_dir(
+            "override",
+            &[
+                ("main.db", "# version: 7\nShared=aaaa\nMain=bbbb\nMain=bbbc\n"),
+                ("extra.ndb", "Shared:0:*:cccc\nExtra:0:*:dddd\n"),
+                ("local.db", "Shared=eeee\n"),
+            ],
+        );
+        let loaded = read_database_dir(dir.to_str().unwrap(), &Verification::Skipped).unwrap();
+        let mut definitions: Vec<String> = loaded.signatures.signatures().map(ToString::to_string).collect();
+        definitions.sort();
+        assert_eq!(definitions, ["Extra=dddd", "Main=bbbc", "Shared=eeee"]);
+        // ClamAV files have no version; unversioned databases go by a fingerprint.
+        assert!(loaded.signatures.version().starts_with("main.db=7,local.db=fnv-"), "{}", loaded.signatures.version());
+        let warnings: Vec<(String, &str)> =
+            loaded.warnings.iter().map(|(file, warning)| (name(Path::new(file)), warning.as_str())).collect();
+        assert_eq!(warnings.len(), 3, "{:?}", warnings);
+        assert_eq!(warnings[0].0, "main.db");
+        assert!(warnings[0].1.contains("Main is defined again"), "{:?}", warnings);
+        assert_eq!(warnings[1].0, "main.db");
+        assert!(warnings[1].1.starts_with("Shared replaces the one in") && warnings[1].1.ends_with("extra.ndb"));
+        assert_eq!(warnings[2].0, "local.db");
+        assert!(warnings[2].1.starts_with("Shared replaces the one in") && warnings[2].1.ends_with("main.db"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ignored_names_are_left_out() {
+        let dir = temp_dir(
+            "ignored",
+            &[
+                ("main.db", "Keep=aaaa\nDrop=bbbb\n"),
+                ("main.hdb", "44d88612fea8a8f36de82e1278abb02f:68:Drop\n44d88612fea8a8f36de82e1278abb02f:68:Other\n"),
+                ("local.ign", "# false positives\nDrop\nNowhere\n"),
+                ("broken.db", "Bad=zz\n"),
+            ],
+        );
+        let loaded = read_database_dir(dir.to_str().unwrap(), &Verification::Skipped).unwrap();
+        let names: Vec<&str> = loaded.signatures.signatures().map(|signature| signature.name.as_str()).collect();
+        assert_eq!(names, ["Keep"]);
+        let hashes: Vec<&str> = loaded.hashes.iter().map(|signature| signature.name.as_str()).collect();
+        assert_eq!(hashes, ["Other"]);
+        let warnings: Vec<&str> = loaded.warnings.iter().map(|(_, warning)| warning.as_str()).collect();
+        assert_eq!(warnings, ["Nowhere is disabled but no database defines it"]);
+        assert_eq!(loaded.errors.len(), 1);
+        assert_eq!(name(Path::new(&loaded.errors[0].0)), "broken.db");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
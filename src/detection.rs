@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::SignatureMeta;
+use crate::policy::Severity;
+use crate::signatures::category_from_name;
+
+// Bytes of surrounding data captured on either side of a match offset.
+pub const CONTEXT_BYTES: usize = 16;
+
+// What produced a detection: a byte pattern found at `offset`, a logical
+// signature or YARA rule whose first sub-signature match is at `offset`, or an
+// exact or fuzzy digest of the whole file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DetectionKind {
+    #[default]
+    Pattern,
+    Logical,
+    Yara,
+    Hash,
+    Fuzzy,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Detection {
+    pub offset: u64,
+    pub signature: String,
+    pub length: usize,
+    // Hex of the bytes around the match; `context_offset` is where they start.
+    pub context_offset: u64,
+    pub context: String,
+    #[serde(default)]
+    pub kind: DetectionKind,
+    // Similarity in percent, for fuzzy matches.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<u8>,
+    // Details of the signature, when its database gives any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<SignatureMeta>,
+}
+
+impl Detection {
+    // `start` is the match position within `data`, which begins at file offset `base`.
+    pub fn new(signature: &str, length: usize, data: &[u8], start: usize, base: u64) -> Detection {
+        let from = start.saturating_sub(CONTEXT_BYTES);
+        let to = (start + CONTEXT_BYTES).min(data.len());
+        Detection {
+            offset: base + start as u64,
+            signature: signature.to_owned(),
+            length,
+            context_offset: base + from as u64,
+            context: hex::encode(&data[from..to]),
+            kind: DetectionKind::Pattern,
+            score: None,
+            metadata: None,
+        }
+    }
+
+    // The category from the metadata, or else from the signature name.
+    pub fn category(&self) -> String {
+        match self.metadata.as_ref().and_then(|meta| meta.category.as_ref()) {
+            Some(category) => category.to_lowercase(),
+            None => category_from_name(&self.signature),
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self.metadata.as_ref().and_then(|meta| meta.severity) {
+            Some(severity) => severity,
+            None => Severity::for_category(&self.category()),
+        }
+    }
+
+    // A match covering the whole file, which has no meaningful context.
+    pub fn whole_file(signature: &str, kind: DetectionKind, size: u64) -> Detection {
+        Detection {
+            offset: 0,
+            signature: signature.to_owned(),
+            length: size as usize,
+            context_offset: 0,
+            context: String::new(),
+            kind,
+            score: None,
+            metadata: None,
+        }
+    }
+}
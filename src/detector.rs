@@ -0,0 +1,16 @@
+use crate::detection::Detection;
+
+// A detector that judges files as a whole rather than by byte patterns. The
+// scanner feeds it every byte of the file in the same pass that runs the
+// pattern matcher, so no file is read twice.
+pub trait Detector: Send + Sync {
+    // `None` when nothing in the detector could match a file of `size` bytes,
+    // letting the scanner skip the work entirely.
+    fn begin(&self, size: u64) -> Option<Box<dyn FileState + '_>>;
+}
+
+// Per-file state of a `Detector`, fed the file front to back.
+pub trait FileState {
+    fn update(&mut self, data: &[u8]);
+    fn finish(self: Box<Self>, detections: &mut Vec<Detection>);
+}
@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use memmap2::Mmap;
+
+use crate::detection::{Detection, CONTEXT_BYTES};
+use crate::detector::{Detector, FileState};
+use crate::chunked_reader::{ChunkedReader, DEFAULT_CHUNK_SIZE};
+use crate::database_dir::read_database_dir;
+use crate::fuzzy::FuzzyDatabase;
+use crate::hash_signatures::HashDatabase;
+use crate::logical::{LogicalMatches, LogicalSignature};
+use crate::policy::{Responder, Response};
+use crate::report;
+use crate::signatures::{read_signatures, DbError, Signature, SignatureSet};
+use crate::signing::Verification;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ScanOptions {
+    pub chunk_size: usize,
+    // Files at least this large are memory-mapped instead of read in chunks.
+    pub mmap_threshold: Option<u64>,
+}
+
+impl Default for ScanOptions {
+    fn default() -> ScanOptions {
+        ScanOptions { chunk_size: DEFAULT_CHUNK_SIZE, mmap_threshold: None }
+    }
+}
+
+// Everything scans match files against.
+#[derive(Clone)]
+pub struct Databases {
+    pub signatures: Arc<SignatureSet>,
+    pub hashes: Option<Arc<HashDatabase>>,
+    pub fuzzy: Option<Arc<FuzzyDatabase>>,
+}
+
+impl Databases {
+    fn detectors(&self) -> impl Iterator<Item = &dyn Detector> {
+        let hashes = self.hashes.as_deref().map(|hashes| hashes as &dyn Detector);
+        let fuzzy = self.fuzzy.as_deref().map(|fuzzy| fuzzy as &dyn Detector);
+        hashes.into_iter().chain(fuzzy)
+    }
+}
+
+// The databases in use, behind a lock held only to clone or replace the `Arc`.
+// Each scan takes one snapshot and keeps it to the end, so databases swapped in
+// by a reload never disturb scans already running. Clones share the same slot.
+#[derive(Clone)]
+pub struct DatabaseHandle {
+    current: Arc<RwLock<Arc<Databases>>>,
+}
+
+impl DatabaseHandle {
+    pub fn new(databases: Databases) -> DatabaseHandle {
+        DatabaseHandle { current: Arc::new(RwLock::new(Arc::new(databases))) }
+    }
+
+    pub fn snapshot(&self) -> Arc<Databases> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    // Installs `databases` for scans started from now on and returns the ones
+    // they replace.
+    pub fn swap(&self, databases: Databases) -> Arc<Databases> {
+        mem::replace(&mut *self.current.write().unwrap(), Arc::new(databases))
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Databases)) {
+        let mut current = self.current.write().unwrap();
+        let mut databases = Databases::clone(&current);
+        change(&mut databases);
+        *current = Arc::new(databases);
+    }
+}
+
+pub struct FileCompare {
+    database_path: Option<String>,
+    databases: DatabaseHandle,
+    options: ScanOptions,
+    // Lines that didn't load, with the file they are in.
+    load_errors: Vec<(String, DbError)>,
+    load_warnings: Vec<(String, String)>,
+    risk_files: BTreeMap<String, Vec<Detection>>,
+    responder: Option<Responder>,
+}
+
+impl FileCompare {
+    // Loads the signature database at `database_path`, or every database in it
+    // if it is a directory, refusing any that doesn't pass `verification`.
+    pub fn new(database_path: &str, verification: &Verification) -> io::Result<FileCompare> {
+        let mut file_compare = if Path::new(database_path).is_dir() {
+            let loaded = read_database_dir(database_path, verification)?;
+            let mut file_compare = FileCompare::with_signatures(Arc::new(loaded.signatures));
+            file_compare.set_hash_database(Arc::new(HashDatabase::new(loaded.hashes)));
+            file_compare.set_fuzzy_database(Arc::new(FuzzyDatabase::new(loaded.fuzzy)));
+            file_compare.load_errors = loaded.errors;
+            file_compare.load_warnings = loaded.warnings;
+            file_compare
+        } else {
+            let (signatures, load_errors) = read_signatures(database_path, verification)?;
+            let redefined = signatures.redefined().iter().map(|error| (database_path.to_owned(), error.to_string()));
+            let load_warnings = redefined.collect();
+            let mut file_compare = FileCompare::with_signatures(Arc::new(signatures));
+            file_compare.load_errors =
+                load_errors.into_iter().map(|error| (database_path.to_owned(), error)).collect();
+            file_compare.load_warnings = load_warnings;
+            file_compare
+        };
+        file_compare.database_path = Some(database_path.to_owned());
+        Ok(file_compare)
+    }
+
+    pub fn with_signatures(signatures: Arc<SignatureSet>) -> FileCompare {
+        FileCompare {
+            database_path: None,
+            databases: DatabaseHandle::new(Databases { signatures, hashes: None, fuzzy: None }),
+            options: ScanOptions::default(),
+            load_errors: Vec::new(),
+            load_warnings: Vec::new(),
+            risk_files: BTreeMap::new(),
+            responder: None,
+        }
+    }
+
+    // Whole-file hash signatures checked alongside the byte patterns.
+    pub fn set_hash_database(&mut self, hashes: Arc<HashDatabase>) {
+        self.databases.update(|databases| databases.hashes = Some(hashes).filter(|hashes| !hashes.is_empty()));
+    }
+
+    pub fn get_hash_database(&self) -> Option<Arc<HashDatabase>> {
+        self.databases.snapshot().hashes.clone()
+    }
+
+    // Fuzzy hashes of known samples, reported when a file is similar enough.
+    pub fn set_fuzzy_database(&mut self, fuzzy: Arc<FuzzyDatabase>) {
+        self.databases.update(|databases| databases.fuzzy = Some(fuzzy).filter(|fuzzy| !fuzzy.is_empty()));
+    }
+
+    pub fn get_fuzzy_database(&self) -> Option<Arc<FuzzyDatabase>> {
+        self.databases.snapshot().fuzzy.clone()
+    }
+
+    // Signatures from outside the signature database, such as compiled YARA
+    // rules or imported ClamAV databases, matched in the same pass as the rest.
+    pub fn add_signatures(&mut self, signatures: Vec<Signature>, logical: Vec<LogicalSignature>) {
+        if !signatures.is_empty() || !logical.is_empty() {
+            self.databases.update(|databases| {
+                databases.signatures = Arc::new(databases.signatures.extended(signatures, logical));
+            });
+        }
+    }
+
+    // The slot scans take their databases from; a reloader swaps new ones in
+    // through a clone of it.
+    pub fn get_database_handle(&self) -> &DatabaseHandle {
+        &self.databases
+    }
+
+    pub fn set_options(&mut self, options: ScanOptions) {
+        self.options = options;
+    }
+
+    pub fn get_options(&self) -> &ScanOptions {
+        &self.options
+    }
+
+    // Every file recorded from now on is handed to `responder`, which decides
+    // and carries out what happens to it.
+    pub fn set_responder(&mut self, responder: Responder) {
+        self.responder = Some(responder);
+    }
+
+    pub fn get_responses(&self) -> &[Response] {
+        self.responder.as_ref().map_or(&[], |responder| responder.responses())
+    }
+
+    // Only reads shared state, so any number of workers can scan concurrently.
+    // Detections come back ordered by offset, then signature name.
+    pub fn scan_file(&self, path: &str) -> io::Result<Vec<Detection>> {
+        self.scan_file_with(&self.databases.snapshot(), path)
+    }
+
+    // Scans against `databases`, a snapshot taken once for a whole pass so every
+    // file in it sees the same databases even if a reload swaps in new ones.
+    pub fn scan_file_with(&self, databases: &Databases, path: &str) -> io::Result<Vec<Detection>> {
+        let signatures = &*databases.signatures;
+        let mut detections = Vec::new();
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut states: Vec<_> = databases.detectors().filter_map(|detector| detector.begin(size)).collect();
+        let mut logical = signatures.logical_matches(size);
+        let header_len = signatures.header_len() as u64;
+        let trailer_len = signatures.trailer_len() as u64;
+        if !signatures.has_floating() && states.is_empty() && header_len.saturating_add(trailer_len) < size {
+            // Every signature is tied to an offset, so only the two ends of the file matter.
+            self.scan_stream(signatures, (&mut file).take(header_len), 0, size, &mut [], &mut logical, &mut detections)?;
+            if trailer_len > 0 {
+                let base = size - trailer_len;
+                file.seek(SeekFrom::Start(base))?;
+                self.scan_stream(signatures, file.take(trailer_len), base, size, &mut [], &mut logical, &mut detections)?;
+            }
+        } else if self.options.mmap_threshold.is_some_and(|threshold| size >= threshold) {
+            // SAFETY: the map is read-only and dropped before returning; a file
+            // truncated underneath us is the usual mmap caveat.
+            let map = unsafe { Mmap::map(&file)? };
+            for state in &mut states {
+                state.update(&map);
+            }
+            signatures.scan(&map, 0, size, &mut logical, |signature, start, length| {
+                detections.push(Detection::new(&signature.name, length, &map, start, 0));
+            });
+        } else {
+            self.scan_stream(signatures, file, 0, size, &mut states, &mut logical, &mut detections)?;
+        }
+        logical.finish(&mut detections);
+        for state in states {
+            state.finish(&mut detections);
+        }
+        detections.sort();
+        // A variable-length match straddling a window boundary can be found
+        // from both sides with different lengths.
+        detections.dedup_by(|a, b| a.offset == b.offset && a.signature == b.signature && a.kind == b.kind);
+        for detection in &mut detections {
+            detection.metadata = signatures.metadata(&detection.signature).cloned();
+        }
+        Ok(detections)
+    }
+
+    // Scans `reader`, which yields the file from offset `base` on, in chunks.
+    #[allow(clippy::too_many_arguments)]
+    fn scan_stream<'a, R: Read>(
+        &self,
+        signatures: &'a SignatureSet,
+        reader: R,
+        base: u64,
+        size: u64,
+        states: &mut [Box<dyn FileState + 'a>],
+        logical: &mut LogicalMatches<'a>,
+        detections: &mut Vec<Detection>,
+    ) -> io::Result<()> {
+        let overlap = signatures.max_len().saturating_sub(1) + CONTEXT_BYTES;
+        let mut reader = ChunkedReader::new(reader, self.options.chunk_size, overlap);
+        while let Some(window) = reader.next_window()? {
+            for state in states.iter_mut() {
+                state.update(&window.data[window.seen..]);
+            }
+            let offset = base + window.offset;
+            signatures.scan(window.data, offset, size, logical, |signature, start, length| {
+                if start + length > window.seen {
+                    detections.push(Detection::new(&signature.name, length, window.data, start, offset));
+                }
+            });
+        }
+        Ok(())
+    }
+
+    pub fn compare(&mut self, path: &str) -> io::Result<()> {
+        let detections = self.scan_file(path)?;
+        self.record(path, detections);
+        Ok(())
+    }
+
+    pub fn record(&mut self, path: &str, detections: Vec<Detection>) {
+        if !detections.is_empty() {
+            if let Some(responder) = &mut self.responder {
+                responder.respond(path, &detections);
+            }
+            self.risk_files.insert(path.to_owned(), detections);
+        }
+    }
+
+    pub fn get_database_path(&self) -> Option<&str> {
+        self.database_path.as_deref()
+    }
+
+    pub fn get_signatures(&self) -> Arc<SignatureSet> {
+        Arc::clone(&self.databases.snapshot().signatures)
+    }
+
+    pub fn get_load_errors(&self) -> &[(String, DbError)] {
+        &self.load_errors
+    }
+
+    // Signatures replaced by a later database of the same directory, and
+    // disabled names no database defines.
+    pub fn get_load_warnings(&self) -> &[(String, String)] {
+        &self.load_warnings
+    }
+
+    // Keyed by path, so iteration order is stable between runs.
+    pub fn get_risk_files(&self) -> &BTreeMap<String, Vec<Detection>> {
+        &self.risk_files
+    }
+
+    // Forgets the files recorded so far, e.g. before scanning the same roots again.
+    pub fn clear_risk_files(&mut self) {
+        self.risk_files.clear();
+    }
+
+    pub fn log_risk_files(&self, directory: &str) -> io::Result<()> {
+        let log_dir = format!("{}/logs", directory);
+        fs::create_dir_all(&log_dir)?;
+        let log_file = format!("{}/risk_files.log", log_dir);
+        let file = File::create(&log_file)?;
+        let mut writer = BufWriter::new(file);
+        report::write_text(&mut writer, self.risk_files.iter().map(|(path, d)| (path.as_str(), d.as_slice())))?;
+        writer.flush()
+    }
+}
@@ -0,0 +1,504 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::str::FromStr;
+
+use crate::detection::{Detection, DetectionKind};
+use crate::detector::{Detector, FileState};
+use crate::signatures::DbError;
+
+// Context triggered piecewise hashing compatible with ssdeep: a rolling hash
+// over a 7 byte window picks the piece boundaries, and each piece contributes
+// one base64 character of its FNV hash.
+const ROLLING_WINDOW: usize = 7;
+const MIN_BLOCKSIZE: u64 = 3;
+const SPAMSUM_LENGTH: usize = 64;
+const HASH_INIT: u32 = 0x2802_1967;
+const HASH_PRIME: u32 = 0x0100_0193;
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn sum_hash(c: u8, h: u32) -> u32 {
+    h.wrapping_mul(HASH_PRIME) ^ c as u32
+}
+
+#[derive(Default)]
+struct RollingHash {
+    window: [u8; ROLLING_WINDOW],
+    h1: u32,
+    h2: u32,
+    h3: u32,
+    n: usize,
+}
+
+impl RollingHash {
+    fn update(&mut self, c: u8) {
+        self.h2 = self.h2.wrapping_sub(self.h1).wrapping_add(ROLLING_WINDOW as u32 * c as u32);
+        self.h1 = self.h1.wrapping_add(c as u32).wrapping_sub(self.window[self.n] as u32);
+        self.window[self.n] = c;
+        self.n = (self.n + 1) % ROLLING_WINDOW;
+        self.h3 = (self.h3 << 5) ^ c as u32;
+    }
+
+    fn sum(&self) -> u32 {
+        self.h1.wrapping_add(self.h2).wrapping_add(self.h3)
+    }
+}
+
+// Digest state for one block size. `half` is the short digest ssdeep keeps for
+// the doubled block size. Once a digest is full, its last character is
+// replaced by each further piece instead, kept in `tail` and `half_tail`.
+struct Level {
+    h: u32,
+    half_h: u32,
+    digest: String,
+    half: String,
+    tail: Option<char>,
+    half_tail: Option<char>,
+}
+
+// The block size ssdeep ends up with depends on how many pieces the file
+// produces, which is only known at the end. Every candidate size is tracked in
+// one pass; sizes that can no longer be chosen are dropped as soon as possible.
+pub struct FuzzyHasher {
+    roll: RollingHash,
+    levels: Vec<Level>,
+    // Index of the block size suggested by the file size.
+    initial: usize,
+    lowest: usize,
+}
+
+impl FuzzyHasher {
+    pub fn new(size: u64) -> FuzzyHasher {
+        let mut initial = 0;
+        while (MIN_BLOCKSIZE << initial) * (SPAMSUM_LENGTH as u64) < size {
+            initial += 1;
+        }
+        let levels = (0..initial + 2)
+            .map(|_| Level {
+                h: HASH_INIT,
+                half_h: HASH_INIT,
+                digest: String::new(),
+                half: String::new(),
+                tail: None,
+                half_tail: None,
+            })
+            .collect();
+        FuzzyHasher { roll: RollingHash::default(), levels, initial, lowest: 0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &c in data {
+            self.roll.update(c);
+            for level in &mut self.levels[self.lowest..] {
+                level.h = sum_hash(c, level.h);
+                level.half_h = sum_hash(c, level.half_h);
+            }
+            let h = self.roll.sum() as u64;
+            // A boundary for one block size is also one for every smaller size.
+            for (index, level) in self.levels.iter_mut().enumerate().skip(self.lowest) {
+                let block_size = MIN_BLOCKSIZE << index;
+                if h % block_size != block_size - 1 {
+                    break;
+                }
+                let c = BASE64[(level.h % 64) as usize] as char;
+                if level.digest.len() < SPAMSUM_LENGTH - 1 {
+                    level.digest.push(c);
+                    level.h = HASH_INIT;
+                } else {
+                    level.tail = Some(c);
+                }
+                let c = BASE64[(level.half_h % 64) as usize] as char;
+                if level.half.len() < SPAMSUM_LENGTH / 2 - 1 {
+                    level.half.push(c);
+                    level.half_h = HASH_INIT;
+                } else {
+                    level.half_tail = Some(c);
+                }
+            }
+            // Once the next size up has enough pieces, smaller sizes are never picked.
+            while self.lowest < self.initial && self.levels[self.lowest + 1].digest.len() >= SPAMSUM_LENGTH / 2 {
+                self.lowest += 1;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> FuzzyHash {
+        let mut index = self.initial;
+        while index > self.lowest && self.levels[index].digest.len() < SPAMSUM_LENGTH / 2 {
+            index -= 1;
+        }
+        let mut digest = std::mem::take(&mut self.levels[index].digest);
+        let mut double = std::mem::take(&mut self.levels[index + 1].half);
+        // Like ssdeep, the trailing, unterminated piece counts unless the file
+        // ends right on a boundary; a full digest then ends with its last piece.
+        if self.roll.sum() != 0 {
+            digest.push(BASE64[(self.levels[index].h % 64) as usize] as char);
+            double.push(BASE64[(self.levels[index + 1].half_h % 64) as usize] as char);
+        } else {
+            digest.extend(self.levels[index].tail);
+            double.extend(self.levels[index + 1].half_tail);
+        }
+        FuzzyHash { block_size: MIN_BLOCKSIZE << index, digest, double }
+    }
+}
+
+// An ssdeep digest, written `blocksize:digest:double`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuzzyHash {
+    pub block_size: u64,
+    pub digest: String,
+    pub double: String,
+}
+
+impl FuzzyHash {
+    pub fn of(data: &[u8]) -> FuzzyHash {
+        let mut hasher = FuzzyHasher::new(data.len() as u64);
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    // Similarity from 0 to 100, scored the way ssdeep does. Hashes whose block
+    // sizes are more than a factor two apart can't be compared and score 0.
+    pub fn compare(&self, other: &FuzzyHash) -> u8 {
+        let (a, b) = (self.block_size, other.block_size);
+        if a != b && a != b * 2 && b != a * 2 {
+            return 0;
+        }
+        let (a1, a2) = (collapse_runs(&self.digest), collapse_runs(&self.double));
+        let (b1, b2) = (collapse_runs(&other.digest), collapse_runs(&other.double));
+        if a == b && a1 == b1 && a2 == b2 {
+            return 100;
+        }
+        if a == b {
+            score_strings(&a1, &b1, a).max(score_strings(&a2, &b2, a * 2))
+        } else if a == b * 2 {
+            score_strings(&a1, &b2, a)
+        } else {
+            score_strings(&a2, &b1, b)
+        }
+    }
+}
+
+impl fmt::Display for FuzzyHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.block_size, self.digest, self.double)
+    }
+}
+
+impl FromStr for FuzzyHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FuzzyHash, String> {
+        let mut parts = s.trim().splitn(3, ':');
+        let (Some(block_size), Some(digest), Some(double)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err("expected blocksize:digest:digest".to_string());
+        };
+        let block_size: u64 = block_size.parse().map_err(|_| format!("invalid block size {}", block_size))?;
+        if !block_size.is_multiple_of(MIN_BLOCKSIZE) || !(block_size / MIN_BLOCKSIZE).is_power_of_two() {
+            return Err(format!("invalid block size {}", block_size));
+        }
+        // ssdeep output may carry a `,"filename"` suffix.
+        let double = double.split(',').next().unwrap_or(double);
+        for part in [digest, double] {
+            if part.len() > SPAMSUM_LENGTH || !part.bytes().all(|c| BASE64.contains(&c)) {
+                return Err(format!("invalid digest {}", part));
+            }
+        }
+        Ok(FuzzyHash { block_size, digest: digest.to_string(), double: double.to_string() })
+    }
+}
+
+// Runs of more than three identical characters carry little information and
+// are cut down before comparing.
+fn collapse_runs(digest: &str) -> Vec<u8> {
+    let mut collapsed: Vec<u8> = Vec::with_capacity(digest.len());
+    for c in digest.bytes() {
+        let n = collapsed.len();
+        if n < 3 || collapsed[n - 1] != c || collapsed[n - 2] != c || collapsed[n - 3] != c {
+            collapsed.push(c);
+        }
+    }
+    collapsed
+}
+
+fn has_common_substring(a: &[u8], b: &[u8]) -> bool {
+    a.windows(ROLLING_WINDOW).any(|window| b.windows(ROLLING_WINDOW).any(|other| other == window))
+}
+
+// Levenshtein distance with substitutions costing two, as in ssdeep.
+fn edit_distance(a: &[u8], b: &[u8]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, &ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = previous[j] + if ca == cb { 0 } else { 2 };
+            current[j + 1] = substitute.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+fn score_strings(a: &[u8], b: &[u8], block_size: u64) -> u8 {
+    if a.len() > SPAMSUM_LENGTH || b.len() > SPAMSUM_LENGTH || !has_common_substring(a, b) {
+        return 0;
+    }
+    let distance = edit_distance(a, b) * SPAMSUM_LENGTH / (a.len() + b.len());
+    let distance = 100 * distance / SPAMSUM_LENGTH;
+    if distance >= 100 {
+        return 0;
+    }
+    let score = (100 - distance) as u64;
+    // Small block sizes give short, noisy digests, so their scores are capped.
+    let unlimited = (99 + ROLLING_WINDOW as u64) / MIN_BLOCKSIZE * MIN_BLOCKSIZE;
+    if block_size >= unlimited {
+        return score as u8;
+    }
+    score.min(block_size / MIN_BLOCKSIZE * a.len().min(b.len()) as u64) as u8
+}
+
+#[derive(Clone, Debug)]
+pub struct FuzzySignature {
+    pub name: String,
+    // Minimum similarity for a file to be reported.
+    pub threshold: u8,
+    pub hash: FuzzyHash,
+}
+
+#[derive(Default)]
+pub struct FuzzyDatabase {
+    signatures: Vec<FuzzySignature>,
+}
+
+impl FuzzyDatabase {
+    pub fn new(signatures: Vec<FuzzySignature>) -> FuzzyDatabase {
+        FuzzyDatabase { signatures }
+    }
+
+    pub fn len(&self) -> usize {
+        self.signatures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.signatures.is_empty()
+    }
+
+    pub fn signatures(&self) -> &[FuzzySignature] {
+        &self.signatures
+    }
+
+    // Every signature `hash` is at least as similar to as its threshold, with the score.
+    pub fn matches(&self, hash: &FuzzyHash) -> Vec<(&FuzzySignature, u8)> {
+        self.signatures
+            .iter()
+            .map(|signature| (signature, signature.hash.compare(hash)))
+            .filter(|&(signature, score)| score > 0 && score >= signature.threshold)
+            .collect()
+    }
+}
+
+struct FuzzyState<'a> {
+    database: &'a FuzzyDatabase,
+    hasher: FuzzyHasher,
+    size: u64,
+}
+
+impl FileState for FuzzyState<'_> {
+    fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    fn finish(self: Box<Self>, detections: &mut Vec<Detection>) {
+        let hash = self.hasher.finish();
+        for (signature, score) in self.database.matches(&hash) {
+            detections.push(Detection {
+                score: Some(score),
+                ..Detection::whole_file(&signature.name, DetectionKind::Fuzzy, self.size)
+            });
+        }
+    }
+}
+
+impl Detector for FuzzyDatabase {
+    fn begin(&self, size: u64) -> Option<Box<dyn FileState + '_>> {
+        if self.is_empty() {
+            return None;
+        }
+        Some(Box::new(FuzzyState { database: self, hasher: FuzzyHasher::new(size), size }))
+    }
+}
+
+fn parse_line(line: &str) -> Result<FuzzySignature, String> {
+    let mut fields = line.splitn(3, ':');
+    let (Some(name), Some(threshold), Some(hash)) = (fields.next(), fields.next(), fields.next()) else {
+        return Err("expected name:threshold:ssdeep".to_string());
+    };
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("missing signature name".to_string());
+    }
+    let threshold = match threshold.trim().parse::<u8>() {
+        Ok(threshold) if (1..=100).contains(&threshold) => threshold,
+        _ => return Err(format!("threshold for {} must be between 1 and 100", name)),
+    };
+    let hash = hash.parse().map_err(|e| format!("{}: {}", name, e))?;
+    Ok(FuzzySignature { name: name.to_string(), threshold, hash })
+}
+
+// Parses `name:threshold:ssdeep` lines, e.g.
+// `Unix.Trojan.Agent-1:80:96:lTlkS3hr2UHo:lRzhrJ`. Blank lines and `#` comments
+// are ignored, invalid lines are skipped and returned with their line numbers.
+pub fn parse_fuzzy_signatures<R: BufRead>(reader: R) -> io::Result<(Vec<FuzzySignature>, Vec<DbError>)> {
+    let mut signatures = Vec::new();
+    let mut errors = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        match parse_line(trimmed) {
+            Ok(signature) => signatures.push(signature),
+            Err(message) => errors.push(DbError { line: index + 1, message }),
+        }
+    }
+    Ok((signatures, errors))
+}
+
+pub fn read_fuzzy_signatures(path: &str) -> io::Result<(Vec<FuzzySignature>, Vec<DbError>)> {
+    let file = File::open(path)?;
+    parse_fuzzy_signatures(BufReader::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_fuzzy_signatures, FuzzyDatabase, FuzzyHash, FuzzyHasher};
+
+    // Deterministic noise, so digests can be checked against a spamsum reference.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x = x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (x >> 33) as u8
+            })
+            .collect()
+    }
+
+    fn text() -> Vec<u8> {
+        (0..2000).flat_map(|i| format!("line {} of a fuzzy hash test\n", i).into_bytes()).collect()
+    }
+
+    fn hash(s: &str) -> FuzzyHash {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn digests_match_ssdeep() {
+        let cases: [(&[u8], &str); 3] = [
+            (b"", "3::"),
+            (
+                b"Also called fuzzy hashes, Ctph can match inputs that have homologies.",
+                "3:AXGBicFlgVNhBGcL6wCrFQEv:AXGHsNhxLsr2C",
+            ),
+            (
+                b"Also called fuzzy hashes, CTPH can match inputs that have homologies.",
+                "3:AXGBicFlIHBGcL6wCrFQEv:AXGH6xLsr2C",
+            ),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(FuzzyHash::of(data).to_string(), expected);
+        }
+        assert_eq!(FuzzyHash::of(&noise(4096, 1)).to_string(), "96:hYAH/vbQz76aTS8odfs7Zh6W6K4E6LaPx:9/TQzrm09h6W6/E6u");
+        assert_eq!(
+            FuzzyHash::of(&noise(100_000, 2)).to_string(),
+            "3072:zH/dzJrWJPeguaG+TytNUxeNcFRouWGPuC9w5d5p:79VUe9aGqynU/fu5dH"
+        );
+        assert_eq!(
+            FuzzyHash::of(&noise(1_000_000, 3)).to_string(),
+            "24576:L2bbOZ/jUaG+xIsCavojDjgu3dOEw6RT53W:LNZ/j1xxf2zs96H3W"
+        );
+        assert_eq!(FuzzyHash::of(&text()).to_string(), "384:Vva8fkRsy9g7+Z8nKNmSHV+7yXP+TF+Af:Vi4kRsy9g7+Z8nKNmsV+7y/+p7f");
+    }
+
+    #[test]
+    fn streaming_matches_one_shot() {
+        let data = noise(100_000, 2);
+        for piece in [1, 7, 4096, 65536] {
+            let mut hasher = FuzzyHasher::new(data.len() as u64);
+            for chunk in data.chunks(piece) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finish(), FuzzyHash::of(&data));
+        }
+    }
+
+    #[test]
+    fn compare_scores() {
+        let a = FuzzyHash::of(b"Also called fuzzy hashes, Ctph can match inputs that have homologies.");
+        let b = FuzzyHash::of(b"Also called fuzzy hashes, CTPH can match inputs that have homologies.");
+        assert_eq!(a.compare(&a), 100);
+        assert_eq!(a.compare(&b), 22);
+        assert_eq!(b.compare(&a), 22);
+
+        let mut edited = text();
+        edited[20000..20050].fill(b'X');
+        let (original, edited) = (FuzzyHash::of(&text()), FuzzyHash::of(&edited));
+        assert_eq!(edited.to_string(), "384:Vva8fkRsy9g7+Z8nKNmSHVX7yXP+TF+Af:Vi4kRsy9g7+Z8nKNmsVX7y/+p7f");
+        let score = original.compare(&edited);
+        assert!(score > 80 && score < 100, "{}", score);
+
+        let (x, y) = (FuzzyHash::of(&noise(100_000, 2)), FuzzyHash::of(&noise(100_000, 4)));
+        assert_eq!(x.compare(&y), 0);
+    }
+
+    #[test]
+    fn block_sizes_must_be_within_a_factor_two() {
+        let small = hash("3:AXGBicFlgVNhBGcL6wCrFQEv:AXGHsNhxLsr2C");
+        // The double digest of `small` is compared with the plain digest at block
+        // size 6; ssdeep caps scores for small block sizes, so this isn't 100.
+        let double = hash("6:AXGHsNhxLsr2C:AXGH");
+        assert_eq!(small.compare(&double), 26);
+        assert_eq!(double.compare(&small), 26);
+        assert_eq!(small.compare(&hash("12:AXGHsNhxLsr2C:AXGHsNhxLsr2C")), 0);
+        assert_eq!(small.compare(&hash("24:AXGBicFlgVNhBGcL6wCrFQEv:AXGHsNhxLsr2C")), 0);
+    }
+
+    #[test]
+    fn parsing_hashes() {
+        let parsed = hash("96:hYAH/vbQz76aTS8odfs7Zh6W6K4E6LaPx:9/TQzrm09h6W6/E6u,\"/tmp/sample\"");
+        assert_eq!(parsed, FuzzyHash::of(&noise(4096, 1)));
+        assert_eq!(parsed.to_string().parse::<FuzzyHash>().unwrap(), parsed);
+        for invalid in ["", "3:abc", "x:abc:def", "5:abc:def", "0:abc:def", "3:a!c:def", &format!("3:{}:a", "a".repeat(65))] {
+            assert!(invalid.parse::<FuzzyHash>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn signatures_and_thresholds() {
+        let input = "\
+# fuzzy signatures
+Test.Fuzzy.Text:80:384:Vva8fkRsy9g7+Z8nKNmSHV+7yXP+TF+Af:Vi4kRsy9g7+Z8nKNmsV+7y/+p7f
+
+Test.Fuzzy.Strict:100:384:Vva8fkRsy9g7+Z8nKNmSHV+7yXP+TF+Af:Vi4kRsy9g7+Z8nKNmsV+7y/+p7f
+Test.Fuzzy.Zero:0:3::
+:50:3::
+Test.Fuzzy.Bad:50:7:abc:def
+Test.Fuzzy.Short:50
+";
+        let (signatures, errors) = parse_fuzzy_signatures(input.as_bytes()).unwrap();
+        assert_eq!(signatures.len(), 2);
+        assert_eq!(errors.iter().map(|e| e.line).collect::<Vec<_>>(), [5, 6, 7, 8]);
+
+        let database = FuzzyDatabase::new(signatures);
+        let mut edited = text();
+        edited[20000..20050].fill(b'X');
+        let names = |data: &[u8]| {
+            database.matches(&FuzzyHash::of(data)).iter().map(|(s, _)| s.name.clone()).collect::<Vec<_>>()
+        };
+        assert_eq!(names(&text()), ["Test.Fuzzy.Text", "Test.Fuzzy.Strict"]);
+        assert_eq!(names(&edited), ["Test.Fuzzy.Text"]);
+        assert!(names(&noise(100_000, 2)).is_empty());
+    }
+}
@@ -0,0 +1,323 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+
+use fnv::FnvHashMap;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::detection::{Detection, DetectionKind};
+use crate::detector::{Detector, FileState};
+use crate::signatures::DbError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    // The algorithm is implied by the digest length, as in ClamAV databases.
+    fn from_hex_len(len: usize) -> Option<HashAlgorithm> {
+        match len {
+            32 => Some(HashAlgorithm::Md5),
+            40 => Some(HashAlgorithm::Sha1),
+            64 => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashAlgorithm::Md5 => write!(f, "md5"),
+            HashAlgorithm::Sha1 => write!(f, "sha1"),
+            HashAlgorithm::Sha256 => write!(f, "sha256"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HashSignature {
+    pub name: String,
+    pub algorithm: HashAlgorithm,
+    pub digest: Vec<u8>,
+    // `None` for entries written with a `*` size, which match files of any size.
+    pub size: Option<u64>,
+}
+
+// Whole-file hash signatures indexed by file size, so only files whose size
+// matches a known entry are hashed at all, and only with the algorithms those
+// entries need.
+#[derive(Default)]
+pub struct HashDatabase {
+    signatures: Vec<HashSignature>,
+    by_size: FnvHashMap<u64, Vec<usize>>,
+    // Entries of unknown size force hashing every file; ClamAV discourages them
+    // for the same reason.
+    any_size: Vec<usize>,
+}
+
+impl HashDatabase {
+    pub fn new(signatures: Vec<HashSignature>) -> HashDatabase {
+        let mut by_size: FnvHashMap<u64, Vec<usize>> = FnvHashMap::default();
+        let mut any_size = Vec::new();
+        for (id, signature) in signatures.iter().enumerate() {
+            match signature.size {
+                Some(size) => by_size.entry(size).or_default().push(id),
+                None => any_size.push(id),
+            }
+        }
+        HashDatabase { signatures, by_size, any_size }
+    }
+
+    pub fn len(&self) -> usize {
+        self.signatures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.signatures.is_empty()
+    }
+
+    pub fn signatures(&self) -> &[HashSignature] {
+        &self.signatures
+    }
+
+    fn candidates(&self, size: u64) -> impl Iterator<Item = &HashSignature> {
+        self.by_size
+            .get(&size)
+            .map_or(&[][..], Vec::as_slice)
+            .iter()
+            .chain(&self.any_size)
+            .map(|&id| &self.signatures[id])
+    }
+
+    // A hasher for a file of `size` bytes, or `None` when no entry could match it.
+    pub fn hasher_for(&self, size: u64) -> Option<FileHasher> {
+        let mut hasher = FileHasher::default();
+        for signature in self.candidates(size) {
+            match signature.algorithm {
+                HashAlgorithm::Md5 => {
+                    hasher.md5.get_or_insert_with(Md5::new);
+                }
+                HashAlgorithm::Sha1 => {
+                    hasher.sha1.get_or_insert_with(Sha1::new);
+                }
+                HashAlgorithm::Sha256 => {
+                    hasher.sha256.get_or_insert_with(Sha256::new);
+                }
+            }
+        }
+        hasher.size = size;
+        hasher.is_active().then_some(hasher)
+    }
+
+    pub fn lookup(&self, digests: &FileDigests) -> Vec<&HashSignature> {
+        self.candidates(digests.size)
+            .filter(|signature| digests.get(signature.algorithm) == Some(signature.digest.as_slice()))
+            .collect()
+    }
+}
+
+// Hashes a file as it streams past, computing only the digests asked for.
+#[derive(Default)]
+pub struct FileHasher {
+    size: u64,
+    md5: Option<Md5>,
+    sha1: Option<Sha1>,
+    sha256: Option<Sha256>,
+}
+
+impl FileHasher {
+    fn is_active(&self) -> bool {
+        self.md5.is_some() || self.sha1.is_some() || self.sha256.is_some()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        if let Some(md5) = &mut self.md5 {
+            md5.update(data);
+        }
+        if let Some(sha1) = &mut self.sha1 {
+            sha1.update(data);
+        }
+        if let Some(sha256) = &mut self.sha256 {
+            sha256.update(data);
+        }
+    }
+
+    pub fn finish(self) -> FileDigests {
+        FileDigests {
+            size: self.size,
+            md5: self.md5.map(|hasher| hasher.finalize().to_vec()),
+            sha1: self.sha1.map(|hasher| hasher.finalize().to_vec()),
+            sha256: self.sha256.map(|hasher| hasher.finalize().to_vec()),
+        }
+    }
+}
+
+pub struct FileDigests {
+    pub size: u64,
+    md5: Option<Vec<u8>>,
+    sha1: Option<Vec<u8>>,
+    sha256: Option<Vec<u8>>,
+}
+
+impl FileDigests {
+    pub fn get(&self, algorithm: HashAlgorithm) -> Option<&[u8]> {
+        match algorithm {
+            HashAlgorithm::Md5 => self.md5.as_deref(),
+            HashAlgorithm::Sha1 => self.sha1.as_deref(),
+            HashAlgorithm::Sha256 => self.sha256.as_deref(),
+        }
+    }
+}
+
+struct HashState<'a> {
+    database: &'a HashDatabase,
+    hasher: FileHasher,
+}
+
+impl FileState for HashState<'_> {
+    fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    fn finish(self: Box<Self>, detections: &mut Vec<Detection>) {
+        let digests = self.hasher.finish();
+        for signature in self.database.lookup(&digests) {
+            detections.push(Detection::whole_file(&signature.name, DetectionKind::Hash, digests.size));
+        }
+    }
+}
+
+impl Detector for HashDatabase {
+    fn begin(&self, size: u64) -> Option<Box<dyn FileState + '_>> {
+        let hasher = self.hasher_for(size)?;
+        Some(Box::new(HashState { database: self, hasher }))
+    }
+}
+
+fn parse_line(line: &str) -> Result<HashSignature, String> {
+    let mut fields = line.split(':');
+    let (Some(hash), Some(size), Some(name)) = (fields.next(), fields.next(), fields.next()) else {
+        return Err("expected hash:size:name".to_string());
+    };
+    // Anything after the name is a ClamAV functionality level, which doesn't apply here.
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("missing signature name".to_string());
+    }
+    let hash = hash.trim();
+    let algorithm = HashAlgorithm::from_hex_len(hash.len())
+        .ok_or_else(|| format!("{}: hash must be an MD5, SHA-1 or SHA-256 hex digest", name))?;
+    let digest = hex::decode(hash).map_err(|e| format!("invalid hex in {}: {}", name, e))?;
+    let size = match size.trim() {
+        "*" => None,
+        size => Some(size.parse().map_err(|_| format!("invalid size for {}: {}", name, size))?),
+    };
+    Ok(HashSignature { name: name.to_string(), algorithm, digest, size })
+}
+
+// Parses ClamAV style `hash:size:name` lines (`.hdb` and `.hsb`). Blank lines and
+// `#` comments are ignored, invalid lines are skipped and returned with their
+// line numbers.
+pub fn parse_hash_signatures<R: BufRead>(reader: R) -> io::Result<(Vec<HashSignature>, Vec<DbError>)> {
+    let mut signatures = Vec::new();
+    let mut errors = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        match parse_line(trimmed) {
+            Ok(signature) => signatures.push(signature),
+            Err(message) => errors.push(DbError { line: index + 1, message }),
+        }
+    }
+    Ok((signatures, errors))
+}
+
+pub fn read_hash_signatures(path: &str) -> io::Result<(Vec<HashSignature>, Vec<DbError>)> {
+    let file = File::open(path)?;
+    parse_hash_signatures(BufReader::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_hash_signatures, HashAlgorithm, HashDatabase};
+    use crate::detector::Detector;
+
+    const MD5_ABC: &str = "900150983cd24fb0d6963f7d28e17f72";
+    const SHA1_ABC: &str = "a9993e364706816aba3e25717850c26c9cd0d89d";
+    const SHA256_ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn database(input: &str) -> HashDatabase {
+        let (signatures, errors) = parse_hash_signatures(input.as_bytes()).unwrap();
+        assert!(errors.is_empty(), "{:?}", errors.iter().map(|e| &e.message).collect::<Vec<_>>());
+        HashDatabase::new(signatures)
+    }
+
+    fn scan(database: &HashDatabase, data: &[u8]) -> Vec<String> {
+        let Some(mut state) = database.begin(data.len() as u64) else {
+            return Vec::new();
+        };
+        state.update(data);
+        let mut detections = Vec::new();
+        state.finish(&mut detections);
+        detections.into_iter().map(|detection| detection.signature).collect()
+    }
+
+    #[test]
+    fn algorithm_follows_digest_length() {
+        let input = format!("{}:3:Test.Md5\n{}:3:Test.Sha1\n{}:3:Test.Sha256:73\n", MD5_ABC, SHA1_ABC, SHA256_ABC.to_uppercase());
+        let database = database(&input);
+        let algorithms: Vec<_> = database.signatures().iter().map(|s| s.algorithm).collect();
+        assert_eq!(algorithms, [HashAlgorithm::Md5, HashAlgorithm::Sha1, HashAlgorithm::Sha256]);
+        assert_eq!(scan(&database, b"abc"), ["Test.Md5", "Test.Sha1", "Test.Sha256"]);
+        assert!(scan(&database, b"abd").is_empty());
+    }
+
+    #[test]
+    fn only_files_of_a_listed_size_are_hashed() {
+        let database = database(&format!("{}:3:Test.Abc\n{}:4:Test.WrongSize\n", MD5_ABC, SHA1_ABC));
+        assert!(database.hasher_for(2).is_none());
+        assert!(database.hasher_for(5).is_none());
+        assert_eq!(scan(&database, b"abc"), ["Test.Abc"]);
+
+        // A size-4 file is only hashed with SHA-1, since nothing else can match it.
+        let mut hasher = database.hasher_for(4).unwrap();
+        hasher.update(b"abcd");
+        let digests = hasher.finish();
+        assert!(digests.get(HashAlgorithm::Md5).is_none());
+        assert!(digests.get(HashAlgorithm::Sha1).is_some());
+        assert!(database.lookup(&digests).is_empty());
+    }
+
+    #[test]
+    fn star_sizes_match_any_file() {
+        let database = database(&format!("{}:*:Test.AnySize\n{}:3:Test.Sized\n", SHA256_ABC, MD5_ABC));
+        assert_eq!(database.signatures()[0].size, None);
+        assert!(database.hasher_for(0).is_some());
+        assert!(database.hasher_for(1 << 40).is_some());
+        assert_eq!(scan(&database, b"abc"), ["Test.Sized", "Test.AnySize"]);
+        assert!(scan(&database, b"abcd").is_empty());
+    }
+
+    #[test]
+    fn malformed_lines_are_reported() {
+        let input = format!(
+            "# comment\n\n{md5}:3:Test.Good\n{md5}:3\n{md5}:3: \nabc:3:Test.ShortHash\n{bad}:3:Test.BadHex\n{md5}:three:Test.BadSize\n{md5}:-1:Test.Negative\n",
+            md5 = MD5_ABC,
+            bad = "zz".repeat(16),
+        );
+        let (signatures, errors) = parse_hash_signatures(input.as_bytes()).unwrap();
+        assert_eq!(signatures.len(), 1);
+        assert_eq!(signatures[0].name, "Test.Good");
+        assert_eq!(errors.iter().map(|e| e.line).collect::<Vec<_>>(), [4, 5, 6, 7, 8, 9]);
+        assert!(errors[2].message.contains("MD5, SHA-1 or SHA-256"));
+    }
+}
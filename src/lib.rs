@@ -0,0 +1,28 @@
+pub mod chunked_reader;
+pub mod clamav;
+pub mod compiled;
+pub mod database_dir;
+pub mod detection;
+pub mod detector;
+pub mod file_compare;
+pub mod fuzzy;
+pub mod hash_signatures;
+pub mod lint;
+pub mod logical;
+pub mod logging;
+pub mod matcher;
+pub mod metadata;
+pub mod pattern;
+pub mod policy;
+pub mod quarantine;
+pub mod rec_file_search;
+pub mod reload;
+pub mod report;
+pub mod signatures;
+pub mod signing;
+pub mod update;
+pub mod yara;
+
+pub use detection::Detection;
+pub use file_compare::{FileCompare, ScanOptions};
+pub use rec_file_search::{RecFileSearch, ScanError, ScanSink};
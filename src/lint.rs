@@ -0,0 +1,329 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+
+use fnv::{FnvHashMap, FnvHashSet};
+
+use crate::compiled::is_compiled;
+use crate::metadata::{read_definitions, DbFormat};
+use crate::pattern::{Element, Pattern};
+use crate::signatures::{parse_definition, parse_line, Anchor, Entries, Entry, Signature, SignatureSet};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LintKind {
+    Invalid,
+    // A name defined again, which replaces the earlier definition.
+    NameCollision,
+    Duplicate,
+    // Every match also matches another signature, so this one adds nothing.
+    Shadowed,
+    Short,
+    LowEntropy,
+}
+
+impl fmt::Display for LintKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LintKind::Invalid => "invalid",
+            LintKind::NameCollision => "name collision",
+            LintKind::Duplicate => "duplicate",
+            LintKind::Shadowed => "shadowed",
+            LintKind::Short => "short",
+            LintKind::LowEntropy => "low entropy",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LintFinding {
+    pub line: usize,
+    pub kind: LintKind,
+    pub message: String,
+}
+
+impl fmt::Display for LintFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}: {}", self.line, self.kind, self.message)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LintOptions {
+    // Fewest fully specified bytes a plain signature should have.
+    pub min_len: usize,
+    // Lowest Shannon entropy, in bits per byte, of those bytes.
+    pub min_entropy: f64,
+}
+
+impl Default for LintOptions {
+    fn default() -> LintOptions {
+        LintOptions { min_len: 20, min_entropy: 2.0 }
+    }
+}
+
+// The bytes a pattern always matches exactly; wildcards, jumps and
+// alternatives add nothing.
+fn fixed_bytes(pattern: &Pattern) -> Vec<u8> {
+    pattern
+        .elements()
+        .iter()
+        .filter_map(|element| match element {
+            Element::Byte(byte) => Some(*byte),
+            _ => None,
+        })
+        .collect()
+}
+
+fn entropy(bytes: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for &byte in bytes {
+        counts[byte as usize] += 1;
+    }
+    let total = bytes.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| count as f64 / total * (total / count as f64).log2())
+        .sum()
+}
+
+fn describe_range(start: usize, length: usize, total: usize) -> String {
+    match (start, start + length) {
+        (0, end) if end == total => "all of it".to_string(),
+        (0, _) => format!("its first {} bytes", length),
+        (_, end) if end == total => format!("its last {} bytes", length),
+        (_, end) => format!("its bytes {}-{}", start, end - 1),
+    }
+}
+
+// Checks a signature database for lines that don't load, names defined twice,
+// identical signatures, signatures whose bytes always contain a match of
+// another one, and plain signatures too short or repetitive to be specific.
+// Findings are ordered by line.
+pub fn lint_signatures<R: BufRead>(reader: R, options: &LintOptions) -> io::Result<Vec<LintFinding>> {
+    let mut parsed = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let trimmed = line.trim();
+        if !trimmed.is_empty() && !trimmed.starts_with('#') {
+            parsed.push((index + 1, parse_line(trimmed)));
+        }
+    }
+    Ok(lint_entries(parsed, options))
+}
+
+// The same checks for the enabled signatures of a structured database.
+pub fn lint_database(text: &str, format: DbFormat, options: &LintOptions) -> io::Result<Vec<LintFinding>> {
+    let (_, definitions) = read_definitions(text, format)?;
+    let parsed = definitions
+        .into_iter()
+        .filter(|definition| definition.enabled)
+        .map(|definition| (definition.line, parse_definition(&definition.name, &definition.pattern)))
+        .collect();
+    Ok(lint_entries(parsed, options))
+}
+
+fn lint_entries(parsed: Vec<(usize, Result<Entry, String>)>, options: &LintOptions) -> Vec<LintFinding> {
+    let mut findings = Vec::new();
+    // Line of the definition in effect for each name.
+    let mut lines: FnvHashMap<String, usize> = FnvHashMap::default();
+    let mut entries = Entries::default();
+    for (line, entry) in parsed {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(message) => {
+                findings.push(LintFinding { line, kind: LintKind::Invalid, message });
+                continue;
+            }
+        };
+        let name = entry.name().to_string();
+        if let Some(previous) = lines.insert(name.clone(), line) {
+            findings.push(LintFinding {
+                line,
+                kind: LintKind::NameCollision,
+                message: format!("{} is already defined on line {} and replaces it", name, previous),
+            });
+        }
+        entries.insert(entry);
+    }
+
+    // Logical signatures only get the checks above; their parts are meant to be
+    // weak on their own.
+    let (signatures, _) = entries.into_parts();
+    let line_of = |signature: &Signature| lines[&signature.name];
+
+    let mut first_by_body: FnvHashMap<String, &Signature> = FnvHashMap::default();
+    for signature in &signatures {
+        let body = format!("{}:{}", signature.anchor, signature.pattern);
+        match first_by_body.get(&body) {
+            Some(first) => findings.push(LintFinding {
+                line: line_of(signature),
+                kind: LintKind::Duplicate,
+                message: format!("{} is identical to {} (line {})", signature.name, first.name, line_of(first)),
+            }),
+            None => {
+                first_by_body.insert(body, signature);
+            }
+        }
+    }
+
+    for signature in &signatures {
+        let fixed = fixed_bytes(&signature.pattern);
+        if fixed.len() < options.min_len {
+            findings.push(LintFinding {
+                line: line_of(signature),
+                kind: LintKind::Short,
+                message: format!(
+                    "{} has only {} fixed bytes, fewer than {}",
+                    signature.name,
+                    fixed.len(),
+                    options.min_len
+                ),
+            });
+        }
+        let bits = entropy(&fixed);
+        if !fixed.is_empty() && bits < options.min_entropy {
+            findings.push(LintFinding {
+                line: line_of(signature),
+                kind: LintKind::LowEntropy,
+                message: format!(
+                    "{} has {:.2} bits of entropy per byte, less than {:.2}",
+                    signature.name, bits, options.min_entropy
+                ),
+            });
+        }
+    }
+
+    // Scanning the bytes of each literal signature finds every floating
+    // signature that matches wherever it does.
+    let set = SignatureSet::new(signatures.clone(), Vec::new());
+    for signature in &signatures {
+        let Some(bytes) = signature.pattern.literal_bytes() else {
+            continue;
+        };
+        let mut seen = FnvHashSet::default();
+        let mut logical = set.logical_matches(bytes.len() as u64);
+        set.scan(&bytes, 0, bytes.len() as u64, &mut logical, |other, start, length| {
+            let identical = other.pattern == signature.pattern && other.anchor == signature.anchor;
+            if other.name == signature.name || identical || other.anchor != Anchor::Anywhere {
+                return;
+            }
+            if seen.insert(&other.name) {
+                findings.push(LintFinding {
+                    line: line_of(signature),
+                    kind: LintKind::Shadowed,
+                    message: format!(
+                        "{} is shadowed by {} (line {}), which matches {}",
+                        signature.name,
+                        other.name,
+                        line_of(other),
+                        describe_range(start, length, bytes.len())
+                    ),
+                });
+            }
+        });
+    }
+    findings.sort_by_key(|finding| finding.line);
+    findings
+}
+
+pub fn lint_file(path: &str, options: &LintOptions) -> io::Result<Vec<LintFinding>> {
+    if is_compiled(path) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "compiled databases can't be linted, lint the source"));
+    }
+    match DbFormat::from_path(path) {
+        DbFormat::Legacy => {
+            let file = File::open(path)?;
+            lint_signatures(BufReader::new(file), options)
+        }
+        format => lint_database(&fs::read_to_string(path)?, format, options),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lint_database, lint_file, lint_signatures, LintFinding, LintKind, LintOptions};
+    use crate::metadata::DbFormat;
+
+    const FIXTURE: &str = "\
+# one finding of each kind
+Test.Good=000102030405060708090a0b0c0d0e0f10111213
+Test.Invalid=zz
+Test.Other=202122232425262728292a2b2c2d2e2f30313233
+Test.Copy=202122232425262728292a2b2c2d2e2f30313233
+Test.Long=ff000102030405060708090a0b0c0d0e0f10111213ee
+Test.Short=deadbeef
+Test.Flat=0000000000000000000001010101010101010101
+Test.Renamed=404142434445464748494a4b4c4d4e4f50515253
+Test.Renamed=606162636465666768696a6b6c6d6e6f70717273
+Test.Logic=0&1;aa;bb
+";
+
+    fn kinds(findings: &[LintFinding]) -> Vec<(usize, LintKind)> {
+        findings.iter().map(|finding| (finding.line, finding.kind)).collect()
+    }
+
+    #[test]
+    fn finds_each_kind() {
+        let findings = lint_signatures(FIXTURE.as_bytes(), &LintOptions::default()).unwrap();
+        assert_eq!(
+            kinds(&findings),
+            [
+                (3, LintKind::Invalid),
+                (5, LintKind::Duplicate),
+                (6, LintKind::Shadowed),
+                (7, LintKind::Short),
+                (8, LintKind::LowEntropy),
+                (10, LintKind::NameCollision),
+            ]
+        );
+        let messages: Vec<String> = findings.iter().map(ToString::to_string).collect();
+        assert_eq!(messages[1], "line 5: duplicate: Test.Copy is identical to Test.Other (line 4)");
+        assert_eq!(
+            messages[2],
+            "line 6: shadowed: Test.Long is shadowed by Test.Good (line 2), which matches its bytes 1-20"
+        );
+        assert_eq!(messages[3], "line 7: short: Test.Short has only 4 fixed bytes, fewer than 20");
+        assert_eq!(messages[4], "line 8: low entropy: Test.Flat has 1.00 bits of entropy per byte, less than 2.00");
+        assert_eq!(messages[5], "line 10: name collision: Test.Renamed is already defined on line 9 and replaces it");
+    }
+
+    #[test]
+    fn thresholds_are_options() {
+        let options = LintOptions { min_len: 4, min_entropy: 0.5 };
+        let findings = lint_signatures(FIXTURE.as_bytes(), &options).unwrap();
+        assert!(!kinds(&findings).iter().any(|&(_, kind)| kind == LintKind::Short || kind == LintKind::LowEntropy));
+    }
+
+    #[test]
+    fn structured_databases_skip_disabled_signatures() {
+        let text = r#"format = 1
+
+[[signature]]
+name = "Test.Good"
+pattern = "000102030405060708090a0b0c0d0e0f10111213"
+
+[[signature]]
+name = "Test.Off"
+pattern = "000102030405060708090a0b0c0d0e0f10111213"
+enabled = false
+
+[[signature]]
+name = "Test.Copy"
+pattern = "000102030405060708090a0b0c0d0e0f10111213"
+"#;
+        let findings = lint_database(text, DbFormat::Toml, &LintOptions::default()).unwrap();
+        assert_eq!(kinds(&findings), [(13, LintKind::Duplicate)]);
+    }
+
+    #[test]
+    fn test_database_findings() {
+        let findings = lint_file("Test_env/signatures.db", &LintOptions::default()).unwrap();
+        let count = |kind| findings.iter().filter(|finding| finding.kind == kind).count();
+        assert_eq!(findings.len(), 19);
+        assert_eq!(
+            [LintKind::Duplicate, LintKind::Shadowed, LintKind::Short, LintKind::LowEntropy].map(count),
+            [1, 10, 7, 1]
+        );
+    }
+}
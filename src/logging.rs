@@ -0,0 +1,86 @@
+use std::fs::{self, File};
+use std::io::{self, Write, BufWriter};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use once_cell::sync::Lazy;
+use crossbeam::channel;
+
+enum LogMessage {
+    Line(String),
+    Flush(channel::Sender<()>),
+}
+
+static LOG_CHANNEL: Lazy<(channel::Sender<LogMessage>, channel::Receiver<LogMessage>)> = Lazy::new(|| {
+    let (sender, receiver) = channel::unbounded();
+    (sender, receiver)
+});
+
+// Messages are dropped until a logging thread is running, so library users that
+// never start one don't accumulate an unbounded backlog.
+static LOGGING_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// The first write to the log file that failed. Later lines are dropped and
+// `write_to_log` reports it instead.
+static LOG_ERROR: Mutex<Option<String>> = Mutex::new(None);
+
+fn record_error(result: io::Result<()>) {
+    if let Err(e) = result {
+        LOG_ERROR.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).get_or_insert(e.to_string());
+    }
+}
+
+fn failed() -> bool {
+    LOG_ERROR.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).is_some()
+}
+
+pub fn start_logging_thread(directory: Arc<str>) -> io::Result<()> {
+    let (_, log_receiver) = &*LOG_CHANNEL;
+    let log_dir = format!("{}/logs", &*directory);
+    fs::create_dir_all(&log_dir)?;
+    let file = File::create(format!("{}/performance.log", log_dir))?;
+    let mut writer = BufWriter::new(file);
+    LOGGING_ENABLED.store(true, Ordering::Release);
+    std::thread::spawn(move || {
+        while let Ok(message) = log_receiver.recv() {
+            match message {
+                LogMessage::Line(line) => {
+                    if !failed() {
+                        record_error(writeln!(writer, "{}", line));
+                    }
+                }
+                LogMessage::Flush(done) => {
+                    if !failed() {
+                        record_error(writer.flush());
+                    }
+                    let _ = done.send(());
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+pub fn write_to_log(directory: &Arc<str>, message: &str) -> io::Result<()> {
+    if !LOGGING_ENABLED.load(Ordering::Acquire) {
+        return Ok(());
+    }
+    if let Some(e) = &*LOG_ERROR.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+        return Err(io::Error::other(format!("couldn't write the performance log: {}", e)));
+    }
+    let (log_sender, _) = &*LOG_CHANNEL;
+    log_sender
+        .send(LogMessage::Line(format!("{}: {}", directory, message)))
+        .map_err(|_| io::Error::other("the logging thread has stopped"))
+}
+
+// Blocks until every message sent so far has been written to disk.
+pub fn flush_log() {
+    if !LOGGING_ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    let (log_sender, _) = &*LOG_CHANNEL;
+    let (done_sender, done_receiver) = channel::bounded(1);
+    if log_sender.send(LogMessage::Flush(done_sender)).is_ok() {
+        let _ = done_receiver.recv();
+    }
+}
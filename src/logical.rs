@@ -0,0 +1,482 @@
+use std::fmt;
+
+use fnv::FnvHashMap;
+
+use crate::detection::{Detection, DetectionKind};
+use crate::signatures::Signature;
+use crate::yara::Rule;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    More,
+    Fewer,
+}
+
+// The condition of a logical signature over the matches of its sub-signatures.
+// An expression evaluates to a match count and holds when that is non-zero.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expression {
+    // `n`: how often sub-signature `n` matched.
+    Part(usize),
+    // `a&b`: the summed counts, or 0 unless every operand matched.
+    And(Vec<Expression>),
+    // `a|b`: the summed counts.
+    Or(Vec<Expression>),
+    // `a->b`: 1 when the sub-signatures match one after the other without overlapping.
+    Ordered(Vec<usize>),
+    // `a=n`, `a>n`, `a<n`: 1 when the count of `a` compares.
+    Count { operand: Box<Expression>, comparison: Comparison, count: u64 },
+}
+
+struct Parser<'t> {
+    text: &'t str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&mut self) -> Option<char> {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+        self.text[self.pos..].chars().next()
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.peek();
+        if self.text[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn number(&mut self) -> Result<u64, String> {
+        self.peek();
+        let rest = &self.text[self.pos..];
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if digits == 0 {
+            return Err(match rest.chars().next() {
+                Some(c) => format!("expected a number at '{}'", c),
+                None => "unexpected end of expression".to_string(),
+            });
+        }
+        self.pos += digits;
+        rest[..digits].parse().map_err(|_| format!("number too large: {}", &rest[..digits]))
+    }
+
+    fn or(&mut self) -> Result<Expression, String> {
+        let mut operands = vec![self.and()?];
+        while self.eat("|") {
+            operands.push(self.and()?);
+        }
+        Ok(if operands.len() == 1 { operands.remove(0) } else { Expression::Or(operands) })
+    }
+
+    fn and(&mut self) -> Result<Expression, String> {
+        let mut operands = vec![self.ordered()?];
+        while self.eat("&") {
+            operands.push(self.ordered()?);
+        }
+        Ok(if operands.len() == 1 { operands.remove(0) } else { Expression::And(operands) })
+    }
+
+    fn ordered(&mut self) -> Result<Expression, String> {
+        let first = self.count()?;
+        if !self.eat("->") {
+            return Ok(first);
+        }
+        let mut operands = vec![first, self.count()?];
+        while self.eat("->") {
+            operands.push(self.count()?);
+        }
+        let parts = operands
+            .into_iter()
+            .map(|operand| match operand {
+                Expression::Part(index) => Ok(index),
+                _ => Err("`->` only orders sub-signature indices".to_string()),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Expression::Ordered(parts))
+    }
+
+    fn count(&mut self) -> Result<Expression, String> {
+        let operand = self.primary()?;
+        let comparison = if self.eat("=") {
+            Comparison::Equal
+        } else if self.eat(">") {
+            Comparison::More
+        } else if self.eat("<") {
+            Comparison::Fewer
+        } else {
+            return Ok(operand);
+        };
+        let count = self.number()?;
+        Ok(Expression::Count { operand: Box::new(operand), comparison, count })
+    }
+
+    fn primary(&mut self) -> Result<Expression, String> {
+        if self.eat("(") {
+            let expression = self.or()?;
+            if !self.eat(")") {
+                return Err("missing ')'".to_string());
+            }
+            return Ok(expression);
+        }
+        let index = self.number()?;
+        usize::try_from(index).map(Expression::Part).map_err(|_| format!("number too large: {}", index))
+    }
+}
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Expression, String> {
+        let mut parser = Parser { text, pos: 0 };
+        let expression = parser.or()?;
+        match parser.peek() {
+            None => Ok(expression),
+            Some(c) => Err(format!("unexpected '{}' in expression", c)),
+        }
+    }
+
+    // Highest sub-signature index the expression refers to.
+    pub fn max_part(&self) -> usize {
+        match self {
+            Expression::Part(index) => *index,
+            Expression::And(operands) | Expression::Or(operands) => operands.iter().map(Expression::max_part).max().unwrap_or(0),
+            Expression::Ordered(parts) => parts.iter().copied().max().unwrap_or(0),
+            Expression::Count { operand, .. } => operand.max_part(),
+        }
+    }
+
+    // Counts the matches of sub-signature `extra` as matches of `index`, for a
+    // sub-signature that needs two patterns. `None` if `index` is ordered, as
+    // order only applies to single patterns.
+    pub fn merge_part(&self, index: usize, extra: usize) -> Option<Expression> {
+        let merge_all = |operands: &[Expression]| -> Option<Vec<Expression>> {
+            operands.iter().map(|operand| operand.merge_part(index, extra)).collect()
+        };
+        match self {
+            Expression::Part(part) if *part == index => Some(Expression::Or(vec![Expression::Part(index), Expression::Part(extra)])),
+            Expression::Part(_) => Some(self.clone()),
+            Expression::And(operands) => merge_all(operands).map(Expression::And),
+            Expression::Or(operands) => merge_all(operands).map(Expression::Or),
+            Expression::Ordered(parts) if parts.contains(&index) => None,
+            Expression::Ordered(_) => Some(self.clone()),
+            Expression::Count { operand, comparison, count } => Some(Expression::Count {
+                operand: Box::new(operand.merge_part(index, extra)?),
+                comparison: *comparison,
+                count: *count,
+            }),
+        }
+    }
+
+    // `hits` holds the sorted, distinct `(start, end)` offsets of each sub-signature.
+    pub fn evaluate(&self, hits: &[Vec<(u64, u64)>]) -> u64 {
+        match self {
+            Expression::Part(index) => hits.get(*index).map_or(0, |hits| hits.len() as u64),
+            Expression::And(operands) => {
+                let counts: Vec<u64> = operands.iter().map(|operand| operand.evaluate(hits)).collect();
+                if counts.contains(&0) { 0 } else { counts.iter().sum() }
+            }
+            Expression::Or(operands) => operands.iter().map(|operand| operand.evaluate(hits)).sum(),
+            Expression::Ordered(parts) => {
+                // Taking the earliest-ending match each time leaves the most room
+                // for the rest of the sequence.
+                let mut position = 0;
+                for &index in parts {
+                    let end = hits
+                        .get(index)
+                        .and_then(|hits| hits.iter().filter(|&&(start, _)| start >= position).map(|&(_, end)| end).min());
+                    match end {
+                        Some(end) => position = end,
+                        None => return 0,
+                    }
+                }
+                1
+            }
+            Expression::Count { operand, comparison, count } => {
+                let actual = operand.evaluate(hits);
+                let holds = match comparison {
+                    Comparison::Equal => actual == *count,
+                    Comparison::More => actual > *count,
+                    Comparison::Fewer => actual < *count,
+                };
+                holds as u64
+            }
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Expression::Or(_) => 0,
+            Expression::And(_) => 1,
+            Expression::Ordered(_) => 2,
+            Expression::Count { .. } => 3,
+            Expression::Part(_) => 4,
+        }
+    }
+
+    fn fmt_operand(&self, f: &mut fmt::Formatter<'_>, min_precedence: u8) -> fmt::Result {
+        if self.precedence() < min_precedence {
+            write!(f, "({})", self)
+        } else {
+            write!(f, "{}", self)
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expression::Part(index) => write!(f, "{}", index),
+            Expression::And(operands) | Expression::Or(operands) => {
+                let (separator, min_precedence) = if matches!(self, Expression::And(_)) { ("&", 1) } else { ("|", 2) };
+                for (i, operand) in operands.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "{}", separator)?;
+                    }
+                    operand.fmt_operand(f, min_precedence)?;
+                }
+                Ok(())
+            }
+            Expression::Ordered(parts) => {
+                let parts: Vec<String> = parts.iter().map(usize::to_string).collect();
+                write!(f, "{}", parts.join("->"))
+            }
+            Expression::Count { operand, comparison, count } => {
+                operand.fmt_operand(f, 4)?;
+                let operator = match comparison {
+                    Comparison::Equal => '=',
+                    Comparison::More => '>',
+                    Comparison::Fewer => '<',
+                };
+                write!(f, "{}{}", operator, count)
+            }
+        }
+    }
+}
+
+// What a logical signature requires of a file.
+#[derive(Clone, Debug)]
+pub enum Condition {
+    Expression(Expression),
+    Yara(Box<Rule>),
+}
+
+impl Condition {
+    fn holds(&self, facts: &FileFacts) -> bool {
+        match self {
+            Condition::Expression(expression) => expression.evaluate(facts.hits) > 0,
+            Condition::Yara(rule) => rule.holds(facts),
+        }
+    }
+
+    // Bytes from the start of the file the condition reads directly.
+    pub fn read_len(&self) -> u64 {
+        match self {
+            Condition::Expression(_) => 0,
+            Condition::Yara(rule) => rule.read_len(),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Expression(expression) => write!(f, "{}", expression),
+            Condition::Yara(rule) => write!(f, "{}", rule),
+        }
+    }
+}
+
+// What is known about a file once it has been scanned.
+pub struct FileFacts<'f> {
+    // Sorted, distinct `(start, end)` offsets of each sub-signature's matches.
+    pub hits: &'f [Vec<(u64, u64)>],
+    pub size: u64,
+    // The first bytes of the file, as far as any condition reads them.
+    pub header: &'f [u8],
+}
+
+// A detection that fires only when its condition holds over the matches of its
+// sub-signatures. Written `name=expression;sub0;sub1;...` in a signature
+// database, or compiled from a YARA rule.
+#[derive(Clone, Debug)]
+pub struct LogicalSignature {
+    pub name: String,
+    pub condition: Condition,
+    pub subsignatures: Vec<Signature>,
+}
+
+impl LogicalSignature {
+    pub fn new(name: String, expression: Expression, subsignatures: Vec<Signature>) -> Result<LogicalSignature, String> {
+        if expression.max_part() >= subsignatures.len() {
+            return Err(format!(
+                "expression refers to sub-signature {} but only {} are given",
+                expression.max_part(),
+                subsignatures.len()
+            ));
+        }
+        if expression.evaluate(&vec![Vec::new(); subsignatures.len()]) > 0 {
+            return Err(format!("expression {} matches files without any sub-signature", expression));
+        }
+        Ok(LogicalSignature { name, condition: Condition::Expression(expression), subsignatures })
+    }
+
+    pub fn kind(&self) -> DetectionKind {
+        match self.condition {
+            Condition::Expression(_) => DetectionKind::Logical,
+            Condition::Yara(_) => DetectionKind::Yara,
+        }
+    }
+}
+
+// Sub-signature matches collected while one file is scanned; the conditions
+// are only evaluated once the whole file has been seen.
+pub struct LogicalMatches<'a> {
+    signatures: &'a [LogicalSignature],
+    size: u64,
+    hits: FnvHashMap<usize, Vec<Vec<(u64, u64)>>>,
+    // The earliest sub-signature match of each logical signature, reported as
+    // its location.
+    first: FnvHashMap<usize, Detection>,
+    header: Vec<u8>,
+    header_len: usize,
+}
+
+impl<'a> LogicalMatches<'a> {
+    // `header_len` is how much of the start of the file the conditions read.
+    pub fn new(signatures: &'a [LogicalSignature], size: u64, header_len: usize) -> LogicalMatches<'a> {
+        LogicalMatches {
+            signatures,
+            size,
+            hits: FnvHashMap::default(),
+            first: FnvHashMap::default(),
+            header: Vec::new(),
+            header_len,
+        }
+    }
+
+    // Keeps the part of the header in `data`, which begins at file offset `base`.
+    // Windows arrive in file order, so the header fills up front to back.
+    pub fn capture(&mut self, data: &[u8], base: u64) {
+        let have = self.header.len() as u64;
+        if have >= self.header_len as u64 || base > have {
+            return;
+        }
+        let from = (have - base) as usize;
+        let to = data.len().min(self.header_len - base as usize);
+        if from < to {
+            self.header.extend_from_slice(&data[from..to]);
+        }
+    }
+
+    // `start` is the match position within `data`, which begins at file offset `base`.
+    pub fn record(&mut self, logical: usize, index: usize, data: &[u8], start: usize, length: usize, base: u64) {
+        let signature = &self.signatures[logical];
+        let offset = base + start as u64;
+        self.hits
+            .entry(logical)
+            .or_insert_with(|| vec![Vec::new(); signature.subsignatures.len()])[index]
+            .push((offset, offset + length as u64));
+        if self.first.get(&logical).is_none_or(|first| offset < first.offset) {
+            let detection = Detection { kind: signature.kind(), ..Detection::new(&signature.name, length, data, start, base) };
+            self.first.insert(logical, detection);
+        }
+    }
+
+    pub fn finish(mut self, detections: &mut Vec<Detection>) {
+        for (logical, signature) in self.signatures.iter().enumerate() {
+            // Logical expressions can't hold without matches; YARA conditions
+            // like `filesize < 100` can.
+            let mut hits = match self.hits.remove(&logical) {
+                Some(hits) => hits,
+                None if matches!(signature.condition, Condition::Yara(_)) => vec![Vec::new(); signature.subsignatures.len()],
+                None => continue,
+            };
+            // Overlapping scan windows can report the same match twice.
+            for part in &mut hits {
+                part.sort_unstable();
+                part.dedup_by_key(|&mut (start, _)| start);
+            }
+            let facts = FileFacts { hits: &hits, size: self.size, header: &self.header };
+            if signature.condition.holds(&facts) {
+                let detection = self.first.remove(&logical);
+                detections.push(detection.unwrap_or_else(|| Detection::whole_file(&signature.name, signature.kind(), self.size)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Comparison, Expression, LogicalSignature};
+    use crate::pattern::Pattern;
+    use crate::signatures::{Anchor, Signature};
+
+    fn hits(counts: &[&[(u64, u64)]]) -> Vec<Vec<(u64, u64)>> {
+        counts.iter().map(|hits| hits.to_vec()).collect()
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let expression = Expression::parse("0|1&2").unwrap();
+        assert_eq!(
+            expression,
+            Expression::Or(vec![Expression::Part(0), Expression::And(vec![Expression::Part(1), Expression::Part(2)])])
+        );
+        assert_eq!(expression.max_part(), 2);
+    }
+
+    #[test]
+    fn display_parses_back() {
+        for text in ["0|1&2", "(0|1)&2", "0->1->2", "(0|1)>2", "0=1&1<3", "((0&1)|2)&3"] {
+            let expression = Expression::parse(text).unwrap();
+            assert_eq!(Expression::parse(&expression.to_string()).unwrap(), expression, "{}", text);
+        }
+        let count = Expression::parse("(0|1)>2").unwrap();
+        assert!(matches!(count, Expression::Count { comparison: Comparison::More, count: 2, .. }));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for text in ["", "0&", "(0|1", "0)", "a", "(0|1)->2", "0>"] {
+            assert!(Expression::parse(text).is_err(), "{:?} parsed", text);
+        }
+    }
+
+    #[test]
+    fn evaluates_counts() {
+        let hits = hits(&[&[(0, 2), (5, 7)], &[], &[(9, 10)]]);
+        assert_eq!(Expression::parse("0&2").unwrap().evaluate(&hits), 3);
+        assert_eq!(Expression::parse("0&1").unwrap().evaluate(&hits), 0);
+        assert_eq!(Expression::parse("0|1").unwrap().evaluate(&hits), 2);
+        assert_eq!(Expression::parse("0=2").unwrap().evaluate(&hits), 1);
+        assert_eq!(Expression::parse("0>2").unwrap().evaluate(&hits), 0);
+        assert_eq!(Expression::parse("1<1").unwrap().evaluate(&hits), 1);
+        assert_eq!(Expression::parse("5").unwrap().evaluate(&hits), 0);
+    }
+
+    #[test]
+    fn ordered_parts_must_not_overlap() {
+        let ordered = Expression::parse("0->1").unwrap();
+        assert_eq!(ordered.evaluate(&hits(&[&[(0, 4)], &[(4, 6)]])), 1);
+        assert_eq!(ordered.evaluate(&hits(&[&[(0, 4)], &[(3, 6)]])), 0);
+        assert_eq!(ordered.evaluate(&hits(&[&[(5, 8)], &[(0, 2)]])), 0);
+        assert_eq!(ordered.evaluate(&hits(&[&[(0, 9), (1, 2)], &[(3, 4)]])), 1);
+    }
+
+    #[test]
+    fn merge_part_counts_the_second_form() {
+        let merged = Expression::parse("0&1").unwrap().merge_part(0, 2).unwrap();
+        assert_eq!(merged.evaluate(&hits(&[&[], &[(0, 1)], &[(4, 5)]])), 2);
+        assert!(Expression::parse("0->1").unwrap().merge_part(1, 2).is_none());
+    }
+
+    #[test]
+    fn subsignatures_must_cover_the_expression() {
+        let part = Signature { name: "L".to_string(), pattern: Pattern::literal(b"ab"), anchor: Anchor::Anywhere };
+        assert!(LogicalSignature::new("L".to_string(), Expression::parse("0&1").unwrap(), vec![part.clone()]).is_err());
+        assert!(LogicalSignature::new("L".to_string(), Expression::parse("0").unwrap(), vec![part]).is_ok());
+    }
+}
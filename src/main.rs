@@ -1,163 +1,1027 @@
+use std::collections::BTreeMap;
 use std::fs::{self, File};
-use std::io::{self, BufRead, BufReader, Write, BufWriter};
-use std::time::{Instant, Duration};
-use std::sync::{Arc, Mutex};
-use walkdir::WalkDir;
-use rayon::prelude::*;
-use fnv::FnvHashMap;
-use once_cell::sync::Lazy;
-use crossbeam::channel;
-
-pub struct FileCompare {
-    database: FnvHashMap<String, String>,
-    risk_files: FnvHashMap<String, String>,
-}
-
-impl FileCompare {
-    pub fn new(database_path: &str) -> io::Result<FileCompare> {
-        let mut file_compare = FileCompare {
-            database: FnvHashMap::default(),
-            risk_files: FnvHashMap::default(),
-        };
-        file_compare.read_signatures(database_path)?;
-        Ok(file_compare)
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use anti_virus::clamav::{parse_clamav_database, read_clamav_database, ClamavDatabase, ClamavFormat};
+use anti_virus::compiled::{compile_database, compiled_path, is_compiled, verify_compiled};
+use anti_virus::fuzzy::{parse_fuzzy_signatures, FuzzyDatabase, FuzzyHash, FuzzyHasher};
+use anti_virus::hash_signatures::{parse_hash_signatures, HashDatabase};
+use anti_virus::lint::{lint_file, LintOptions};
+use anti_virus::logical::Condition;
+use anti_virus::logging::{flush_log, start_logging_thread};
+use anti_virus::policy::{Action, Policy, Responder};
+use anti_virus::quarantine::Vault;
+use anti_virus::file_compare::Databases;
+use anti_virus::rec_file_search::ScanError;
+use anti_virus::reload::{Reload, Reloader, Trigger};
+use anti_virus::report::{format_time, text_signature_counts, NdjsonWriter, ReportFormat, ScanReport};
+use anti_virus::signatures::Anchor;
+use anti_virus::signing::{generate_key, is_untrusted, public_key_path, sign_database, signature_path, TrustedKeys, Verification};
+use anti_virus::update::{rollback_database, update_database, UpdateOutcome};
+use anti_virus::yara::parse_yara_rules;
+use anti_virus::{Detection, FileCompare, RecFileSearch, ScanOptions, ScanSink};
+
+const EXIT_CLEAN: u8 = 0;
+const EXIT_INFECTED: u8 = 1;
+const EXIT_ERROR: u8 = 2;
+
+#[derive(Parser)]
+#[command(name = "anti_virus", version, about = "Signature based file scanner")]
+struct Cli {
+    /// Signature database to load: `name=hex` lines, TOML/JSON with metadata (.toml, .json), or a
+    /// directory of databases loaded in file name order, `*.local.*` overrides last and `.ign` files
+    /// listing signatures to disable
+    #[arg(long, global = true, default_value = "signatures.db")]
+    db: String,
+
+    /// Whole-file hash database (`hash:size:name`), may be repeated. Defaults to
+    /// the .hdb and .hsb files next to --db
+    #[arg(long = "hash-db", global = true, value_name = "PATH")]
+    hash_dbs: Vec<String>,
+
+    /// Fuzzy hash database (`name:threshold:ssdeep`), may be repeated. Defaults to
+    /// the .fdb file next to --db
+    #[arg(long = "fuzzy-db", global = true, value_name = "PATH")]
+    fuzzy_dbs: Vec<String>,
+
+    /// YARA rule file, may be repeated. Defaults to the .yar and .yara files next
+    /// to --db
+    #[arg(long = "yara", global = true, value_name = "PATH")]
+    yara_rules: Vec<String>,
+
+    /// ClamAV database (.hdb, .hsb, .ndb or .ldb) to load as well, may be repeated
+    #[arg(long = "clamav", global = true, value_name = "PATH")]
+    clamav_dbs: Vec<String>,
+
+    /// Public keys databases must be signed with, one hex key per line
+    #[arg(long, global = true, value_name = "PATH", default_value = "trusted_keys")]
+    trusted_keys: String,
+
+    /// Load databases that aren't signed with a trusted key, or whose signature doesn't match
+    #[arg(long, global = true)]
+    allow_unsigned: bool,
+
+    /// Number of worker threads (defaults to one per core)
+    #[arg(short = 'j', long, global = true)]
+    threads: Option<usize>,
+
+    /// Print more detail, repeat for even more
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Only report errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Scan files and directories for known signatures
+    Scan(ScanArgs),
+    /// Inspect the signature database
+    #[command(subcommand)]
+    Db(DbCommand),
+    /// Rescan files and directories periodically, reloading the databases on SIGHUP or when they change
+    Watch {
+        /// Files or directories to scan
+        #[arg(required = true)]
+        roots: Vec<String>,
+
+        /// Seconds between scans
+        #[arg(long, default_value_t = 60)]
+        interval: u64,
+    },
+    /// Manage files moved into the quarantine vault
+    Quarantine {
+        /// Quarantine vault directory
+        #[arg(long, default_value = "quarantine")]
+        vault: PathBuf,
+        #[command(subcommand)]
+        action: QuarantineCommand,
+    },
+    /// Summarize an earlier scan report, or convert a JSON/NDJSON report to another format
+    Report {
+        /// Report written by an earlier scan
+        #[arg(default_value = "logs/risk_files.log")]
+        input: String,
+
+        /// Convert the report into this format instead of summarizing it
+        #[arg(short, long, value_enum)]
+        format: Option<ReportFormat>,
+
+        /// Where to write the converted report (stdout by default)
+        #[arg(long)]
+        out: Option<String>,
+    },
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("response").args(["quarantine", "policy"]).multiple(true)))]
+struct ScanArgs {
+    /// Files or directories to scan
+    #[arg(required = true)]
+    roots: Vec<String>,
+
+    /// Directory that receives logs/risk_files.<ext> and logs/performance.log
+    #[arg(short, long, default_value = ".")]
+    output: String,
+
+    /// Format of the scan report
+    #[arg(short, long, value_enum, default_value_t = ReportFormat::Text)]
+    format: ReportFormat,
+
+    /// Write the report here instead of the output directory, `-` for stdout
+    #[arg(long)]
+    report: Option<String>,
+
+    /// Read buffer size per worker in bytes
+    #[arg(long)]
+    chunk_size: Option<usize>,
+
+    /// Memory-map files of at least this many bytes instead of reading them in chunks
+    #[arg(long)]
+    mmap_threshold: Option<u64>,
+
+    /// Move detected files into this quarantine vault (unless a policy decides otherwise)
+    #[arg(long, value_name = "VAULT")]
+    quarantine: Option<PathBuf>,
+
+    /// Response policy deciding what happens to each detected file
+    #[arg(long)]
+    policy: Option<PathBuf>,
+
+    /// Only print the actions the policy would take
+    #[arg(long, requires = "response")]
+    dry_run: bool,
+}
+
+#[derive(Subcommand)]
+enum QuarantineCommand {
+    /// List quarantined files
+    List,
+    /// Put a quarantined file back where it came from
+    Restore {
+        id: String,
+        /// Restore to this path instead of the original location
+        #[arg(long)]
+        to: Option<PathBuf>,
+        /// Replace an existing file at the destination
+        #[arg(long)]
+        force: bool,
+    },
+    /// Permanently delete quarantined files
+    Purge {
+        /// Entries to delete
+        #[arg(required_unless_present = "all")]
+        ids: Vec<String>,
+        /// Delete everything in the vault
+        #[arg(long, conflicts_with = "ids")]
+        all: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum DbCommand {
+    /// Validate the database and report invalid lines
+    Check,
+    /// List the loaded signatures
+    List,
+    /// Report duplicate, shadowed, weak and colliding signatures in the database
+    Lint {
+        /// Flag plain signatures with fewer fully specified bytes than this
+        #[arg(long, default_value_t = LintOptions::default().min_len)]
+        min_len: usize,
+
+        /// Flag plain signatures whose bytes have less entropy than this, in bits per byte
+        #[arg(long, default_value_t = LintOptions::default().min_entropy)]
+        min_entropy: f64,
+    },
+    /// Compile the database into a binary form the scanner maps without parsing
+    Compile {
+        /// Where to write it, by default next to the database with an .avdb extension
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Sign the database, or the given files, writing a detached .sig next to each
+    Sign {
+        /// Private key from `db keygen`
+        #[arg(short, long)]
+        key: PathBuf,
+
+        files: Vec<String>,
+    },
+    /// Create a signing key pair: the private key at KEY and the public key at KEY.pub
+    Keygen {
+        key: PathBuf,
+    },
+    /// Update the database from a mirror, keeping the version it replaces for `db rollback`
+    Update {
+        /// Directory or http:// URL with the mirror's manifest.json, by default the mirror of the last update
+        #[arg(long)]
+        mirror: Option<String>,
+
+        /// How many replaced versions to keep, at least one so the update can be rolled back
+        #[arg(long, default_value_t = 3, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        keep: usize,
+    },
+    /// Put back the version the last update replaced
+    Rollback,
+    /// Translate ClamAV databases and report what could not be loaded as written
+    Import {
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        /// Write the translated .ndb and .ldb signatures to this signature database
+        #[arg(long, value_name = "PATH")]
+        out: Option<String>,
+    },
+    /// Print fuzzy hash database lines (`name:threshold:ssdeep`) for files
+    FuzzyHash {
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        /// Signature name for every line, by default the name of each file
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Lowest similarity, 1 to 100, that counts as a match
+        #[arg(long, default_value_t = 80, value_parser = clap::value_parser!(u8).range(1..=100))]
+        threshold: u8,
+    },
+}
+
+struct Verbosity(i8);
+
+impl Verbosity {
+    fn normal(&self) -> bool {
+        self.0 >= 0
+    }
+
+    fn verbose(&self) -> bool {
+        self.0 >= 1
+    }
+}
+
+// The explicitly given files, or otherwise whichever files next to --db with
+// one of `extensions` exist. A directory given as --db holds its own.
+fn companion_paths(cli: &Cli, explicit: &[String], extensions: &[&str]) -> Vec<String> {
+    if !explicit.is_empty() || Path::new(&cli.db).is_dir() {
+        return explicit.to_vec();
+    }
+    extensions
+        .iter()
+        .map(|ext| Path::new(&cli.db).with_extension(ext))
+        .filter(|path| path.is_file())
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect()
+}
+
+// Databases must be signed with a key from --trusted-keys unless
+// --allow-unsigned is given. A missing trusted keys file trusts no one.
+fn verification(cli: &Cli) -> io::Result<Verification> {
+    if cli.allow_unsigned {
+        return Ok(Verification::Skipped);
+    }
+    let keys = match TrustedKeys::read(&cli.trusted_keys) {
+        Ok(keys) => keys,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => TrustedKeys::default(),
+        Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", cli.trusted_keys, e))),
+    };
+    Ok(Verification::Required(Arc::new(keys)))
+}
+
+fn database_error(path: &str, e: io::Error) -> io::Error {
+    if is_untrusted(&e) {
+        return io::Error::new(e.kind(), format!("{}: {} (--allow-unsigned loads it anyway)", path, e));
+    }
+    io::Error::new(e.kind(), format!("{}: {}", path, e))
+}
+
+// Loads the pattern, hash and fuzzy hash databases, YARA rules and ClamAV
+// databases, printing invalid lines. Every one of them has to pass the
+// signature check. Also returns how many lines were invalid across all of
+// them.
+fn load_database(cli: &Cli) -> io::Result<(FileCompare, usize)> {
+    let verification = verification(cli)?;
+    let read = |path: &str| verification.read(path).map_err(|e| database_error(path, e));
+    let mut comparer = FileCompare::new(&cli.db, &verification).map_err(|e| database_error(&cli.db, e))?;
+    let mut invalid = comparer.get_load_errors().len();
+    for (file, error) in comparer.get_load_errors() {
+        eprintln!("{}: {}", file, error);
     }
+    for (file, warning) in comparer.get_load_warnings() {
+        eprintln!("{}: {}", file, warning);
+    }
+    let mut signatures = Vec::new();
+    let mut logical = Vec::new();
+    let mut hash_signatures = comparer.get_hash_database().map_or_else(Vec::new, |hashes| hashes.signatures().to_vec());
+    for path in &cli.clamav_dbs {
+        let format = ClamavFormat::from_path(path).ok_or_else(|| {
+            database_error(
+                path,
+                io::Error::new(io::ErrorKind::InvalidInput, "not a ClamAV .hdb, .hsb, .ndb or .ldb database"),
+            )
+        })?;
+        let database = parse_clamav_database(&read(path)?[..], format).map_err(|e| database_error(path, e))?;
+        for skipped in &database.skipped {
+            eprintln!("{}: {}", path, skipped);
+        }
+        if !database.skipped.is_empty() || !database.downgraded.is_empty() {
+            eprintln!("{}: {} (see `db import` for details)", path, import_summary(&database));
+        }
+        signatures.extend(database.signatures);
+        logical.extend(database.logical);
+        hash_signatures.extend(database.hashes);
+    }
+    for path in companion_paths(cli, &cli.hash_dbs, &["hdb", "hsb"]) {
+        let (signatures, errors) = parse_hash_signatures(&read(&path)?[..]).map_err(|e| database_error(&path, e))?;
+        for error in &errors {
+            eprintln!("{}: {}", path, error);
+        }
+        invalid += errors.len();
+        hash_signatures.extend(signatures);
+    }
+    comparer.set_hash_database(Arc::new(HashDatabase::new(hash_signatures)));
+    let mut fuzzy_signatures = comparer.get_fuzzy_database().map_or_else(Vec::new, |fuzzy| fuzzy.signatures().to_vec());
+    for path in companion_paths(cli, &cli.fuzzy_dbs, &["fdb"]) {
+        let (signatures, errors) = parse_fuzzy_signatures(&read(&path)?[..]).map_err(|e| database_error(&path, e))?;
+        for error in &errors {
+            eprintln!("{}: {}", path, error);
+        }
+        invalid += errors.len();
+        fuzzy_signatures.extend(signatures);
+    }
+    comparer.set_fuzzy_database(Arc::new(FuzzyDatabase::new(fuzzy_signatures)));
+    for path in companion_paths(cli, &cli.yara_rules, &["yar", "yara"]) {
+        let source = String::from_utf8(read(&path)?).map_err(|e| {
+            database_error(&path, io::Error::new(io::ErrorKind::InvalidData, e.utf8_error()))
+        })?;
+        let (compiled, errors) = parse_yara_rules(&source);
+        for error in &errors {
+            eprintln!("{}: {}", path, error);
+        }
+        invalid += errors.len();
+        logical.extend(compiled);
+    }
+    comparer.add_signatures(signatures, logical);
+    Ok((comparer, invalid))
+}
 
-    fn read_signatures(&mut self, path: &str) -> io::Result<()> {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        for line in reader.lines() {
-            let line = line?;
-            let parts: Vec<&str> = line.split('=').collect();
-            if parts.len() == 2 {
-                self.database.insert(parts[0].to_string(), parts[1].to_string());
+fn import_summary(database: &ClamavDatabase) -> String {
+    format!(
+        "{} signatures loaded ({} downgraded), {} skipped",
+        database.loaded(),
+        database.downgraded.len(),
+        database.skipped.len()
+    )
+}
+
+fn yara_rule_count(comparer: &FileCompare) -> usize {
+    let signatures = comparer.get_signatures();
+    signatures.logical().iter().filter(|signature| matches!(signature.condition, Condition::Yara(_))).count()
+}
+
+fn run_scan(cli: &Cli, args: &ScanArgs, verbosity: &Verbosity) -> io::Result<RecFileSearch> {
+    let (mut comparer, _) = load_database(cli)?;
+    let mut options = ScanOptions::default();
+    if let Some(chunk_size) = args.chunk_size {
+        options.chunk_size = chunk_size;
+    }
+    options.mmap_threshold = args.mmap_threshold;
+    comparer.set_options(options);
+    let policy = match (&args.policy, &args.quarantine) {
+        (Some(path), vault) => {
+            let mut policy = Policy::load(path)?;
+            if let Some(vault) = vault {
+                policy.set_vault(vault.clone());
             }
+            Some(policy)
+        }
+        (None, Some(vault)) => {
+            let mut policy = Policy::always(Action::Quarantine);
+            policy.set_vault(vault.clone());
+            Some(policy)
+        }
+        (None, None) => None,
+    };
+    if let Some(policy) = policy {
+        comparer.set_responder(Responder::new(policy, args.dry_run));
+    }
+    if verbosity.verbose() {
+        let rules = yara_rule_count(&comparer);
+        println!("Loaded {} signatures from {}", comparer.get_signatures().len() - rules, cli.db);
+        if rules > 0 {
+            println!("Loaded {} YARA rules", rules);
+        }
+        if let Some(hashes) = comparer.get_hash_database() {
+            println!("Loaded {} hash signatures", hashes.len());
+        }
+        if let Some(fuzzy) = comparer.get_fuzzy_database() {
+            println!("Loaded {} fuzzy hash signatures", fuzzy.len());
         }
-        Ok(())
     }
 
-    pub fn compare(&mut self, path: &str) -> io::Result<()> {
-        let file_bytes = fs::read(path)?;
-        for (name, signature) in &self.database {
-            let signature_bytes = hex::decode(signature).expect("Invalid hex in signature");
-            if file_bytes.starts_with(&signature_bytes) {
-                self.risk_files.insert(path.to_owned(), name.to_owned());
-                break;
+    let report_path = args
+        .report
+        .clone()
+        .unwrap_or_else(|| format!("{}/logs/risk_files.{}", args.output, args.format.extension()));
+    // Keep stdout clean for the report itself.
+    let verbosity = if report_path == "-" { &Verbosity(-1) } else { verbosity };
+    let mut writer = open_output(Some(&report_path))?;
+
+    let mut search = RecFileSearch::with_roots(args.roots.clone(), comparer);
+    start_logging_thread(Arc::from(args.output.as_str()))?;
+    let total_runtime = if args.format == ReportFormat::Ndjson {
+        let mut ndjson = NdjsonWriter::new(&mut writer);
+        ndjson.begin(&search)?;
+        let total_runtime = search.start_with_sink(&mut ndjson)?;
+        ndjson.finish(&ScanReport::from_search(&search))?;
+        total_runtime
+    } else {
+        let total_runtime = search.start()?;
+        ScanReport::from_search(&search).write(args.format, &mut writer)?;
+        total_runtime
+    };
+    writer.flush()?;
+    flush_log();
+
+    if verbosity.verbose() {
+        for file in search.get_files() {
+            println!("Scanned: {}", file);
+        }
+    }
+    for error in search.get_errors() {
+        eprintln!("{}: {}", error.path, error.message);
+    }
+    if verbosity.normal() {
+        for (path, detections) in search.get_tester().get_risk_files() {
+            for detection in detections {
+                match detection.score {
+                    Some(score) => println!("{}: {} ({}% similar)", path, detection.signature, score),
+                    None => println!("{}: {} at offset {}", path, detection.signature, detection.offset),
+                }
             }
         }
-        Ok(())
+        println!(
+            "Scanned {} files in {} directories, {} infected, {} errors in {:?}",
+            search.get_files().len(),
+            search.get_dirs().len(),
+            search.get_tester().get_risk_files().len(),
+            search.get_errors().len(),
+            total_runtime
+        );
     }
+    Ok(search)
+}
 
-    pub fn get_database(&self) -> &FnvHashMap<String, String> {
-        &self.database
+// Every file the databases are loaded from, so `watch` notices when one changes.
+fn database_paths(cli: &Cli) -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(&cli.db), signature_path(&cli.db), compiled_path(&cli.db)];
+    let companions = [
+        companion_paths(cli, &cli.hash_dbs, &["hdb", "hsb"]),
+        companion_paths(cli, &cli.fuzzy_dbs, &["fdb"]),
+        companion_paths(cli, &cli.yara_rules, &["yar", "yara"]),
+        cli.clamav_dbs.clone(),
+    ];
+    for path in companions.concat() {
+        paths.push(signature_path(&path));
+        paths.push(PathBuf::from(path));
     }
+    paths.push(PathBuf::from(&cli.trusted_keys));
+    paths
+}
 
-    pub fn get_risk_files(&self) -> &FnvHashMap<String, String> {
-        &self.risk_files
+fn version_name(version: &str) -> &str {
+    if version.is_empty() {
+        "unversioned"
+    } else {
+        version
     }
+}
 
-    pub fn log_risk_files(&self, directory: &str) -> io::Result<()> {
-        let log_file = format!("{}/logs/risk_files.log", directory);
-        let file = File::create(&log_file)?;
-        let mut writer = BufWriter::new(file);
-        for (path, name) in &self.risk_files {
-            writeln!(writer, "Risky file: {} - Signature: {}", path, name)?;
+fn log_reload(trigger: Trigger, reload: &Reload) {
+    let reason = match trigger {
+        Trigger::Requested => "SIGHUP",
+        Trigger::Changed => "database changed",
+    };
+    let time = format_time(SystemTime::now());
+    match reload {
+        Reload::Swapped { old_version, new_version } => eprintln!(
+            "{} reloaded databases ({}): version {} -> {}",
+            time,
+            reason,
+            version_name(old_version),
+            version_name(new_version)
+        ),
+        Reload::Failed { version, error } => eprintln!(
+            "{} reload failed ({}), keeping version {}: {}",
+            time,
+            reason,
+            version_name(version),
+            error
+        ),
+    }
+}
+
+// Prints files as they are found infected, each one only once until its
+// detections change.
+#[derive(Default)]
+struct WatchSink {
+    reported: BTreeMap<String, Vec<String>>,
+}
+
+impl ScanSink for WatchSink {
+    fn file_scanned(&mut self, path: &str, detections: &[Detection]) -> io::Result<()> {
+        if detections.is_empty() {
+            self.reported.remove(path);
+            return Ok(());
+        }
+        let names: Vec<String> = detections.iter().map(|detection| detection.signature.clone()).collect();
+        if self.reported.get(path) == Some(&names) {
+            return Ok(());
+        }
+        for detection in detections {
+            match detection.score {
+                Some(score) => println!("{}: {} ({}% similar)", path, detection.signature, score),
+                None => println!("{}: {} at offset {}", path, detection.signature, detection.offset),
+            }
         }
+        self.reported.insert(path.to_string(), names);
         Ok(())
     }
+
+    fn scan_error(&mut self, error: &ScanError) -> io::Result<()> {
+        eprintln!("{}: {}", error.path, error.message);
+        Ok(())
+    }
+}
+
+// The compiled databases a load of --db may read.
+fn compiled_databases(cli: &Cli) -> Vec<String> {
+    let db = Path::new(&cli.db);
+    if db.is_dir() {
+        let Ok(entries) = fs::read_dir(db) else {
+            return Vec::new();
+        };
+        return entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "avdb"))
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+    }
+    if is_compiled(&cli.db) {
+        return vec![cli.db.clone()];
+    }
+    let compiled = compiled_path(&cli.db);
+    if compiled.is_file() {
+        vec![compiled.to_string_lossy().into_owned()]
+    } else {
+        Vec::new()
+    }
 }
 
-pub struct RecFileSearch {
-    directory: Arc<str>,
-    found_files: Arc<Mutex<Vec<Arc<str>>>>,
-    found_dirs: Arc<Mutex<Vec<Arc<str>>>>,
-    tester: Arc<Mutex<FileCompare>>,
-}
-
-impl RecFileSearch {
-    pub fn new(directory: String, tester: FileCompare) -> RecFileSearch {
-        RecFileSearch {
-            directory: Arc::from(directory),
-            found_files: Arc::new(Mutex::new(Vec::new())),
-            found_dirs: Arc::new(Mutex::new(Vec::new())),
-            tester: Arc::new(Mutex::new(tester)),
-        }
-    }
-
-    pub fn start(&mut self) -> io::Result<Duration> {
-        let start_time = Instant::now();
-        let directory = Arc::clone(&self.directory);
-        let found_files = Arc::clone(&self.found_files);
-        let found_dirs = Arc::clone(&self.found_dirs);
-        let tester = Arc::clone(&self.tester);
-        WalkDir::new(&*directory).into_iter().par_bridge().for_each(move |entry| {
-            let entry = entry.expect("Failed to read directory entry");
-            if entry.file_type().is_dir() {
-                let dir_path: Arc<str> = Arc::from(entry.path().to_string_lossy().into_owned());
-                found_dirs.lock().unwrap().push(dir_path.clone());
-                let dir_start_time = Instant::now();
-                // Process directory contents recursively if needed
-                // ...
-                let dir_elapsed = dir_start_time.elapsed();
-                write_to_log(&directory, &format!("Directory: {} - Time: {:?}", dir_path, dir_elapsed)).unwrap();
-            } else if entry.file_type().is_file() {
-                let file_path: Arc<str> = Arc::from(entry.path().to_string_lossy().into_owned());
-                found_files.lock().unwrap().push(file_path.clone());
-                let file_start_time = Instant::now();
-                tester.lock().unwrap().compare(&file_path).unwrap();
-                let file_elapsed = file_start_time.elapsed();
-                write_to_log(&directory, &format!("File: {} - Time: {:?}", file_path, file_elapsed)).unwrap();
+// Loads the databases for `watch` to swap in. Unlike at startup, anything short
+// of a clean load is refused: a line that doesn't load would silently drop its
+// signature, and a damaged compiled database would only fail once scanned.
+fn reload_databases(cli: &Cli) -> io::Result<Databases> {
+    for path in compiled_databases(cli) {
+        verify_compiled(&path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    }
+    let (comparer, invalid) = load_database(cli)?;
+    if invalid > 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} invalid lines", invalid)));
+    }
+    Ok(Databases::clone(&comparer.get_database_handle().snapshot()))
+}
+
+// Scans `roots` every `interval` until an error stops it. A second thread
+// reloads the databases meanwhile; each pass scans every file against the
+// databases in place when the pass started.
+fn watch(cli: &Cli, roots: &[String], interval: Duration, verbosity: &Verbosity) -> io::Result<()> {
+    let (mut comparer, _) = load_database(cli)?;
+    let mut reloader =
+        Reloader::new(comparer.get_database_handle().clone(), database_paths(cli), || reload_databases(cli));
+    reloader.listen_for_hangup()?;
+    if verbosity.normal() {
+        eprintln!(
+            "{} watching with databases version {}",
+            format_time(SystemTime::now()),
+            version_name(comparer.get_signatures().version())
+        );
+    }
+    let stop = AtomicBool::new(false);
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            while !stop.load(Ordering::Acquire) {
+                thread::sleep(Duration::from_secs(1));
+                if let Some((trigger, reload)) = reloader.poll() {
+                    log_reload(trigger, &reload);
+                }
             }
         });
-        let elapsed = start_time.elapsed();
-        write_to_log(&self.directory, &format!("Total runtime: {:?}", elapsed))?;
-        Ok(elapsed)
+        let mut sink = WatchSink::default();
+        let result = loop {
+            // Each pass reports what is infected now, not what ever was.
+            comparer.clear_risk_files();
+            let mut search = RecFileSearch::with_roots(roots.to_vec(), comparer);
+            let scanned = search.start_with_sink(&mut sink);
+            if verbosity.verbose() {
+                println!(
+                    "Scanned {} files in {} directories, {} errors",
+                    search.get_files().len(),
+                    search.get_dirs().len(),
+                    search.get_errors().len()
+                );
+            }
+            comparer = search.into_tester();
+            if let Err(e) = scanned {
+                break Err(e);
+            }
+            thread::sleep(interval);
+        };
+        stop.store(true, Ordering::Release);
+        result
+    })
+}
+
+fn scan_exit_code(search: &RecFileSearch) -> u8 {
+    if !search.get_tester().get_risk_files().is_empty() {
+        EXIT_INFECTED
+    } else if !search.get_errors().is_empty() {
+        EXIT_ERROR
+    } else {
+        EXIT_CLEAN
     }
+}
 
-    pub fn get_files(&self) -> &Arc<Mutex<Vec<Arc<str>>>> {
-        &self.found_files
+// Prints what the response policy did, returning false if any action failed.
+fn print_responses(search: &RecFileSearch, verbosity: &Verbosity) -> bool {
+    let mut succeeded = true;
+    for response in search.get_tester().get_responses() {
+        if response.action == Action::Report {
+            continue;
+        }
+        let signatures = response.signatures.join(", ");
+        match &response.outcome {
+            Ok(_) if response.dry_run => {
+                if verbosity.normal() {
+                    println!("Would {} {} ({})", response.action, response.path, signatures);
+                }
+            }
+            Ok(detail) => {
+                if verbosity.normal() {
+                    let detail = if detail.is_empty() { String::new() } else { format!(" -> {}", detail) };
+                    println!("Applied {} to {}{} ({})", response.action, response.path, detail, signatures);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}: {} failed: {}", response.path, response.action, e);
+                succeeded = false;
+            }
+        }
     }
+    succeeded
+}
 
-    pub fn get_dirs(&self) -> &Arc<Mutex<Vec<Arc<str>>>> {
-        &self.found_dirs
+fn manage_quarantine(vault: &Path, action: &QuarantineCommand, verbosity: &Verbosity) -> io::Result<()> {
+    let mut vault = Vault::open(vault)?;
+    match action {
+        QuarantineCommand::List => {
+            for entry in vault.entries() {
+                println!(
+                    "{}  {}  {:o} {}:{}  {} bytes  {}  {}",
+                    entry.id,
+                    entry.quarantined_at,
+                    entry.mode,
+                    entry.uid,
+                    entry.gid,
+                    entry.size,
+                    entry.signatures.join(","),
+                    entry.original_path
+                );
+            }
+        }
+        QuarantineCommand::Restore { id, to, force } => {
+            let entry = vault.restore(id, to.as_deref(), *force)?;
+            if verbosity.normal() {
+                let target = to.as_ref().map(|p| p.display().to_string()).unwrap_or(entry.original_path);
+                println!("Restored {} to {}", id, target);
+            }
+        }
+        QuarantineCommand::Purge { ids, all: _ } => {
+            let purged = vault.purge(ids)?;
+            if verbosity.normal() {
+                println!("Purged {} quarantined files", purged.len());
+            }
+        }
     }
+    Ok(())
+}
 
-    pub fn set_dir(&mut self, path: Arc<str>) {
-        self.directory = path;
+// `None` or `-` means stdout.
+fn open_output(path: Option<&str>) -> io::Result<Box<dyn Write + Send>> {
+    match path {
+        None | Some("-") => Ok(Box::new(io::stdout())),
+        Some(path) => {
+            if let Some(parent) = Path::new(path).parent() {
+                fs::create_dir_all(parent)?;
+            }
+            Ok(Box::new(BufWriter::new(File::create(path)?)))
+        }
     }
 }
 
-static LOG_CHANNEL: Lazy<(channel::Sender<String>, channel::Receiver<String>)> = Lazy::new(|| {
-    let (sender, receiver) = channel::unbounded();
-    (sender, receiver)
-});
+fn is_structured_report(input: &str) -> bool {
+    Path::new(input)
+        .extension()
+        .is_some_and(|ext| ext == "json" || ext == "ndjson" || ext == "jsonl")
+}
 
-fn start_logging_thread(directory: Arc<str>) {
-    let (log_sender, log_receiver) = &*LOG_CHANNEL;
-    let log_file = format!("{}/logs/performance.log", &*directory);
-    let file = File::create(&log_file).unwrap();
-    let mut writer = BufWriter::new(file);
-    std::thread::spawn(move || {
-        while let Ok(message) = log_receiver.recv() {
-            writeln!(writer, "{}", message).unwrap();
+fn report(input: &str, format: Option<ReportFormat>, out: Option<&str>) -> io::Result<()> {
+    if let Some(format) = format {
+        if !is_structured_report(input) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: only JSON and NDJSON reports can be converted", input),
+            ));
         }
-    });
+        let mut writer = open_output(out)?;
+        ScanReport::read(input)?.write(format, &mut writer)?;
+        return writer.flush();
+    }
+    let counts = if is_structured_report(input) {
+        let report = ScanReport::read(input)?;
+        println!(
+            "Scan of {} with database version {}: {} files, {} infected, {} errors",
+            report.roots.join(", "),
+            report.db_version,
+            report.files_scanned,
+            report.infected_files,
+            report.errors.len()
+        );
+        report.signature_counts().into_iter().map(|(name, count)| (name.to_string(), count)).collect()
+    } else {
+        text_signature_counts(BufReader::new(File::open(input)?))?
+    };
+    let total: usize = counts.values().sum();
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    for (name, count) in counts {
+        println!("{:>6}  {}", count, name);
+    }
+    println!("{} detections in {}", total, input);
+    Ok(())
 }
 
-pub fn write_to_log(directory: &Arc<str>, message: &str) -> io::Result<()> {
-    let (log_sender, _) = &*LOG_CHANNEL;
-    log_sender.send(format!("{}: {}", directory, message)).unwrap();
-    Ok(())
+fn fuzzy_hash_file(path: &str) -> io::Result<FuzzyHash> {
+    let mut file = File::open(path)?;
+    let mut hasher = FuzzyHasher::new(file.metadata()?.len());
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&buffer[..read]);
+    }
 }
 
-fn main() -> io::Result<()> {
-    let search_path = String::from("/mnt/General_Data/Dev/Rust/AntiVirus/Test_env");
-    let db_path = String::from("/mnt/General_Data/Dev/Rust/AntiVirus/Test_env/signatures.db");
-    let comparer = FileCompare::new(&db_path)?;
-    let mut secure_dir = RecFileSearch::new(search_path, comparer);
-    start_logging_thread(Arc::clone(&secure_dir.directory));
-    let total_runtime = secure_dir.start()?;
-    println!("Total runtime: {:?}", total_runtime);
-    secure_dir.tester.lock().unwrap().log_risk_files(&secure_dir.directory)?;
-    Ok(())
+fn run(cli: &Cli) -> io::Result<u8> {
+    let verbosity = Verbosity(if cli.quiet { -1 } else { cli.verbose as i8 });
+    if let Some(threads) = cli.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .map_err(|e| io::Error::other(e.to_string()))?;
+    }
+    match &cli.command {
+        Command::Scan(args) => {
+            let search = run_scan(cli, args, &verbosity)?;
+            if !print_responses(&search, &verbosity) {
+                return Ok(EXIT_ERROR);
+            }
+            Ok(scan_exit_code(&search))
+        }
+        Command::Watch { roots, interval } => {
+            watch(cli, roots, Duration::from_secs(*interval), &verbosity)?;
+            Ok(EXIT_CLEAN)
+        }
+        Command::Quarantine { vault, action } => {
+            manage_quarantine(vault, action, &verbosity)?;
+            Ok(EXIT_CLEAN)
+        }
+        Command::Db(DbCommand::Check) => {
+            if is_compiled(&cli.db) {
+                verify_compiled(&cli.db).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", cli.db, e)))?;
+            }
+            let (comparer, invalid) = load_database(cli)?;
+            if verbosity.normal() {
+                println!(
+                    "{}: {} signatures, {} YARA rules, {} hash signatures, {} fuzzy hash signatures, {} invalid lines",
+                    cli.db,
+                    comparer.get_signatures().len() - yara_rule_count(&comparer),
+                    yara_rule_count(&comparer),
+                    comparer.get_hash_database().map_or(0, |hashes| hashes.len()),
+                    comparer.get_fuzzy_database().map_or(0, |fuzzy| fuzzy.len()),
+                    invalid
+                );
+            }
+            Ok(if invalid == 0 { EXIT_CLEAN } else { EXIT_ERROR })
+        }
+        Command::Db(DbCommand::List) => {
+            let (comparer, _) = load_database(cli)?;
+            for signature in comparer.get_signatures().signatures() {
+                let pattern = &signature.pattern;
+                let anchor = match signature.anchor {
+                    Anchor::Anywhere => String::new(),
+                    anchor => format!(", at {}", anchor),
+                };
+                if pattern.min_len() == pattern.max_len() {
+                    println!("{} ({} bytes{})", signature.name, pattern.max_len(), anchor);
+                } else {
+                    println!("{} ({}-{} bytes{}: {})", signature.name, pattern.min_len(), pattern.max_len(), anchor, pattern);
+                }
+            }
+            for signature in comparer.get_signatures().logical() {
+                let kind = match signature.condition {
+                    Condition::Expression(_) => "logical",
+                    Condition::Yara(_) => "yara",
+                };
+                println!("{} ({}, {} sub-signatures: {})", signature.name, kind, signature.subsignatures.len(), signature.condition);
+            }
+            let hashes = comparer.get_hash_database();
+            for signature in hashes.as_deref().map_or(&[][..], |hashes| hashes.signatures()) {
+                let size = signature.size.map_or("any size".to_string(), |size| format!("{} bytes", size));
+                println!("{} ({}, {})", signature.name, signature.algorithm, size);
+            }
+            let fuzzy = comparer.get_fuzzy_database();
+            for signature in fuzzy.as_deref().map_or(&[][..], |fuzzy| fuzzy.signatures()) {
+                println!("{} (ssdeep {}, {}% similar)", signature.name, signature.hash, signature.threshold);
+            }
+            Ok(EXIT_CLEAN)
+        }
+        Command::Db(DbCommand::Lint { min_len, min_entropy }) => {
+            let options = LintOptions { min_len: *min_len, min_entropy: *min_entropy };
+            let findings =
+                lint_file(&cli.db, &options).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", cli.db, e)))?;
+            if verbosity.normal() {
+                for finding in &findings {
+                    println!("{}: {}", cli.db, finding);
+                }
+                println!("{}: {} findings", cli.db, findings.len());
+            }
+            Ok(if findings.is_empty() { EXIT_CLEAN } else { EXIT_ERROR })
+        }
+        Command::Db(DbCommand::Compile { output }) => {
+            let output = output.clone().unwrap_or_else(|| compiled_path(&cli.db));
+            let (count, errors) = compile_database(&cli.db, &output)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", cli.db, e)))?;
+            for error in &errors {
+                eprintln!("{}: {}", cli.db, error);
+            }
+            if verbosity.normal() {
+                println!("{}: {} signatures compiled into {}", cli.db, count, output.display());
+            }
+            Ok(if errors.is_empty() { EXIT_CLEAN } else { EXIT_ERROR })
+        }
+        Command::Db(DbCommand::Sign { key, files }) => {
+            let files = if files.is_empty() { std::slice::from_ref(&cli.db) } else { &files[..] };
+            for file in files {
+                let signature = sign_database(file, key).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file, e)))?;
+                if verbosity.normal() {
+                    println!("{}: signed into {}", file, signature.display());
+                }
+            }
+            Ok(EXIT_CLEAN)
+        }
+        Command::Db(DbCommand::Keygen { key }) => {
+            let public = generate_key(key).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", key.display(), e)))?;
+            if verbosity.normal() {
+                println!("{}", public);
+                println!(
+                    "Private key written to {}, public key to {}; add the public key to {} to trust databases it signs",
+                    key.display(),
+                    public_key_path(key).display(),
+                    cli.trusted_keys
+                );
+            }
+            Ok(EXIT_CLEAN)
+        }
+        Command::Db(DbCommand::Update { mirror, keep }) => {
+            let outcome = update_database(&cli.db, mirror.as_deref(), *keep, &verification(cli)?)
+                .map_err(|e| database_error(&cli.db, e))?;
+            if verbosity.normal() {
+                match outcome {
+                    UpdateOutcome::UpToDate { version } => println!("{}: version {} is up to date", cli.db, version),
+                    UpdateOutcome::Installed { from, to, delta } => println!(
+                        "{}: updated from {} to version {}{}",
+                        cli.db,
+                        from.map_or("an unversioned database".to_string(), |from| format!("version {}", from)),
+                        to,
+                        if delta { " with a delta" } else { "" }
+                    ),
+                }
+            }
+            Ok(EXIT_CLEAN)
+        }
+        Command::Db(DbCommand::Rollback) => {
+            let version = rollback_database(&cli.db).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", cli.db, e)))?;
+            if verbosity.normal() {
+                match version {
+                    Some(version) => println!("{}: rolled back to version {}", cli.db, version),
+                    None => println!("{}: rolled back to the database in place before the first update", cli.db),
+                }
+            }
+            Ok(EXIT_CLEAN)
+        }
+        Command::Db(DbCommand::Import { files, out }) => {
+            let mut status = EXIT_CLEAN;
+            let mut writer = match out {
+                Some(out) => Some(BufWriter::new(
+                    File::create(out).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", out, e)))?,
+                )),
+                None => None,
+            };
+            for file in files {
+                match read_clamav_database(file) {
+                    Ok(database) => {
+                        if verbosity.normal() {
+                            let mut notes: Vec<_> = database
+                                .skipped
+                                .iter()
+                                .map(|error| (error, "skipped"))
+                                .chain(database.downgraded.iter().map(|error| (error, "downgraded")))
+                                .collect();
+                            notes.sort_by_key(|(error, _)| error.line);
+                            for (error, outcome) in notes {
+                                println!("{}: line {}: {}: {}", file, error.line, outcome, error.message);
+                            }
+                        }
+                        println!("{}: {}", file, import_summary(&database));
+                        if let (Some(writer), Some(out)) = (&mut writer, out) {
+                            if ClamavFormat::from_path(file) == Some(ClamavFormat::Hash) {
+                                eprintln!("{}: not written to {}, load hash databases with --hash-db as they are", file, out);
+                                continue;
+                            }
+                            writeln!(writer, "# {}", file)
+                                .and_then(|_| database.write_signatures(&mut *writer))
+                                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", out, e)))?;
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("{}: {}", file, e);
+                        status = EXIT_ERROR;
+                    }
+                }
+            }
+            if let (Some(mut writer), Some(out)) = (writer, out) {
+                writer.flush().map_err(|e| io::Error::new(e.kind(), format!("{}: {}", out, e)))?;
+            }
+            Ok(status)
+        }
+        Command::Db(DbCommand::FuzzyHash { files, name, threshold }) => {
+            let mut status = EXIT_CLEAN;
+            for file in files {
+                // A colon would end the name early when the line is read back.
+                let name = name
+                    .clone()
+                    .unwrap_or_else(|| {
+                        Path::new(file).file_name().map_or_else(|| file.clone(), |name| name.to_string_lossy().into_owned())
+                    })
+                    .replace([':', ' '], "_");
+                match fuzzy_hash_file(file) {
+                    Ok(hash) => println!("{}:{}:{}", name, threshold, hash),
+                    Err(e) => {
+                        eprintln!("{}: {}", file, e);
+                        status = EXIT_ERROR;
+                    }
+                }
+            }
+            Ok(status)
+        }
+        Command::Report { input, format, out } => {
+            report(input, *format, out.as_deref())?;
+            Ok(EXIT_CLEAN)
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(&cli) {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
 }
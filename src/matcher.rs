@@ -0,0 +1,262 @@
+use std::collections::VecDeque;
+use std::io;
+
+use crate::compiled::{SectionReader, SectionWriter, Table};
+
+const ROOT: u32 = 0;
+const NONE: u32 = u32::MAX;
+
+// Aho-Corasick automaton over byte patterns. The trie is kept in flat arrays with
+// sparse, byte-sorted transitions per state and a dense table for the root.
+// The arrays can also be mapped from a compiled database.
+pub struct Automaton {
+    root: Table<u32>,
+    trans_start: Table<u32>,
+    trans_bytes: Table<u8>,
+    trans_next: Table<u32>,
+    fail: Table<u32>,
+    dict: Table<u32>,
+    out_start: Table<u32>,
+    outputs: Table<u32>,
+    pattern_lens: Table<u32>,
+}
+
+struct BuildState {
+    next: Vec<(u8, u32)>,
+    fail: u32,
+    outputs: Vec<u32>,
+}
+
+impl BuildState {
+    fn new() -> BuildState {
+        BuildState { next: Vec::new(), fail: ROOT, outputs: Vec::new() }
+    }
+
+    fn goto(&self, byte: u8) -> Option<u32> {
+        self.next.iter().find(|(b, _)| *b == byte).map(|(_, s)| *s)
+    }
+}
+
+impl Automaton {
+    pub fn new<P: AsRef<[u8]>>(patterns: &[P]) -> Automaton {
+        let mut states = vec![BuildState::new()];
+        let mut pattern_lens = Vec::with_capacity(patterns.len());
+        for (id, pattern) in patterns.iter().enumerate() {
+            let pattern = pattern.as_ref();
+            let mut state = ROOT;
+            for &byte in pattern {
+                state = match states[state as usize].goto(byte) {
+                    Some(next) => next,
+                    None => {
+                        let next = states.len() as u32;
+                        states.push(BuildState::new());
+                        states[state as usize].next.push((byte, next));
+                        next
+                    }
+                };
+            }
+            if !pattern.is_empty() {
+                states[state as usize].outputs.push(id as u32);
+            }
+            pattern_lens.push(pattern.len() as u32);
+        }
+
+        let mut dict = vec![NONE; states.len()];
+        let mut queue = VecDeque::new();
+        for i in 0..states[0].next.len() {
+            let (_, child) = states[0].next[i];
+            states[child as usize].fail = ROOT;
+            queue.push_back(child);
+        }
+        while let Some(state) = queue.pop_front() {
+            for i in 0..states[state as usize].next.len() {
+                let (byte, child) = states[state as usize].next[i];
+                let mut fail = states[state as usize].fail;
+                let target = loop {
+                    if let Some(next) = states[fail as usize].goto(byte) {
+                        break next;
+                    }
+                    if fail == ROOT {
+                        break ROOT;
+                    }
+                    fail = states[fail as usize].fail;
+                };
+                states[child as usize].fail = target;
+                dict[child as usize] = if !states[target as usize].outputs.is_empty() {
+                    target
+                } else {
+                    dict[target as usize]
+                };
+                queue.push_back(child);
+            }
+        }
+
+        let mut root = vec![ROOT; 256];
+        for &(byte, next) in &states[0].next {
+            root[byte as usize] = next;
+        }
+        let mut trans_start = Vec::with_capacity(states.len() + 1);
+        let mut trans_bytes = Vec::new();
+        let mut trans_next = Vec::new();
+        let mut fail = Vec::with_capacity(states.len());
+        let mut out_start = Vec::with_capacity(states.len() + 1);
+        let mut outputs = Vec::new();
+        for mut state in states {
+            state.next.sort_unstable_by_key(|(byte, _)| *byte);
+            trans_start.push(trans_bytes.len() as u32);
+            for (byte, next) in state.next {
+                trans_bytes.push(byte);
+                trans_next.push(next);
+            }
+            fail.push(state.fail);
+            out_start.push(outputs.len() as u32);
+            outputs.extend(state.outputs);
+        }
+        trans_start.push(trans_bytes.len() as u32);
+        out_start.push(outputs.len() as u32);
+        Automaton {
+            root: root.into(),
+            trans_start: trans_start.into(),
+            trans_bytes: trans_bytes.into(),
+            trans_next: trans_next.into(),
+            fail: fail.into(),
+            dict: dict.into(),
+            out_start: out_start.into(),
+            outputs: outputs.into(),
+            pattern_lens: pattern_lens.into(),
+        }
+    }
+
+    pub fn pattern_count(&self) -> usize {
+        self.pattern_lens.len()
+    }
+
+    pub(crate) fn write_tables(&self, writer: &mut SectionWriter) {
+        writer.table(&self.root);
+        writer.table(&self.trans_start);
+        writer.table(&self.trans_bytes);
+        writer.table(&self.trans_next);
+        writer.table(&self.fail);
+        writer.table(&self.dict);
+        writer.table(&self.out_start);
+        writer.table(&self.outputs);
+        writer.table(&self.pattern_lens);
+    }
+
+    // Tables that don't describe a trie with failure links to shallower states
+    // are refused, so a crafted database can't index out of bounds or loop.
+    pub(crate) fn read_tables(reader: &mut SectionReader) -> io::Result<Automaton> {
+        let automaton = Automaton {
+            root: reader.table()?,
+            trans_start: reader.table()?,
+            trans_bytes: reader.table()?,
+            trans_next: reader.table()?,
+            fail: reader.table()?,
+            dict: reader.table()?,
+            out_start: reader.table()?,
+            outputs: reader.table()?,
+            pattern_lens: reader.table()?,
+        };
+        let states = automaton.fail.len();
+        let consistent = automaton.root.len() == 256
+            && automaton.trans_start.len() == states + 1
+            && automaton.trans_next.len() == automaton.trans_bytes.len()
+            && automaton.dict.len() == states
+            && automaton.out_start.len() == states + 1;
+        if !consistent || !automaton.is_valid() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "inconsistent matcher tables"));
+        }
+        Ok(automaton)
+    }
+
+    // Checks what scans rely on: every state number is in range, the
+    // transitions form a tree below the root, failure and dictionary links lead
+    // to shallower states, and no pattern is longer than the depth it ends at.
+    fn is_valid(&self) -> bool {
+        let states = self.fail.len();
+        let bounded = |starts: &[u32], len: usize| {
+            starts.first() == Some(&0)
+                && starts.windows(2).all(|pair| pair[0] <= pair[1])
+                && starts.last().is_some_and(|&end| end as usize == len)
+        };
+        if states == 0
+            || !bounded(&self.trans_start, self.trans_bytes.len())
+            || !bounded(&self.out_start, self.outputs.len())
+            || self.trans_next.iter().any(|&next| next == ROOT || next as usize >= states)
+            || self.outputs.iter().any(|&pattern| pattern as usize >= self.pattern_lens.len())
+        {
+            return false;
+        }
+        let mut depth = vec![u32::MAX; states];
+        depth[ROOT as usize] = 0;
+        let mut queue = VecDeque::from([ROOT]);
+        while let Some(state) = queue.pop_front() {
+            let (start, end) = (self.trans_start[state as usize], self.trans_start[state as usize + 1]);
+            for &next in &self.trans_next[start as usize..end as usize] {
+                if depth[next as usize] != u32::MAX {
+                    return false;
+                }
+                depth[next as usize] = depth[state as usize] + 1;
+                queue.push_back(next);
+            }
+        }
+        let shallower = |link: u32, state: usize| (link as usize) < states && depth[link as usize] < depth[state];
+        let root_ok = self.root.iter().all(|&next| next == ROOT || depth.get(next as usize) == Some(&1));
+        root_ok
+            && (1..states).all(|state| {
+                let (from, to) = (self.out_start[state] as usize, self.out_start[state + 1] as usize);
+                depth[state] != u32::MAX
+                    && shallower(self.fail[state], state)
+                    && (self.dict[state] == NONE || shallower(self.dict[state], state))
+                    && self.outputs[from..to].iter().all(|&pattern| self.pattern_lens[pattern as usize] <= depth[state])
+            })
+            && self.out_start[0] == self.out_start[1]
+    }
+
+    fn goto(&self, state: u32, byte: u8) -> Option<u32> {
+        if state == ROOT {
+            let next = self.root[byte as usize];
+            return if next == ROOT { None } else { Some(next) };
+        }
+        let start = self.trans_start[state as usize] as usize;
+        let end = self.trans_start[state as usize + 1] as usize;
+        self.trans_bytes[start..end]
+            .binary_search(&byte)
+            .ok()
+            .map(|i| self.trans_next[start + i])
+    }
+
+    fn next_state(&self, mut state: u32, byte: u8) -> u32 {
+        loop {
+            if let Some(next) = self.goto(state, byte) {
+                return next;
+            }
+            if state == ROOT {
+                return ROOT;
+            }
+            state = self.fail[state as usize];
+        }
+    }
+
+    fn emit<F: FnMut(usize, usize)>(&self, state: u32, end: usize, on_match: &mut F) {
+        let from = self.out_start[state as usize] as usize;
+        let to = self.out_start[state as usize + 1] as usize;
+        for &pattern in &self.outputs[from..to] {
+            on_match(pattern as usize, end + 1 - self.pattern_lens[pattern as usize] as usize);
+        }
+    }
+
+    // Calls `on_match(pattern, start)` for every occurrence of every pattern in
+    // `haystack`, including overlapping ones, in order of their end position.
+    pub fn find_overlapping<F: FnMut(usize, usize)>(&self, haystack: &[u8], mut on_match: F) {
+        let mut state = ROOT;
+        for (i, &byte) in haystack.iter().enumerate() {
+            state = self.next_state(state, byte);
+            let mut out = state;
+            while out != NONE && out != ROOT {
+                self.emit(out, i, &mut on_match);
+                out = self.dict[out as usize];
+            }
+        }
+    }
+}
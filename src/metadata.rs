@@ -0,0 +1,337 @@
+use std::hash::Hasher;
+use std::io;
+use std::path::Path;
+
+use chrono::NaiveDate;
+use fnv::{FnvHashMap, FnvHasher};
+use serde::{Deserialize, Serialize};
+
+use crate::policy::Severity;
+use crate::signatures::{parse_definition, redefinition, DbError, Entries, SignatureSet};
+
+// Newest structured database format this scanner understands.
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DbFormat {
+    // `name=hex` lines.
+    Legacy,
+    Toml,
+    Json,
+}
+
+impl DbFormat {
+    pub fn from_path(path: &str) -> DbFormat {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => DbFormat::Toml,
+            Some("json") => DbFormat::Json,
+            _ => DbFormat::Legacy,
+        }
+    }
+}
+
+// Optional details about a signature. They travel with its detections into
+// reports and response policies.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SignatureMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub severity: Option<Severity>,
+    // E.g. `virus`, `trojan` or `pua`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    // Platform or file type the signature targets, e.g. `macho` or `pdf`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    // URL with more about the threat.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    // `YYYY-MM-DD`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub added: Option<String>,
+}
+
+impl SignatureMeta {
+    pub fn is_empty(&self) -> bool {
+        *self == SignatureMeta::default()
+    }
+
+    // `(field, value)` for each field that is set, in declaration order.
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        [
+            ("severity", self.severity.map(|severity| severity.to_string())),
+            ("category", self.category.clone()),
+            ("platform", self.platform.clone()),
+            ("description", self.description.clone()),
+            ("reference", self.reference.clone()),
+            ("added", self.added.clone()),
+        ]
+        .into_iter()
+        .filter_map(|(field, value)| Some((field, value?)))
+        .collect()
+    }
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct EntrySpec {
+    name: String,
+    // Everything after `name=` in the legacy format.
+    pattern: String,
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+    #[serde(flatten)]
+    meta: SignatureMeta,
+}
+
+// A structured database, e.g. in TOML:
+//
+//     format = 1
+//     version = "2024.06.01"
+//
+//     [[signature]]
+//     name = "TestTrojan.1"
+//     pattern = "9421ffa0429f0005"
+//     severity = "high"
+//     category = "trojan"
+//     platform = "macho"
+//     description = "Dropper stub"
+//     reference = "https://example.com/testtrojan1"
+//     added = "2024-05-30"
+//     enabled = true
+//
+// JSON takes the same keys, with `signature` (or `signatures`) as an array.
+#[derive(Deserialize)]
+struct DatabaseSpec {
+    format: u32,
+    version: Option<String>,
+    #[serde(default, alias = "signatures")]
+    signature: Vec<EntrySpec>,
+}
+
+// A signature definition read from a structured database.
+pub(crate) struct Definition {
+    // Line of its `name`, for error messages.
+    pub line: usize,
+    pub name: String,
+    pub pattern: String,
+    pub enabled: bool,
+    pub meta: SignatureMeta,
+}
+
+// The line each name is defined on, found by looking for the quoted names in
+// order, since neither parser reports positions.
+fn definition_lines<'a>(text: &str, names: impl Iterator<Item = &'a str>) -> Vec<usize> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut from = 0;
+    names
+        .map(|name| {
+            let quoted = format!("\"{}\"", name);
+            match (from..lines.len()).find(|&index| lines[index].contains(&quoted)) {
+                Some(index) => {
+                    from = index + 1;
+                    index + 1
+                }
+                None => from,
+            }
+        })
+        .collect()
+}
+
+// The version, if the database gives one, and its definitions in order.
+pub(crate) fn read_definitions(text: &str, format: DbFormat) -> io::Result<(Option<String>, Vec<Definition>)> {
+    let spec: DatabaseSpec = match format {
+        DbFormat::Toml => toml::from_str(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?,
+        DbFormat::Json => serde_json::from_str(text).map_err(io::Error::from)?,
+        DbFormat::Legacy => return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a structured database")),
+    };
+    if spec.format == 0 || spec.format > FORMAT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported database format {}, expected at most {}", spec.format, FORMAT_VERSION),
+        ));
+    }
+    let lines = definition_lines(text, spec.signature.iter().map(|entry| entry.name.as_str()));
+    let definitions = spec
+        .signature
+        .into_iter()
+        .zip(lines)
+        .map(|(entry, line)| Definition {
+            line,
+            name: entry.name,
+            pattern: entry.pattern,
+            enabled: entry.enabled,
+            meta: entry.meta,
+        })
+        .collect();
+    Ok((spec.version, definitions))
+}
+
+fn check_meta(meta: &SignatureMeta) -> Result<(), String> {
+    if let Some(added) = &meta.added {
+        NaiveDate::parse_from_str(added, "%Y-%m-%d").map_err(|_| format!("invalid date {}, expected YYYY-MM-DD", added))?;
+    }
+    Ok(())
+}
+
+// Builds the signature set of a structured database. Disabled signatures are
+// left out, invalid ones are skipped and returned like invalid lines of the
+// legacy format, and a repeated name replaces the earlier definition and is
+// noted in `redefined`. Without a
+// `version` key the version is a fingerprint of the text.
+pub fn parse_database(text: &str, format: DbFormat) -> io::Result<(SignatureSet, Vec<DbError>)> {
+    let (version, definitions) = read_definitions(text, format)?;
+    let mut entries = Entries::default();
+    let mut metadata = FnvHashMap::default();
+    let mut errors = Vec::new();
+    let mut redefined = Vec::new();
+    for definition in definitions.into_iter().filter(|definition| definition.enabled) {
+        let entry = check_meta(&definition.meta)
+            .map_err(|e| format!("{}: {}", definition.name.trim(), e))
+            .and_then(|_| parse_definition(&definition.name, &definition.pattern));
+        match entry {
+            Ok(entry) => {
+                let name = entry.name().to_string();
+                if entries.insert(entry) {
+                    redefined.push(DbError { line: definition.line, message: redefinition(&name) });
+                }
+                if definition.meta.is_empty() {
+                    metadata.remove(&name);
+                } else {
+                    metadata.insert(name, definition.meta);
+                }
+            }
+            Err(message) => errors.push(DbError { line: definition.line, message }),
+        }
+    }
+    let version = version.unwrap_or_else(|| {
+        let mut fingerprint = FnvHasher::default();
+        fingerprint.write(text.as_bytes());
+        format!("fnv-{:016x}", fingerprint.finish())
+    });
+    let (signatures, logical) = entries.into_parts();
+    let set = SignatureSet::new(signatures, logical).with_version(version).with_metadata(metadata);
+    Ok((set.with_redefined(redefined), errors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{definition_lines, parse_database, DbFormat};
+    use crate::policy::Severity;
+    use crate::signatures::DbError;
+
+    const TOML: &str = r#"format = 1
+version = "2024.06.01"
+
+[[signature]]
+name = "Test.Full"
+pattern = "9421ffa0429f0005"
+severity = "high"
+category = "trojan"
+platform = "macho"
+description = "Dropper stub"
+reference = "https://example.com/full"
+added = "2024-05-30"
+
+[[signature]]
+name = "Test.Off"
+pattern = "deadbeef"
+enabled = false
+
+[[signature]]
+name = "Test.BadPattern"
+pattern = "zz"
+
+[[signature]]
+name = "Test.BadDate"
+pattern = "cafebabe"
+added = "30/05/2024"
+
+[[signature]]
+name = "Test.Logic"
+pattern = "0&1;aabb;ccdd"
+
+[[signature]]
+name = "Test.Full"
+pattern = "9421ffa0"
+"#;
+
+    fn lines(errors: &[DbError]) -> Vec<usize> {
+        errors.iter().map(|error| error.line).collect()
+    }
+
+    #[test]
+    fn formats_by_extension() {
+        assert_eq!(DbFormat::from_path("signatures.toml"), DbFormat::Toml);
+        assert_eq!(DbFormat::from_path("dir/signatures.json"), DbFormat::Json);
+        assert_eq!(DbFormat::from_path("signatures.db"), DbFormat::Legacy);
+        assert_eq!(DbFormat::from_path("toml"), DbFormat::Legacy);
+    }
+
+    #[test]
+    fn toml_databases() {
+        let (set, errors) = parse_database(TOML, DbFormat::Toml).unwrap();
+        assert_eq!(set.version(), "2024.06.01");
+        let plain: Vec<String> = set.signatures().map(ToString::to_string).collect();
+        assert_eq!(plain, ["Test.Full=9421ffa0"]);
+        assert_eq!(set.logical().len(), 1);
+        assert_eq!(lines(&errors), [20, 24]);
+        assert!(errors[1].message.contains("invalid date 30/05/2024"));
+        assert_eq!(lines(set.redefined()), [33]);
+        // Redefining a signature without metadata drops the earlier metadata.
+        assert!(set.metadata("Test.Full").is_none());
+        assert!(set.metadata("Test.Off").is_none());
+    }
+
+    #[test]
+    fn metadata_travels_with_signatures() {
+        let text = TOML.rsplit_once("\n[[signature]]").unwrap().0;
+        let (set, _) = parse_database(text, DbFormat::Toml).unwrap();
+        let meta = set.metadata("Test.Full").unwrap();
+        assert_eq!(meta.severity, Some(Severity::High));
+        let fields: Vec<&str> = meta.fields().into_iter().map(|(field, _)| field).collect();
+        assert_eq!(fields, ["severity", "category", "platform", "description", "reference", "added"]);
+        assert_eq!(meta.added.as_deref(), Some("2024-05-30"));
+    }
+
+    #[test]
+    fn json_databases() {
+        let text = r#"{
+  "format": 1,
+  "signatures": [
+    { "name": "Test.A", "pattern": "dead??ef", "category": "virus" },
+    { "name": "Test.B", "pattern": "beef", "enabled": false },
+    { "name": "Test.C", "pattern": "10:cafe", "severity": "critical" }
+  ]
+}"#;
+        let (set, errors) = parse_database(text, DbFormat::Json).unwrap();
+        assert!(errors.is_empty());
+        let plain: Vec<String> = set.signatures().map(ToString::to_string).collect();
+        assert_eq!(plain, ["Test.A=dead??ef", "Test.C=10:cafe"]);
+        assert_eq!(set.metadata("Test.C").unwrap().severity, Some(Severity::Critical));
+        // Without a version the text is fingerprinted.
+        assert!(set.version().starts_with("fnv-"));
+        let (again, _) = parse_database(text, DbFormat::Json).unwrap();
+        assert_eq!(again.version(), set.version());
+    }
+
+    #[test]
+    fn unsupported_databases_are_refused() {
+        for text in ["format = 0\n", "format = 2\n", "version = \"1\"\n", "format = \"one\"\n", "[[signature]\n"] {
+            assert!(parse_database(text, DbFormat::Toml).is_err(), "{:?} parsed", text);
+        }
+        assert!(parse_database("{\"format\": 1, \"signature\": [{\"name\": \"A\"}]}", DbFormat::Json).is_err());
+        assert!(parse_database("A=dead\n", DbFormat::Legacy).is_err());
+    }
+
+    #[test]
+    fn definitions_are_found_in_order() {
+        let text = "name = \"B\"\ndescription = \"not \\\"A\\\"\"\nname = \"A\"\nname = \"A\"\nname = \"\\u0043\"\nname = \"D\"\n";
+        // A name written with escapes can't be found and takes the line after the last one found.
+        assert_eq!(definition_lines(text, ["B", "A", "A", "C", "D"].into_iter()), [1, 3, 4, 4, 6]);
+    }
+}
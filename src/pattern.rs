@@ -0,0 +1,428 @@
+use std::fmt;
+
+// Upper bound on the literal strings one atom may expand to. Nibble masks and
+// alternatives multiply, so atoms stop growing once they would exceed this.
+const MAX_ATOM_VARIANTS: usize = 64;
+// Longest jump a pattern may contain, and the most bytes a whole pattern may
+// span. Scan windows overlap by the longest pattern, so these bound how much
+// of a file is held at once.
+const MAX_JUMP: usize = 32 * 1024;
+const MAX_PATTERN_LEN: usize = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Element {
+    Byte(u8),
+    // Matches bytes `b` with `b & mask == value`. `??` is a mask of 0, `a?` and
+    // `?a` mask one nibble.
+    Masked { value: u8, mask: u8 },
+    // `{n}`, `{n-m}` or `{-m}`: skip between `min` and `max` bytes.
+    Jump { min: usize, max: usize },
+    // `(aa|bbcc)`: any one of the literal alternatives.
+    Alt(Vec<Vec<u8>>),
+}
+
+impl Element {
+    fn len_range(&self) -> (usize, usize) {
+        match self {
+            Element::Byte(_) | Element::Masked { .. } => (1, 1),
+            Element::Jump { min, max } => (*min, *max),
+            Element::Alt(alternatives) => (
+                alternatives.iter().map(Vec::len).min().unwrap_or(0),
+                alternatives.iter().map(Vec::len).max().unwrap_or(0),
+            ),
+        }
+    }
+
+    // The literal strings this element can match, if there are few enough to
+    // put into the automaton.
+    fn variants(&self) -> Option<Vec<Vec<u8>>> {
+        match self {
+            Element::Byte(byte) => Some(vec![vec![*byte]]),
+            // Up to four free bits, which covers nibble masks.
+            Element::Masked { value, mask } if mask.count_zeros() <= 4 => {
+                Some((0..=255u8).filter(|byte| byte & mask == *value).map(|byte| vec![byte]).collect())
+            }
+            Element::Masked { .. } | Element::Jump { .. } => None,
+            Element::Alt(alternatives) => Some(alternatives.clone()),
+        }
+    }
+
+    fn searchable(&self) -> bool {
+        self.variants().is_some_and(|variants| variants.len() <= MAX_ATOM_VARIANTS)
+    }
+}
+
+// The part of a pattern the automaton searches for. A hit of one of the
+// `variants` means the whole pattern may start `left_min..=left_max` bytes earlier.
+pub struct Atom {
+    pub variants: Vec<Vec<u8>>,
+    pub left_min: usize,
+    pub left_max: usize,
+}
+
+// A byte pattern in the hex syntax of ClamAV `.ndb` bodies: plain hex bytes,
+// `??` wildcards, nibble masks `a?`/`?a`, bounded jumps `{n-m}` and
+// alternatives `(aa|bb)`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern {
+    elements: Vec<Element>,
+    min_len: usize,
+    max_len: usize,
+    literal: bool,
+}
+
+fn nibble(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+fn parse_jump(body: &str) -> Result<Element, String> {
+    let number = |s: &str| s.trim().parse::<usize>().map_err(|_| format!("invalid jump {{{}}}", body));
+    let (min, max) = match body.split_once('-') {
+        None => {
+            let n = number(body)?;
+            (n, n)
+        }
+        Some((_, "")) => return Err(format!("unbounded jump {{{}}} is not supported", body)),
+        Some(("", max)) => (0, number(max)?),
+        Some((min, max)) => (number(min)?, number(max)?),
+    };
+    if min > max {
+        return Err(format!("invalid jump {{{}}}", body));
+    }
+    if max > MAX_JUMP {
+        return Err(format!("jump {{{}}} is longer than {} bytes", body, MAX_JUMP));
+    }
+    Ok(Element::Jump { min, max })
+}
+
+fn parse_alternatives(body: &str) -> Result<Element, String> {
+    let mut alternatives = Vec::new();
+    for alternative in body.split('|') {
+        let bytes = hex::decode(alternative.trim()).map_err(|e| format!("invalid alternative ({}): {}", body, e))?;
+        if bytes.is_empty() {
+            return Err(format!("empty alternative in ({})", body));
+        }
+        alternatives.push(bytes);
+    }
+    Ok(Element::Alt(alternatives))
+}
+
+impl Pattern {
+    pub fn parse(text: &str) -> Result<Pattern, String> {
+        let text = text.trim();
+        let bytes = text.as_bytes();
+        let mut elements = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                open @ (b'{' | b'(') => {
+                    let close = if open == b'{' { '}' } else { ')' };
+                    let end = text[i..].find(close).ok_or_else(|| format!("missing '{}'", close))? + i;
+                    let body = &text[i + 1..end];
+                    elements.push(if open == b'{' { parse_jump(body)? } else { parse_alternatives(body)? });
+                    i = end + 1;
+                }
+                b'*' => return Err("unbounded `*` jumps are not supported, use {n-m}".to_string()),
+                _ => {
+                    let (high, low) = match bytes.get(i..i + 2) {
+                        Some(&[high, low]) => (high, low),
+                        _ => return Err("odd number of hex digits".to_string()),
+                    };
+                    let element = match (high, low) {
+                        (b'?', b'?') => Element::Masked { value: 0, mask: 0 },
+                        (b'?', low) => Element::Masked { value: nibble(low).ok_or("invalid hex digit")?, mask: 0x0f },
+                        (high, b'?') => Element::Masked { value: nibble(high).ok_or("invalid hex digit")? << 4, mask: 0xf0 },
+                        (high, low) => Element::Byte(
+                            nibble(high).ok_or("invalid hex digit")? << 4 | nibble(low).ok_or("invalid hex digit")?,
+                        ),
+                    };
+                    elements.push(element);
+                    i += 2;
+                }
+            }
+        }
+        Pattern::new(elements)
+    }
+
+    pub fn literal(bytes: &[u8]) -> Pattern {
+        Pattern {
+            elements: bytes.iter().map(|&byte| Element::Byte(byte)).collect(),
+            min_len: bytes.len(),
+            max_len: bytes.len(),
+            literal: true,
+        }
+    }
+
+    pub fn new(elements: Vec<Element>) -> Result<Pattern, String> {
+        if elements.is_empty() {
+            return Err("empty pattern".to_string());
+        }
+        if matches!(elements.first(), Some(Element::Jump { .. })) || matches!(elements.last(), Some(Element::Jump { .. })) {
+            return Err("a pattern can't start or end with a jump".to_string());
+        }
+        for element in &elements {
+            if let Element::Alt(alternatives) = element {
+                if alternatives.is_empty() || alternatives.iter().any(Vec::is_empty) {
+                    return Err("empty alternative".to_string());
+                }
+            }
+        }
+        if !elements.iter().any(Element::searchable) {
+            return Err("a pattern needs at least one fixed byte".to_string());
+        }
+        let (min_len, max_len) = elements
+            .iter()
+            .try_fold((0usize, 0usize), |(min, max), element| {
+                let (element_min, element_max) = element.len_range();
+                Some((min.checked_add(element_min)?, max.checked_add(element_max)?))
+            })
+            .filter(|&(_, max_len)| max_len <= MAX_PATTERN_LEN)
+            .ok_or_else(|| format!("a pattern can't span more than {} bytes", MAX_PATTERN_LEN))?;
+        let literal = elements.iter().all(|element| matches!(element, Element::Byte(_)));
+        Ok(Pattern { elements, min_len, max_len, literal })
+    }
+
+    pub fn elements(&self) -> &[Element] {
+        &self.elements
+    }
+
+    pub fn min_len(&self) -> usize {
+        self.min_len
+    }
+
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    // True for plain hex patterns, which need no verification after the
+    // automaton finds them.
+    pub fn is_literal(&self) -> bool {
+        self.literal
+    }
+
+    pub fn literal_bytes(&self) -> Option<Vec<u8>> {
+        self.literal.then(|| self.leading_literal())
+    }
+
+    // The fixed bytes every match starts with.
+    pub fn leading_literal(&self) -> Vec<u8> {
+        self.elements
+            .iter()
+            .map_while(|element| match element {
+                Element::Byte(byte) => Some(*byte),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Picks the run of elements with the longest guaranteed literal length that
+    // expands to at most `MAX_ATOM_VARIANTS` strings.
+    pub fn atom(&self) -> Atom {
+        let mut best: Option<(usize, usize, usize, usize)> = None;
+        for start in 0..self.elements.len() {
+            let mut count = 1;
+            let mut min_len = 0;
+            for end in start..self.elements.len() {
+                let Some(variants) = self.elements[end].variants() else { break };
+                count *= variants.len();
+                if count > MAX_ATOM_VARIANTS {
+                    break;
+                }
+                min_len += variants.iter().map(Vec::len).min().unwrap_or(0);
+                let better = best.is_none_or(|(_, _, best_len, best_count)| {
+                    min_len > best_len || (min_len == best_len && count < best_count)
+                });
+                if better {
+                    best = Some((start, end + 1, min_len, count));
+                }
+            }
+        }
+        // `new` guarantees at least one searchable element.
+        let (start, end, _, _) = best.expect("pattern without fixed bytes");
+        let mut variants = vec![Vec::new()];
+        for element in &self.elements[start..end] {
+            let element_variants = element.variants().unwrap_or_default();
+            variants = variants
+                .iter()
+                .flat_map(|prefix| {
+                    element_variants.iter().map(move |suffix| {
+                        let mut variant = prefix.clone();
+                        variant.extend_from_slice(suffix);
+                        variant
+                    })
+                })
+                .collect();
+        }
+        let (left_min, left_max) = self.elements[..start].iter().fold((0, 0), |(min, max), element| {
+            let (element_min, element_max) = element.len_range();
+            (min + element_min, max + element_max)
+        });
+        Atom { variants, left_min, left_max }
+    }
+
+    // The same pattern with ASCII letters matching in either case. Alternatives
+    // keep their case.
+    pub fn ignore_case(&self) -> Pattern {
+        let elements = self
+            .elements
+            .iter()
+            .map(|element| match element {
+                // Upper and lower case letters differ only in bit 0x20.
+                Element::Byte(byte) if byte.is_ascii_alphabetic() => Element::Masked { value: byte & 0xdf, mask: 0xdf },
+                element => element.clone(),
+            })
+            .collect();
+        Pattern::new(elements).expect("same shape as a valid pattern")
+    }
+
+    // The pattern as UTF-16LE text: every byte followed by a zero byte. Fails
+    // if that makes it too long.
+    pub fn wide(&self) -> Result<Pattern, String> {
+        let widen = |bytes: &Vec<u8>| bytes.iter().flat_map(|&byte| [byte, 0]).collect();
+        let elements = self
+            .elements
+            .iter()
+            .flat_map(|element| match element {
+                Element::Byte(_) | Element::Masked { .. } => vec![element.clone(), Element::Byte(0)],
+                Element::Jump { min, max } => vec![Element::Jump { min: min * 2, max: max * 2 }],
+                Element::Alt(alternatives) => vec![Element::Alt(alternatives.iter().map(widen).collect())],
+            })
+            .collect();
+        Pattern::new(elements)
+    }
+
+    // Length of the match starting at `data[start]`, if there is one. Jumps try
+    // their shortest distance first, alternatives their listed order.
+    pub fn match_at(&self, data: &[u8], start: usize) -> Option<usize> {
+        match_elements(&self.elements, data, start).map(|end| end - start)
+    }
+}
+
+fn match_elements(elements: &[Element], data: &[u8], mut pos: usize) -> Option<usize> {
+    for (i, element) in elements.iter().enumerate() {
+        match element {
+            Element::Byte(byte) => {
+                if data.get(pos) != Some(byte) {
+                    return None;
+                }
+                pos += 1;
+            }
+            Element::Masked { value, mask } => {
+                if data.get(pos)? & mask != *value {
+                    return None;
+                }
+                pos += 1;
+            }
+            Element::Jump { min, max } => {
+                let last = (*max).min(data.len().saturating_sub(pos));
+                return (*min..=last).find_map(|skip| match_elements(&elements[i + 1..], data, pos + skip));
+            }
+            Element::Alt(alternatives) => {
+                return alternatives.iter().find_map(|alternative| {
+                    if data.get(pos..)?.starts_with(alternative) {
+                        match_elements(&elements[i + 1..], data, pos + alternative.len())
+                    } else {
+                        None
+                    }
+                });
+            }
+        }
+    }
+    Some(pos)
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for element in &self.elements {
+            match element {
+                Element::Byte(byte) => write!(f, "{:02x}", byte)?,
+                Element::Masked { mask: 0, .. } => write!(f, "??")?,
+                Element::Masked { value, mask: 0xf0 } => write!(f, "{:x}?", value >> 4)?,
+                Element::Masked { value, mask: 0x0f } => write!(f, "?{:x}", value)?,
+                // Other masks have no hex syntax of their own.
+                Element::Masked { value, mask } => {
+                    let bytes: Vec<String> = (0..=255u8).filter(|b| b & mask == *value).map(|b| format!("{:02x}", b)).collect();
+                    write!(f, "({})", bytes.join("|"))?;
+                }
+                Element::Jump { min, max } if min == max => write!(f, "{{{}}}", min)?,
+                Element::Jump { min, max } => write!(f, "{{{}-{}}}", min, max)?,
+                Element::Alt(alternatives) => {
+                    let alternatives: Vec<String> = alternatives.iter().map(hex::encode).collect();
+                    write!(f, "({})", alternatives.join("|"))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Element, Pattern};
+
+    #[test]
+    fn parses_every_element() {
+        let pattern = Pattern::parse("dead??4?{2-4}(beef|00)?f").unwrap();
+        assert_eq!(
+            pattern.elements(),
+            [
+                Element::Byte(0xde),
+                Element::Byte(0xad),
+                Element::Masked { value: 0, mask: 0 },
+                Element::Masked { value: 0x40, mask: 0xf0 },
+                Element::Jump { min: 2, max: 4 },
+                Element::Alt(vec![vec![0xbe, 0xef], vec![0x00]]),
+                Element::Masked { value: 0x0f, mask: 0x0f },
+            ]
+        );
+        assert_eq!((pattern.min_len(), pattern.max_len()), (8, 11));
+        assert!(!pattern.is_literal());
+        assert_eq!(pattern.to_string(), "dead??4?{2-4}(beef|00)?f");
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        for text in ["", "abc", "zz", "{2}aa", "aa{2}", "aa{3-}bb", "aa{4-2}bb", "aa(bb|)", "aa(bb", "aa*bb", "????"] {
+            assert!(Pattern::parse(text).is_err(), "{:?} parsed", text);
+        }
+    }
+
+    #[test]
+    fn oversized_jumps_are_rejected() {
+        assert!(Pattern::parse("aa{0-18446744073709551615}bb").is_err());
+        assert!(Pattern::parse("aa{0-4000000000}bb").is_err());
+        assert!(Pattern::parse("aa{32768}bb{32768}cc").is_err());
+        assert_eq!(Pattern::parse("aa{32768}bb").unwrap().max_len(), 32770);
+        // Wide forms double their jumps.
+        assert!(Pattern::parse("aa{32000}bb{1000}cc").unwrap().wide().is_err());
+    }
+
+    #[test]
+    fn matches_jumps_and_alternatives() {
+        let pattern = Pattern::parse("aa{1-2}(bb|cccc)dd").unwrap();
+        assert_eq!(pattern.match_at(b"\xaa\x00\xbb\xdd", 0), Some(4));
+        assert_eq!(pattern.match_at(b"\xaa\x00\x00\xcc\xcc\xdd", 0), Some(6));
+        assert_eq!(pattern.match_at(b"\xaa\xbb\xdd", 0), None);
+        assert_eq!(pattern.match_at(b"\xaa\x00\x00\x00\xbb\xdd", 0), None);
+        assert_eq!(pattern.match_at(b"\x00\xaa\x00\xbb\xdd", 1), Some(4));
+        assert_eq!(Pattern::parse("4?").unwrap().match_at(b"\x4f", 0), Some(1));
+        assert_eq!(Pattern::parse("4?").unwrap().match_at(b"\x5f", 0), None);
+    }
+
+    #[test]
+    fn atom_is_the_longest_fixed_run() {
+        let atom = Pattern::parse("aa{0-3}bbccdd??ee").unwrap().atom();
+        assert_eq!(atom.variants, [vec![0xbb, 0xcc, 0xdd]]);
+        assert_eq!((atom.left_min, atom.left_max), (1, 4));
+    }
+
+    #[test]
+    fn wide_and_ignore_case() {
+        let wide = Pattern::literal(b"ab").wide().unwrap();
+        assert_eq!(wide.literal_bytes(), Some(b"a\0b\0".to_vec()));
+        let any_case = Pattern::literal(b"a1").ignore_case();
+        assert_eq!(any_case.match_at(b"A1", 0), Some(2));
+        assert_eq!(any_case.match_at(b"a1", 0), Some(2));
+        assert_eq!(any_case.match_at(b"b1", 0), None);
+    }
+}
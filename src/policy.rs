@@ -0,0 +1,506 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use fnv::FnvHashSet;
+use globset::{Glob, GlobMatcher};
+use serde::{Deserialize, Serialize};
+
+use crate::detection::Detection;
+use crate::quarantine::Vault;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl Severity {
+    // Used when a signature doesn't state its own severity.
+    pub fn for_category(category: &str) -> Severity {
+        match category {
+            "ransomware" => Severity::Critical,
+            "virus" | "trojan" | "worm" | "backdoor" | "rootkit" => Severity::High,
+            "pua" | "adware" => Severity::Low,
+            _ => Severity::Medium,
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Low => "low",
+            Severity::Medium => "medium",
+            Severity::High => "high",
+            Severity::Critical => "critical",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActionKind {
+    Report,
+    Quarantine,
+    Delete,
+    Chmod,
+    Rename,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    Report,
+    Quarantine,
+    Delete,
+    // Strips read and execute permission for everyone.
+    StripPermissions,
+    Rename(String),
+}
+
+const DEFAULT_SUFFIX: &str = ".infected";
+
+impl Action {
+    fn from_kind(kind: ActionKind, suffix: Option<String>) -> Action {
+        match kind {
+            ActionKind::Report => Action::Report,
+            ActionKind::Quarantine => Action::Quarantine,
+            ActionKind::Delete => Action::Delete,
+            ActionKind::Chmod => Action::StripPermissions,
+            ActionKind::Rename => Action::Rename(suffix.unwrap_or_else(|| DEFAULT_SUFFIX.to_string())),
+        }
+    }
+
+    // When several detections in one file ask for different actions, the most
+    // drastic one wins.
+    fn strength(&self) -> u8 {
+        match self {
+            Action::Report => 0,
+            Action::StripPermissions => 1,
+            Action::Rename(_) => 2,
+            Action::Quarantine => 3,
+            Action::Delete => 4,
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Report => write!(f, "report"),
+            Action::Quarantine => write!(f, "quarantine"),
+            Action::Delete => write!(f, "delete"),
+            Action::StripPermissions => write!(f, "chmod"),
+            Action::Rename(suffix) => write!(f, "rename *{}", suffix),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    signature: Option<String>,
+    category: Option<String>,
+    min_severity: Option<Severity>,
+    path: Option<String>,
+    action: ActionKind,
+    suffix: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicySpec {
+    vault: Option<PathBuf>,
+    action: Option<ActionKind>,
+    suffix: Option<String>,
+    #[serde(default, rename = "rule")]
+    rules: Vec<RuleSpec>,
+}
+
+struct Rule {
+    signature: Option<GlobMatcher>,
+    category: Option<String>,
+    min_severity: Option<Severity>,
+    path: Option<GlobMatcher>,
+    action: Action,
+}
+
+impl Rule {
+    fn matches(&self, path: &str, signature: &str, category: &str, severity: Severity) -> bool {
+        self.signature.as_ref().is_none_or(|glob| glob.is_match(signature))
+            && self.category.as_ref().is_none_or(|c| c.eq_ignore_ascii_case(category))
+            && self.min_severity.is_none_or(|min| severity >= min)
+            && self.path.as_ref().is_none_or(|glob| glob.is_match(path))
+    }
+}
+
+fn compile_glob(pattern: &str) -> io::Result<GlobMatcher> {
+    Glob::new(pattern)
+        .map(|glob| glob.compile_matcher())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+// Ordered response rules loaded from TOML; the first rule matching a detection
+// decides its action, otherwise the policy-wide default applies. `path` globs
+// match the absolute path of the file, however it was reached by the scan:
+//
+//     action = "report"
+//     vault = "quarantine"
+//
+//     [[rule]]
+//     category = "trojan"
+//     min_severity = "high"
+//     path = "/srv/**"
+//     action = "quarantine"
+pub struct Policy {
+    rules: Vec<Rule>,
+    default: Action,
+    vault: Option<PathBuf>,
+}
+
+impl Policy {
+    pub fn always(action: Action) -> Policy {
+        Policy { rules: Vec::new(), default: action, vault: None }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Policy> {
+        let text = fs::read_to_string(&path)?;
+        Policy::parse(&text).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.as_ref().display(), e)))
+    }
+
+    pub fn parse(text: &str) -> io::Result<Policy> {
+        let spec: PolicySpec = toml::from_str(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let mut rules = Vec::with_capacity(spec.rules.len());
+        for rule in spec.rules {
+            rules.push(Rule {
+                signature: rule.signature.as_deref().map(compile_glob).transpose()?,
+                category: rule.category,
+                min_severity: rule.min_severity,
+                path: rule.path.as_deref().map(compile_glob).transpose()?,
+                action: Action::from_kind(rule.action, rule.suffix),
+            });
+        }
+        Ok(Policy {
+            rules,
+            default: Action::from_kind(spec.action.unwrap_or(ActionKind::Report), spec.suffix),
+            vault: spec.vault,
+        })
+    }
+
+    pub fn set_vault(&mut self, vault: PathBuf) {
+        self.vault = Some(vault);
+    }
+
+    pub fn decide(&self, path: &str, signature: &str, category: &str, severity: Severity) -> &Action {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(path, signature, category, severity))
+            .map(|rule| &rule.action)
+            .unwrap_or(&self.default)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub path: String,
+    pub action: Action,
+    pub signatures: Vec<String>,
+    pub dry_run: bool,
+    // What was done, e.g. the quarantine id or the new name, or why it failed.
+    pub outcome: Result<String, String>,
+}
+
+// Applies a policy to the detections of each file as it is recorded.
+pub struct Responder {
+    policy: Policy,
+    dry_run: bool,
+    vault: Option<Vault>,
+    responses: Vec<Response>,
+    // Absolute paths already acted on, so a file scanned through two
+    // overlapping roots is only acted on once.
+    handled: FnvHashSet<PathBuf>,
+}
+
+// `path` made absolute through its directory, which still works once the file
+// itself has been deleted or renamed. `None` if the directory doesn't resolve.
+fn absolute_path(path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    let name = path.file_name()?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Some(fs::canonicalize(parent).ok()?.join(name))
+}
+
+impl Responder {
+    pub fn new(policy: Policy, dry_run: bool) -> Responder {
+        Responder { policy, dry_run, vault: None, responses: Vec::new(), handled: FnvHashSet::default() }
+    }
+
+    pub fn responses(&self) -> &[Response] {
+        &self.responses
+    }
+
+    pub fn respond(&mut self, path: &str, detections: &[Detection]) {
+        let absolute = absolute_path(path);
+        if let Some(absolute) = &absolute {
+            if !self.handled.insert(absolute.clone()) {
+                return;
+            }
+        }
+        let matched = absolute.as_deref().and_then(Path::to_str).unwrap_or(path);
+        let mut action = &Action::Report;
+        let mut signatures = Vec::new();
+        for detection in detections {
+            let category = detection.category();
+            let decided = self.policy.decide(matched, &detection.signature, &category, detection.severity());
+            if decided.strength() > action.strength() {
+                action = decided;
+                signatures.clear();
+            }
+            if decided == action && !signatures.contains(&detection.signature) {
+                signatures.push(detection.signature.clone());
+            }
+        }
+        let action = action.clone();
+        let outcome = if self.dry_run {
+            Ok(String::new())
+        } else {
+            self.execute(path, &action, detections).map_err(|e| e.to_string())
+        };
+        self.responses.push(Response { path: path.to_owned(), action, signatures, dry_run: self.dry_run, outcome });
+    }
+
+    fn execute(&mut self, path: &str, action: &Action, detections: &[Detection]) -> io::Result<String> {
+        match action {
+            Action::Report => Ok(String::new()),
+            Action::Quarantine => {
+                if self.vault.is_none() {
+                    let dir = self.policy.vault.clone().unwrap_or_else(|| PathBuf::from("quarantine"));
+                    self.vault = Some(Vault::open(dir)?);
+                }
+                let vault = self.vault.as_mut().unwrap();
+                Ok(vault.quarantine(path, detections)?.id)
+            }
+            Action::Delete => fs::remove_file(path).map(|_| String::new()),
+            Action::StripPermissions => strip_permissions(path),
+            Action::Rename(suffix) => {
+                let target = unused_name(&format!("{}{}", path, suffix));
+                fs::rename(path, &target)?;
+                Ok(target)
+            }
+        }
+    }
+}
+
+// `name`, or `name.1`, `name.2` and so on if it is taken, so renaming never
+// replaces another file, such as one renamed by an earlier scan.
+fn unused_name(name: &str) -> String {
+    let taken = |candidate: &str| fs::symlink_metadata(candidate).is_ok();
+    if !taken(name) {
+        return name.to_string();
+    }
+    (1..).map(|n| format!("{}.{}", name, n)).find(|candidate| !taken(candidate)).unwrap()
+}
+
+#[cfg(unix)]
+fn strip_permissions(path: &str) -> io::Result<String> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(path)?.permissions().mode() & 0o7777 & !0o555;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(format!("{:o}", mode))
+}
+
+#[cfg(not(unix))]
+fn strip_permissions(path: &str) -> io::Result<String> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(path, permissions)?;
+    Ok("read-only".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::{unused_name, Action, Policy, Responder, Severity};
+    use crate::detection::Detection;
+
+    const POLICY: &str = r#"
+action = "report"
+
+[[rule]]
+signature = "Eicar.*"
+action = "delete"
+
+[[rule]]
+category = "TROJAN"
+min_severity = "high"
+action = "quarantine"
+
+[[rule]]
+path = "/srv/**"
+action = "rename"
+suffix = ".bad"
+
+[[rule]]
+min_severity = "medium"
+action = "chmod"
+"#;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("anti_virus-policy-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn detection(signature: &str) -> Detection {
+        Detection::new(signature, 2, b"ab", 0, 0)
+    }
+
+    fn file(dir: &Path, name: &str) -> String {
+        let path = dir.join(name);
+        fs::write(&path, name).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let policy = Policy::parse(POLICY).unwrap();
+        let decide = |path, signature: &str| {
+            let detection = detection(signature);
+            policy.decide(path, signature, &detection.category(), detection.severity()).clone()
+        };
+        assert_eq!(decide("/srv/a", "Eicar.Test"), Action::Delete);
+        assert_eq!(decide("/srv/a", "Trojan.Agent"), Action::Quarantine);
+        assert_eq!(decide("/srv/a", "Adware.Bar"), Action::Rename(".bad".to_string()));
+        assert_eq!(decide("/home/a", "Worm.X"), Action::StripPermissions);
+        assert_eq!(decide("/home/a", "Adware.Bar"), Action::Report);
+        assert_eq!(Severity::for_category("adware"), Severity::Low);
+    }
+
+    #[test]
+    fn invalid_policies_are_refused() {
+        assert!(Policy::parse("action = \"explode\"").is_err());
+        assert!(Policy::parse("[[rule]]\naction = \"delete\"\nsignatur = \"x\"").is_err());
+        assert!(Policy::parse("[[rule]]\npath = \"[\"\naction = \"delete\"").is_err());
+        assert_eq!(Policy::parse("").unwrap().decide("/a", "Worm.X", "worm", Severity::High), &Action::Report);
+    }
+
+    #[test]
+    fn most_drastic_action_wins() {
+        let dir = temp_dir("strongest");
+        let path = file(&dir, "sample");
+        let mut responder = Responder::new(Policy::parse(POLICY).unwrap(), true);
+        responder.respond(&path, &[detection("Worm.A"), detection("Eicar.B"), detection("Eicar.C"), detection("Worm.D")]);
+        let response = &responder.responses()[0];
+        assert_eq!(response.action, Action::Delete);
+        assert_eq!(response.signatures, ["Eicar.B", "Eicar.C"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn actions_change_the_files() {
+        let dir = temp_dir("actions");
+        let policy = format!("vault = \"{}\"\n{}", dir.join("vault").display(), POLICY);
+        let mut responder = Responder::new(Policy::parse(&policy).unwrap(), false);
+
+        let deleted = file(&dir, "deleted");
+        responder.respond(&deleted, &[detection("Eicar.Test")]);
+        assert!(!fs::exists(&deleted).unwrap());
+
+        let quarantined = file(&dir, "quarantined");
+        responder.respond(&quarantined, &[detection("Trojan.Agent")]);
+        assert!(!fs::exists(&quarantined).unwrap());
+        assert!(fs::exists(dir.join("vault").join("index.json")).unwrap());
+
+        let reported = file(&dir, "reported");
+        responder.respond(&reported, &[detection("Adware.Bar")]);
+        assert_eq!(fs::read_to_string(&reported).unwrap(), "reported");
+
+        let outcomes: Vec<bool> = responder.responses().iter().map(|response| response.outcome.is_ok()).collect();
+        assert_eq!(outcomes, [true, true, true]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn renames_never_replace_files() {
+        let dir = temp_dir("rename");
+        let mut responder = Responder::new(Policy::always(Action::Rename(".infected".to_string())), false);
+        let path = file(&dir, "sample");
+        fs::write(format!("{}.infected", path), "earlier").unwrap();
+        assert_eq!(unused_name(&format!("{}.infected", path)), format!("{}.infected.1", path));
+        responder.respond(&path, &[detection("Worm.A")]);
+        assert_eq!(responder.responses()[0].outcome, Ok(format!("{}.infected.1", path)));
+        assert_eq!(fs::read_to_string(format!("{}.infected", path)).unwrap(), "earlier");
+        assert_eq!(fs::read_to_string(format!("{}.infected.1", path)).unwrap(), "sample");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn chmod_strips_read_and_execute() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir("chmod");
+        let path = file(&dir, "sample");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o4755)).unwrap();
+        let mut responder = Responder::new(Policy::always(Action::StripPermissions), false);
+        responder.respond(&path, &[detection("Worm.A")]);
+        assert_eq!(responder.responses()[0].outcome, Ok("4200".to_string()));
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o7777, 0o4200);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dry_run_touches_nothing() {
+        let dir = temp_dir("dry-run");
+        let policy = format!("vault = \"{}\"\n{}", dir.join("vault").display(), POLICY);
+        let mut responder = Responder::new(Policy::parse(&policy).unwrap(), true);
+        let names = ["delete", "quarantine", "chmod"];
+        let signatures = ["Eicar.Test", "Trojan.Agent", "Worm.X"];
+        for (name, signature) in names.iter().zip(signatures) {
+            responder.respond(&file(&dir, name), &[detection(signature)]);
+        }
+        let mut rename = Responder::new(Policy::always(Action::Rename(".infected".to_string())), true);
+        rename.respond(&file(&dir, "rename"), &[detection("Worm.X")]);
+        let actions: Vec<String> = responder.responses().iter().map(|response| response.action.to_string()).collect();
+        assert_eq!(actions, ["delete", "quarantine", "chmod"]);
+        assert!(responder.responses().iter().chain(rename.responses()).all(|response| response.dry_run));
+
+        let mut left: Vec<String> =
+            fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        left.sort();
+        assert_eq!(left, ["chmod", "delete", "quarantine", "rename"]);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_ne!(fs::metadata(dir.join("chmod")).unwrap().permissions().mode() & 0o444, 0);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn paths_match_however_they_were_scanned() {
+        let dir = temp_dir("paths");
+        let absolute = fs::canonicalize(&dir).unwrap();
+        let policy = format!("[[rule]]\npath = \"{}/**\"\naction = \"delete\"", absolute.display());
+        let mut responder = Responder::new(Policy::parse(&policy).unwrap(), false);
+        let path = file(&dir, "sample");
+        let dotted = format!("{}/./sample", dir.join("..").join(dir.file_name().unwrap()).display());
+        responder.respond(&dotted, &[detection("Worm.A")]);
+        assert!(!fs::exists(&path).unwrap());
+        // The same file reached through another root is only acted on once.
+        responder.respond(&path, &[detection("Worm.A")]);
+        assert_eq!(responder.responses().len(), 1);
+        assert!(responder.responses()[0].outcome.is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,482 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::detection::Detection;
+use crate::file_compare::FileCompare;
+use crate::report::format_time;
+
+const INDEX_FILE: &str = "index.json";
+// Written in front of every stored file so nothing in the vault starts with a
+// recognizable executable header.
+const MAGIC: &[u8; 8] = b"AVQUAR01";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QuarantineEntry {
+    pub id: String,
+    pub original_path: String,
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub sha256: String,
+    pub signatures: Vec<String>,
+    pub quarantined_at: String,
+}
+
+// Directory holding neutered copies of detected files plus an index with
+// everything needed to put them back.
+pub struct Vault {
+    dir: PathBuf,
+    entries: Vec<QuarantineEntry>,
+}
+
+// Keystream for the XOR encoding, derived from the entry id so it never has to
+// be stored.
+fn key_for(id: &str) -> [u8; 32] {
+    Sha256::digest(format!("anti_virus quarantine {}", id).as_bytes()).into()
+}
+
+fn xor_in_place(data: &mut [u8], key: &[u8; 32], position: u64) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= key[((position + i as u64) % 32) as usize];
+    }
+}
+
+#[cfg(unix)]
+fn ownership(metadata: &fs::Metadata) -> (u32, u32, u32) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.mode() & 0o7777, metadata.uid(), metadata.gid())
+}
+
+#[cfg(not(unix))]
+fn ownership(metadata: &fs::Metadata) -> (u32, u32, u32) {
+    (if metadata.permissions().readonly() { 0o444 } else { 0o644 }, 0, 0)
+}
+
+#[cfg(unix)]
+fn apply_ownership(path: &Path, entry: &QuarantineEntry) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(entry.mode))?;
+    // Only root can hand files to other users; keep the restoring user's ownership otherwise.
+    match std::os::unix::fs::chown(path, Some(entry.uid), Some(entry.gid)) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(not(unix))]
+fn apply_ownership(path: &Path, entry: &QuarantineEntry) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(entry.mode & 0o200 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+fn create_private(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+#[cfg(unix)]
+fn check_private(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(dir)?.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is accessible to other users (mode {:o}), refusing to use it as a quarantine vault; chmod 700 it if it is one",
+                dir.display(),
+                mode
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn not_found(id: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no quarantined file with id {}", id))
+}
+
+impl Vault {
+    // Creates the vault readable only by its owner if it doesn't exist yet. An
+    // existing directory others can get into is refused rather than having its
+    // permissions changed, since it may not be a vault at all.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Vault> {
+        let dir = dir.as_ref().to_path_buf();
+        if dir.is_dir() {
+            check_private(&dir)?;
+        } else {
+            if let Some(parent) = dir.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)?;
+            }
+            let mut builder = fs::DirBuilder::new();
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::DirBuilderExt;
+                builder.mode(0o700);
+            }
+            builder.create(&dir)?;
+        }
+        let index = dir.join(INDEX_FILE);
+        let entries = if index.exists() {
+            serde_json::from_reader(BufReader::new(File::open(&index)?))?
+        } else {
+            Vec::new()
+        };
+        Ok(Vault { dir, entries })
+    }
+
+    pub fn entries(&self) -> &[QuarantineEntry] {
+        &self.entries
+    }
+
+    fn blob_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.bin", id))
+    }
+
+    // Rewrites the index through a temporary file so a crash never leaves it half written.
+    fn save_index(&self) -> io::Result<()> {
+        let temp = self.dir.join(format!("{}.tmp", INDEX_FILE));
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        serde_json::to_writer_pretty(&mut writer, &self.entries)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(temp, self.dir.join(INDEX_FILE))
+    }
+
+    fn new_id(&self, path: &str) -> String {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        let mut salt = 0u32;
+        loop {
+            let digest = Sha256::digest(format!("{}:{}:{}", path, nanos, salt).as_bytes());
+            let id = hex::encode(&digest[..6]);
+            if !self.entries.iter().any(|entry| entry.id == id) {
+                return id;
+            }
+            salt += 1;
+        }
+    }
+
+    // Moves `path` into the vault XOR-encoded, then removes the original.
+    pub fn quarantine(&mut self, path: &str, detections: &[Detection]) -> io::Result<QuarantineEntry> {
+        let metadata = fs::symlink_metadata(path)?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a regular file", path)));
+        }
+        let (mode, uid, gid) = ownership(&metadata);
+        let id = self.new_id(path);
+        let key = key_for(&id);
+        let blob = self.blob_path(&id);
+
+        let mut source = File::open(path)?;
+        let mut writer = BufWriter::new(create_private(&blob)?);
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut position = 0u64;
+        let copied = (|| -> io::Result<()> {
+            writer.write_all(MAGIC)?;
+            loop {
+                let read = source.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..read]);
+                xor_in_place(&mut buffer[..read], &key, position);
+                writer.write_all(&buffer[..read])?;
+                position += read as u64;
+            }
+            writer.flush()?;
+            writer.get_ref().sync_all()
+        })();
+        if let Err(e) = copied {
+            let _ = fs::remove_file(&blob);
+            return Err(e);
+        }
+
+        let mut signatures: Vec<String> = detections.iter().map(|d| d.signature.clone()).collect();
+        signatures.sort();
+        signatures.dedup();
+        let entry = QuarantineEntry {
+            id,
+            original_path: fs::canonicalize(path).map(|p| p.to_string_lossy().into_owned()).unwrap_or_else(|_| path.to_owned()),
+            size: position,
+            mode,
+            uid,
+            gid,
+            sha256: hex::encode(hasher.finalize()),
+            signatures,
+            quarantined_at: format_time(SystemTime::now()),
+        };
+        self.entries.push(entry.clone());
+        if let Err(e) = self.save_index() {
+            self.entries.pop();
+            let _ = fs::remove_file(&blob);
+            return Err(e);
+        }
+        // The file stays where it is, so the vault mustn't offer to restore it
+        // over itself.
+        if let Err(e) = fs::remove_file(path) {
+            self.entries.pop();
+            let _ = self.save_index();
+            let _ = fs::remove_file(&blob);
+            return Err(e);
+        }
+        Ok(entry)
+    }
+
+    // Quarantines every file `tester` recorded as risky. Each file gets its own
+    // result so one failure doesn't stop the rest.
+    pub fn quarantine_detected(&mut self, tester: &FileCompare) -> Vec<(String, io::Result<QuarantineEntry>)> {
+        tester
+            .get_risk_files()
+            .iter()
+            .map(|(path, detections)| (path.clone(), self.quarantine(path, detections)))
+            .collect()
+    }
+
+    // Decodes an entry back to its original location (or `target`), checks the
+    // hash and reapplies permissions and ownership before dropping it from the vault.
+    pub fn restore(&mut self, id: &str, target: Option<&Path>, overwrite: bool) -> io::Result<QuarantineEntry> {
+        let index = self.entries.iter().position(|entry| entry.id == id).ok_or_else(|| not_found(id))?;
+        let entry = self.entries[index].clone();
+        let target = target.map(Path::to_path_buf).unwrap_or_else(|| PathBuf::from(&entry.original_path));
+        if target.exists() && !overwrite {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", target.display())));
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let key = key_for(id);
+        let mut reader = BufReader::new(File::open(self.blob_path(id))?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a quarantine file", id)));
+        }
+        let temp = target.with_file_name(format!(
+            ".{}.restore",
+            target.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+        ));
+        // Never follows a link planted at the temporary name, and keeps the
+        // decoded file private until its own permissions are applied.
+        let file = create_private(&temp).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", temp.display(), e)))?;
+        let restored = (|| -> io::Result<()> {
+            let mut writer = BufWriter::new(file);
+            let mut hasher = Sha256::new();
+            let mut buffer = vec![0u8; 64 * 1024];
+            let mut position = 0u64;
+            loop {
+                let read = reader.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                xor_in_place(&mut buffer[..read], &key, position);
+                hasher.update(&buffer[..read]);
+                writer.write_all(&buffer[..read])?;
+                position += read as u64;
+            }
+            writer.flush()?;
+            drop(writer);
+            if hex::encode(hasher.finalize()) != entry.sha256 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("hash mismatch restoring {}", id)));
+            }
+            apply_ownership(&temp, &entry)?;
+            fs::rename(&temp, &target)
+        })();
+        if let Err(e) = restored {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        self.entries.remove(index);
+        self.save_index()?;
+        fs::remove_file(self.blob_path(id))?;
+        Ok(entry)
+    }
+
+    // Permanently deletes the given entries, or all of them when `ids` is empty.
+    pub fn purge(&mut self, ids: &[String]) -> io::Result<Vec<QuarantineEntry>> {
+        for id in ids {
+            if !self.entries.iter().any(|entry| &entry.id == id) {
+                return Err(not_found(id));
+            }
+        }
+        let (purged, kept): (Vec<_>, Vec<_>) = self
+            .entries
+            .drain(..)
+            .partition(|entry| ids.is_empty() || ids.contains(&entry.id));
+        self.entries = kept;
+        self.save_index()?;
+        for entry in &purged {
+            match fs::remove_file(self.blob_path(&entry.id)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+
+    use sha2::{Digest, Sha256};
+
+    use super::Vault;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("anti_virus-quarantine-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // A vault in `dir` and a file to put in it.
+    fn setup(dir: &std::path::Path, contents: &[u8]) -> (Vault, String) {
+        let vault = Vault::open(dir.join("vault")).unwrap();
+        let file = dir.join("infected.bin");
+        fs::write(&file, contents).unwrap();
+        (vault, file.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn round_trip_keeps_bytes_and_mode() {
+        let dir = temp_dir("round-trip");
+        let contents = b"MZ\x90\x00 not really malware".repeat(5000);
+        let (mut vault, file) = setup(&dir, &contents);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+        }
+        let entry = vault.quarantine(&file, &[]).unwrap();
+        assert!(!fs::exists(&file).unwrap());
+        assert_eq!(entry.sha256, hex::encode(Sha256::digest(&contents)));
+        assert_eq!(entry.size, contents.len() as u64);
+        let blob = fs::read(vault.blob_path(&entry.id)).unwrap();
+        assert!(!blob.windows(8).any(|window| window == &contents[..8]));
+
+        // The index is what a later run sees.
+        let mut vault = Vault::open(dir.join("vault")).unwrap();
+        assert_eq!(vault.entries().len(), 1);
+        let restored = vault.restore(&entry.id, None, false).unwrap();
+        assert_eq!(restored.id, entry.id);
+        assert_eq!(fs::read(&file).unwrap(), contents);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&file).unwrap().permissions().mode() & 0o7777, 0o640);
+        }
+        assert!(vault.entries().is_empty());
+        assert!(!fs::exists(vault.blob_path(&entry.id)).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn restore_keeps_existing_files_unless_told() {
+        let dir = temp_dir("existing");
+        let (mut vault, file) = setup(&dir, b"quarantined");
+        let entry = vault.quarantine(&file, &[]).unwrap();
+        fs::write(&file, b"new file").unwrap();
+        assert_eq!(vault.restore(&entry.id, None, false).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&file).unwrap(), b"new file");
+        let elsewhere = dir.join("elsewhere").join("copy.bin");
+        vault.restore(&entry.id, Some(&elsewhere), false).unwrap();
+        assert_eq!(fs::read(&elsewhere).unwrap(), b"quarantined");
+
+        let entry = vault.quarantine(elsewhere.to_str().unwrap(), &[]).unwrap();
+        fs::write(&elsewhere, b"new file").unwrap();
+        vault.restore(&entry.id, None, true).unwrap();
+        assert_eq!(fs::read(&elsewhere).unwrap(), b"quarantined");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn purge_removes_the_given_entries_or_all() {
+        let dir = temp_dir("purge");
+        let vault_dir = dir.join("vault");
+        let mut vault = Vault::open(&vault_dir).unwrap();
+        let mut ids = Vec::new();
+        for i in 0..3 {
+            let file = dir.join(format!("{}.bin", i));
+            fs::write(&file, format!("file {}", i)).unwrap();
+            ids.push(vault.quarantine(file.to_str().unwrap(), &[]).unwrap().id);
+        }
+        assert_eq!(vault.purge(&["unknown".to_string()]).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(vault.entries().len(), 3);
+
+        let purged = vault.purge(&ids[1..2]).unwrap();
+        assert_eq!(purged.len(), 1);
+        assert_eq!(purged[0].id, ids[1]);
+        assert!(!fs::exists(vault.blob_path(&ids[1])).unwrap());
+        let kept: Vec<&str> = vault.entries().iter().map(|entry| entry.id.as_str()).collect();
+        assert_eq!(kept, [ids[0].as_str(), ids[2].as_str()]);
+
+        assert_eq!(vault.purge(&[]).unwrap().len(), 2);
+        assert!(Vault::open(&vault_dir).unwrap().entries().is_empty());
+        assert!(!fs::exists(vault.blob_path(&ids[0])).unwrap() && !fs::exists(vault.blob_path(&ids[2])).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_blobs_are_not_restored() {
+        let dir = temp_dir("damaged");
+        let (mut vault, file) = setup(&dir, b"some quarantined bytes");
+        let entry = vault.quarantine(&file, &[]).unwrap();
+        let blob = vault.blob_path(&entry.id);
+        let mut bytes = fs::read(&blob).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        fs::write(&blob, bytes).unwrap();
+        let error = vault.restore(&entry.id, None, false).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(!fs::exists(&file).unwrap());
+        // Neither the target nor the temporary file is left behind.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(vault.entries().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restore_does_not_follow_planted_links() {
+        let dir = temp_dir("link");
+        let (mut vault, file) = setup(&dir, b"payload");
+        let entry = vault.quarantine(&file, &[]).unwrap();
+        let victim = dir.join("victim");
+        fs::write(&victim, b"keep me").unwrap();
+        std::os::unix::fs::symlink(&victim, dir.join(".infected.bin.restore")).unwrap();
+        assert_eq!(vault.restore(&entry.id, None, false).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&victim).unwrap(), b"keep me");
+        assert_eq!(vault.entries().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn vaults_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir("private");
+        Vault::open(dir.join("new")).unwrap();
+        assert_eq!(fs::metadata(dir.join("new")).unwrap().permissions().mode() & 0o777, 0o700);
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(Vault::open(&dir).err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+use std::io;
+use std::time::{Instant, Duration, SystemTime};
+use std::sync::Arc;
+use walkdir::WalkDir;
+use rayon::prelude::*;
+use crossbeam::channel;
+use serde::{Deserialize, Serialize};
+
+use crate::detection::Detection;
+use crate::file_compare::FileCompare;
+use crate::logging::write_to_log;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScanError {
+    pub path: String,
+    pub message: String,
+}
+
+// Receives results on the collector thread as soon as each file is done, e.g. to
+// stream a report while the scan is still running.
+pub trait ScanSink: Send {
+    fn file_scanned(&mut self, _path: &str, _detections: &[Detection]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn scan_error(&mut self, _error: &ScanError) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct NoSink;
+
+impl ScanSink for NoSink {}
+
+enum ScanEvent {
+    Dir(Arc<str>),
+    File(Arc<str>, Vec<Detection>),
+    Error(ScanError),
+    LogError(io::Error),
+}
+
+#[derive(Default)]
+struct Collected {
+    files: Vec<Arc<str>>,
+    dirs: Vec<Arc<str>>,
+    hits: Vec<(Arc<str>, Vec<Detection>)>,
+    errors: Vec<ScanError>,
+    sink_error: Option<io::Error>,
+    log_error: Option<io::Error>,
+}
+
+pub struct RecFileSearch {
+    roots: Vec<Arc<str>>,
+    found_files: Vec<Arc<str>>,
+    found_dirs: Vec<Arc<str>>,
+    errors: Vec<ScanError>,
+    started_at: Option<SystemTime>,
+    finished_at: Option<SystemTime>,
+    tester: FileCompare,
+}
+
+impl RecFileSearch {
+    pub fn new(directory: String, tester: FileCompare) -> RecFileSearch {
+        RecFileSearch::with_roots(vec![directory], tester)
+    }
+
+    pub fn with_roots(roots: Vec<String>, tester: FileCompare) -> RecFileSearch {
+        RecFileSearch {
+            roots: roots.into_iter().map(Arc::from).collect(),
+            found_files: Vec::new(),
+            found_dirs: Vec::new(),
+            errors: Vec::new(),
+            started_at: None,
+            finished_at: None,
+            tester,
+        }
+    }
+
+    // Workers only share one snapshot of the databases, taken as the pass starts;
+    // everything they find is sent to a single collector thread, so no lock is
+    // held while files are scanned.
+    pub fn start(&mut self) -> io::Result<Duration> {
+        self.start_with_sink(&mut NoSink)
+    }
+
+    pub fn start_with_sink(&mut self, sink: &mut dyn ScanSink) -> io::Result<Duration> {
+        self.started_at = Some(SystemTime::now());
+        let start_time = Instant::now();
+        let roots = self.roots.clone();
+        let tester = &self.tester;
+        let databases = tester.get_database_handle().snapshot();
+        let (sender, receiver) = channel::unbounded();
+        let collected = std::thread::scope(|scope| {
+            let collector = scope.spawn(move || {
+                let mut collected = Collected::default();
+                for event in receiver {
+                    let sent = match &event {
+                        ScanEvent::Dir(_) | ScanEvent::LogError(_) => Ok(()),
+                        ScanEvent::File(path, detections) => sink.file_scanned(path, detections),
+                        ScanEvent::Error(error) => sink.scan_error(error),
+                    };
+                    if let Err(e) = sent {
+                        collected.sink_error.get_or_insert(e);
+                    }
+                    match event {
+                        ScanEvent::Dir(path) => collected.dirs.push(path),
+                        ScanEvent::File(path, detections) => {
+                            if !detections.is_empty() {
+                                collected.hits.push((Arc::clone(&path), detections));
+                            }
+                            collected.files.push(path);
+                        }
+                        ScanEvent::Error(error) => collected.errors.push(error),
+                        ScanEvent::LogError(e) => {
+                            collected.log_error.get_or_insert(e);
+                        }
+                    }
+                }
+                collected
+            });
+            let entries = roots.iter().flat_map(|root| WalkDir::new(&**root).into_iter().map(move |entry| (root, entry)));
+            entries.par_bridge().for_each_with(sender, |sender, (directory, entry)| {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        let path = e.path().map(|p| p.to_string_lossy().into_owned()).unwrap_or_default();
+                        sender.send(ScanEvent::Error(ScanError { path, message: e.to_string() })).unwrap();
+                        return;
+                    }
+                };
+                if entry.file_type().is_dir() {
+                    let dir_path: Arc<str> = Arc::from(entry.path().to_string_lossy().into_owned());
+                    let dir_start_time = Instant::now();
+                    let dir_elapsed = dir_start_time.elapsed();
+                    if let Err(e) = write_to_log(directory, &format!("Directory: {} - Time: {:?}", dir_path, dir_elapsed)) {
+                        sender.send(ScanEvent::LogError(e)).unwrap();
+                    }
+                    sender.send(ScanEvent::Dir(dir_path)).unwrap();
+                } else if entry.file_type().is_file() {
+                    let file_path: Arc<str> = Arc::from(entry.path().to_string_lossy().into_owned());
+                    let file_start_time = Instant::now();
+                    let event = match tester.scan_file_with(&databases, &file_path) {
+                        Ok(detections) => ScanEvent::File(file_path.clone(), detections),
+                        Err(e) => ScanEvent::Error(ScanError { path: file_path.to_string(), message: e.to_string() }),
+                    };
+                    let file_elapsed = file_start_time.elapsed();
+                    if let Err(e) = write_to_log(directory, &format!("File: {} - Time: {:?}", file_path, file_elapsed)) {
+                        sender.send(ScanEvent::LogError(e)).unwrap();
+                    }
+                    sender.send(event).unwrap();
+                }
+            });
+            collector.join().unwrap()
+        });
+        for (path, detections) in collected.hits {
+            self.tester.record(&path, detections);
+        }
+        self.found_files.extend(collected.files);
+        self.found_dirs.extend(collected.dirs);
+        self.errors.extend(collected.errors);
+        self.finished_at = Some(SystemTime::now());
+        // Results are kept either way; a failed sink or log still fails the scan.
+        if let Some(e) = collected.sink_error.or(collected.log_error) {
+            return Err(e);
+        }
+        let elapsed = start_time.elapsed();
+        for root in &self.roots {
+            write_to_log(root, &format!("Total runtime: {:?}", elapsed))?;
+        }
+        Ok(elapsed)
+    }
+
+    pub fn get_files(&self) -> &[Arc<str>] {
+        &self.found_files
+    }
+
+    pub fn get_dirs(&self) -> &[Arc<str>] {
+        &self.found_dirs
+    }
+
+    pub fn get_errors(&self) -> &[ScanError] {
+        &self.errors
+    }
+
+    pub fn get_started_at(&self) -> Option<SystemTime> {
+        self.started_at
+    }
+
+    pub fn get_finished_at(&self) -> Option<SystemTime> {
+        self.finished_at
+    }
+
+    pub fn get_roots(&self) -> &[Arc<str>] {
+        &self.roots
+    }
+
+    pub fn get_tester(&self) -> &FileCompare {
+        &self.tester
+    }
+
+    pub fn into_tester(self) -> FileCompare {
+        self.tester
+    }
+
+    pub fn set_dir(&mut self, path: Arc<str>) {
+        self.roots = vec![path];
+    }
+}
@@ -0,0 +1,213 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::file_compare::{DatabaseHandle, Databases};
+
+// What a watched path looked like: length and modification time of the file,
+// or of every entry of a directory. `None` if it doesn't exist.
+type Stamp = Option<Vec<(PathBuf, u64, Option<SystemTime>)>>;
+
+fn stamp(path: &Path) -> Stamp {
+    let metadata = fs::metadata(path).ok()?;
+    if !metadata.is_dir() {
+        return Some(vec![(path.to_path_buf(), metadata.len(), metadata.modified().ok())]);
+    }
+    let mut entries: Vec<_> = fs::read_dir(path)
+        .ok()?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let metadata = entry.metadata().ok()?;
+            Some((entry.path(), metadata.len(), metadata.modified().ok()))
+        })
+        .collect();
+    entries.sort();
+    Some(entries)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    // SIGHUP, or `Reloader::request`.
+    Requested,
+    // A watched database file changed.
+    Changed,
+}
+
+pub enum Reload {
+    Swapped { old_version: String, new_version: String },
+    // The new databases didn't load or had nothing in them; scans keep using
+    // the old ones.
+    Failed { version: String, error: io::Error },
+}
+
+// Replaces the databases behind a `DatabaseHandle` when asked to or when one of
+// the watched paths changes. New databases are loaded and checked completely
+// before they are swapped in, and scans that already took a snapshot finish
+// with it.
+pub struct Reloader<F> {
+    handle: DatabaseHandle,
+    load: F,
+    paths: Vec<PathBuf>,
+    requested: Arc<AtomicBool>,
+    // The watched paths as of the last load.
+    loaded: Vec<Stamp>,
+    // A change seen on the previous poll. Files are only reloaded once they
+    // stop changing, so a database is never read halfway through a write.
+    pending: Option<Vec<Stamp>>,
+}
+
+impl<F: FnMut() -> io::Result<Databases>> Reloader<F> {
+    pub fn new(handle: DatabaseHandle, paths: Vec<PathBuf>, load: F) -> Reloader<F> {
+        let loaded = paths.iter().map(|path| stamp(path)).collect();
+        Reloader { handle, load, paths, requested: Arc::new(AtomicBool::new(false)), loaded, pending: None }
+    }
+
+    // Reloads on SIGHUP. Does nothing on platforms without it.
+    pub fn listen_for_hangup(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&self.requested))?;
+        Ok(())
+    }
+
+    // Makes the next `poll` reload, as SIGHUP does.
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::Release);
+    }
+
+    // Reloads if asked to since the last call, or if a watched path changed and
+    // has stayed the same since the previous call. Meant to be called every
+    // second or so.
+    pub fn poll(&mut self) -> Option<(Trigger, Reload)> {
+        let stamps: Vec<Stamp> = self.paths.iter().map(|path| stamp(path)).collect();
+        if self.requested.swap(false, Ordering::AcqRel) {
+            self.loaded = stamps;
+            self.pending = None;
+            return Some((Trigger::Requested, self.reload()));
+        }
+        if stamps == self.loaded {
+            self.pending = None;
+            return None;
+        }
+        if self.pending.as_ref() != Some(&stamps) {
+            self.pending = Some(stamps);
+            return None;
+        }
+        self.loaded = stamps;
+        self.pending = None;
+        Some((Trigger::Changed, self.reload()))
+    }
+
+    pub fn reload(&mut self) -> Reload {
+        let version = self.handle.snapshot().signatures.version().to_string();
+        let databases = match (self.load)() {
+            Ok(databases) => databases,
+            Err(error) => return Reload::Failed { version, error },
+        };
+        let empty = databases.signatures.is_empty()
+            && databases.hashes.as_ref().is_none_or(|hashes| hashes.is_empty())
+            && databases.fuzzy.as_ref().is_none_or(|fuzzy| fuzzy.is_empty());
+        if empty {
+            let error = io::Error::new(io::ErrorKind::InvalidData, "the new databases have no signatures");
+            return Reload::Failed { version, error };
+        }
+        let new_version = databases.signatures.version().to_string();
+        let old = self.handle.swap(databases);
+        Reload::Swapped { old_version: old.signatures.version().to_string(), new_version }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use super::{Reload, Reloader, Trigger};
+    use crate::file_compare::{DatabaseHandle, Databases};
+    use crate::signatures::read_source_signatures;
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("anti_virus-reload-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("signatures.db");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn load(path: &Path) -> io::Result<Databases> {
+        let (signatures, _) = read_source_signatures(path.to_str().unwrap())?;
+        Ok(Databases { signatures: Arc::new(signatures), hashes: None, fuzzy: None })
+    }
+
+    fn version(handle: &DatabaseHandle) -> String {
+        handle.snapshot().signatures.version().to_string()
+    }
+
+    #[test]
+    fn requested_reload_swaps_and_keeps_old_snapshots() {
+        let path = temp_file("requested", "# version: 1\nA=aaaa\n");
+        let handle = DatabaseHandle::new(load(&path).unwrap());
+        let mut reloader = Reloader::new(handle.clone(), vec![path.clone()], || load(&path));
+        let before = handle.snapshot();
+        assert!(reloader.poll().is_none());
+
+        // A requested reload doesn't wait for the file to settle.
+        reloader.request();
+        fs::write(&path, "# version: 2\nA=aaaa\nB=bbbb\n").unwrap();
+        match reloader.poll() {
+            Some((Trigger::Requested, Reload::Swapped { old_version, new_version })) => {
+                assert_eq!((old_version.as_str(), new_version.as_str()), ("1", "2"));
+            }
+            _ => panic!("expected a swap"),
+        }
+        assert_eq!(version(&handle), "2");
+        assert_eq!(handle.snapshot().signatures.len(), 2);
+        // A scan that took its snapshot earlier still sees the old databases.
+        assert_eq!(before.signatures.version(), "1");
+        assert!(reloader.poll().is_none());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn changes_reload_once_they_settle() {
+        let path = temp_file("changed", "# version: 1\nA=aaaa\n");
+        let handle = DatabaseHandle::new(load(&path).unwrap());
+        let mut reloader = Reloader::new(handle.clone(), vec![path.clone()], || load(&path));
+        fs::write(&path, "# version: 2\nA=aaaa\n").unwrap();
+        assert!(reloader.poll().is_none());
+        // Still being written: wait for it to stop changing.
+        fs::write(&path, "# version: 3\nA=aaaa\nB=bb").unwrap();
+        assert!(reloader.poll().is_none());
+        assert_eq!(version(&handle), "1");
+        assert!(matches!(reloader.poll(), Some((Trigger::Changed, Reload::Swapped { .. }))));
+        assert_eq!(version(&handle), "3");
+        assert!(reloader.poll().is_none());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn failed_reloads_keep_the_old_databases() {
+        let path = temp_file("failed", "# version: 1\nA=aaaa\n");
+        let handle = DatabaseHandle::new(load(&path).unwrap());
+        let mut reloader = Reloader::new(handle.clone(), vec![path.clone()], || load(&path));
+
+        fs::write(&path, "# version: 2\n").unwrap();
+        match reloader.reload() {
+            Reload::Failed { version, error } => {
+                assert_eq!(version, "1");
+                assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            }
+            Reload::Swapped { .. } => panic!("an empty database was swapped in"),
+        }
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(reloader.reload(), Reload::Failed { .. }));
+        assert_eq!(version(&handle), "1");
+        assert_eq!(handle.snapshot().signatures.len(), 1);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::Path;
+use chrono::DateTime;
+
+use super::ScanReport;
+use crate::detection::Detection;
+use crate::metadata::SignatureMeta;
+use crate::rec_file_search::ScanError;
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c if (c as u32) < 0x20 && c != '\t' && c != '\n' && c != '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn duration_secs(report: &ScanReport) -> f64 {
+    match (&report.started_at, &report.finished_at) {
+        (Some(start), Some(end)) => match (DateTime::parse_from_rfc3339(start), DateTime::parse_from_rfc3339(end)) {
+            (Ok(start), Ok(end)) => (end - start).num_milliseconds() as f64 / 1000.0,
+            _ => 0.0,
+        },
+        _ => 0.0,
+    }
+}
+
+enum Outcome<'a> {
+    Passed,
+    Detected(&'a [Detection]),
+    Failed(&'a ScanError),
+}
+
+fn write_testcase<W: Write>(writer: &mut W, path: &str, outcome: &Outcome) -> io::Result<()> {
+    let classname = Path::new(path)
+        .parent()
+        .map(|parent| parent.to_string_lossy().into_owned())
+        .filter(|parent| !parent.is_empty())
+        .unwrap_or_else(|| ".".to_string());
+    let open = format!("    <testcase classname=\"{}\" name=\"{}\"", escape(&classname), escape(path));
+    match outcome {
+        Outcome::Passed => writeln!(writer, "{}/>", open),
+        Outcome::Detected(detections) => {
+            writeln!(writer, "{}>", open)?;
+            for detection in detections.iter() {
+                write!(
+                    writer,
+                    "      <failure type=\"{}\" message=\"{} at offset {}\">signature={} offset={} length={} context={}",
+                    escape(&detection.signature),
+                    escape(&detection.signature),
+                    detection.offset,
+                    escape(&detection.signature),
+                    detection.offset,
+                    detection.length,
+                    detection.context
+                )?;
+                for (field, value) in detection.metadata.iter().flat_map(SignatureMeta::fields) {
+                    write!(writer, " {}={}", field, escape(&value))?;
+                }
+                writeln!(writer, "</failure>")?;
+            }
+            writeln!(writer, "    </testcase>")
+        }
+        Outcome::Failed(error) => {
+            writeln!(writer, "{}>", open)?;
+            writeln!(writer, "      <error type=\"ScanError\" message=\"{}\"/>", escape(&error.message))?;
+            writeln!(writer, "    </testcase>")
+        }
+    }
+}
+
+// One test case per scanned file: clean files pass, every detection is a
+// <failure> and files that could not be scanned carry an <error>. The failure
+// counts are the number of <failure> elements written. Reports read back from
+// JSON don't list clean files, so only their failures and errors appear.
+pub(super) fn write_report<W: Write>(report: &ScanReport, writer: &mut W) -> io::Result<()> {
+    let mut outcomes: BTreeMap<&str, Outcome> = BTreeMap::new();
+    for file in &report.files {
+        outcomes.insert(file, Outcome::Passed);
+    }
+    for result in &report.results {
+        outcomes.insert(&result.path, Outcome::Detected(&result.detections));
+    }
+    for error in &report.errors {
+        outcomes.insert(&error.path, Outcome::Failed(error));
+    }
+    let tests = outcomes.len();
+    let failures: usize = outcomes
+        .values()
+        .map(|outcome| match outcome {
+            Outcome::Detected(detections) => detections.len(),
+            _ => 0,
+        })
+        .sum();
+    let errors = outcomes.values().filter(|outcome| matches!(outcome, Outcome::Failed(_))).count();
+    let time = duration_secs(report);
+    let name = format!("{} {}", env!("CARGO_PKG_NAME"), report.roots.join(" "));
+
+    writeln!(writer, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(
+        writer,
+        "<testsuites name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+        escape(env!("CARGO_PKG_NAME")),
+        tests,
+        failures,
+        errors,
+        time
+    )?;
+    write!(
+        writer,
+        "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"0\" time=\"{:.3}\"",
+        escape(&name),
+        tests,
+        failures,
+        errors,
+        time
+    )?;
+    if let Some(started_at) = &report.started_at {
+        write!(writer, " timestamp=\"{}\"", escape(started_at))?;
+    }
+    writeln!(writer, ">")?;
+    writeln!(writer, "    <properties>")?;
+    if let Some(database) = &report.database {
+        writeln!(writer, "      <property name=\"database\" value=\"{}\"/>", escape(database))?;
+    }
+    writeln!(writer, "      <property name=\"db_version\" value=\"{}\"/>", escape(&report.db_version))?;
+    writeln!(writer, "    </properties>")?;
+    for (path, outcome) in &outcomes {
+        write_testcase(writer, path, outcome)?;
+    }
+    writeln!(writer, "  </testsuite>")?;
+    writeln!(writer, "</testsuites>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::write_report;
+    use crate::detection::Detection;
+    use crate::rec_file_search::ScanError;
+    use crate::report::{FileResult, ScanReport};
+
+    #[test]
+    fn every_detection_is_a_failure() {
+        let data = b"\xde\xad\xbe\xef\xca\xfe";
+        let report = ScanReport {
+            roots: vec!["dir".to_string()],
+            db_version: "1".to_string(),
+            errors: vec![ScanError { path: "dir/locked".to_string(), message: "Permission denied".to_string() }],
+            results: vec![FileResult {
+                path: "dir/infected".to_string(),
+                detections: vec![Detection::new("Test.A", 2, data, 0, 0), Detection::new("Test.<B>", 2, data, 4, 0)],
+            }],
+            files: vec!["dir/clean".to_string(), "dir/infected".to_string(), "dir/locked".to_string()],
+            ..ScanReport::default()
+        };
+        let mut written = Vec::new();
+        write_report(&report, &mut written).unwrap();
+        let xml = String::from_utf8(written).unwrap();
+        assert!(xml.contains("<testsuites name=\"anti_virus\" tests=\"3\" failures=\"2\" errors=\"1\""));
+        assert_eq!(xml.matches("<failure ").count(), 2);
+        assert!(xml.contains("<failure type=\"Test.A\" message=\"Test.A at offset 0\">"));
+        assert!(xml.contains("<failure type=\"Test.&lt;B&gt;\" message=\"Test.&lt;B&gt; at offset 4\">"));
+        assert!(xml.contains("<testcase classname=\"dir\" name=\"dir/clean\"/>"));
+        assert!(xml.contains("<error type=\"ScanError\" message=\"Permission denied\"/>"));
+    }
+}
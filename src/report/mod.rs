@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::SystemTime;
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::detection::{Detection, DetectionKind};
+use crate::metadata::SignatureMeta;
+use crate::policy::Severity;
+use crate::rec_file_search::{RecFileSearch, ScanError};
+
+mod junit;
+mod ndjson;
+mod sarif;
+
+pub use ndjson::{NdjsonRecord, NdjsonWriter};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    Text,
+    Json,
+    Ndjson,
+    Csv,
+    Sarif,
+    Junit,
+}
+
+impl ReportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ReportFormat::Text => "log",
+            ReportFormat::Json => "json",
+            ReportFormat::Ndjson => "ndjson",
+            ReportFormat::Csv => "csv",
+            ReportFormat::Sarif => "sarif",
+            ReportFormat::Junit => "xml",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileResult {
+    pub path: String,
+    pub detections: Vec<Detection>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ScanReport {
+    pub roots: Vec<String>,
+    pub database: Option<String>,
+    pub db_version: String,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    pub files_scanned: usize,
+    pub directories_scanned: usize,
+    pub infected_files: usize,
+    pub detections: usize,
+    pub errors: Vec<ScanError>,
+    pub results: Vec<FileResult>,
+    // Every scanned file, only kept for reports of a live scan.
+    #[serde(skip)]
+    pub files: Vec<String>,
+}
+
+pub fn format_time(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+impl ScanReport {
+    pub fn from_search(search: &RecFileSearch) -> ScanReport {
+        let tester = search.get_tester();
+        let results: Vec<FileResult> = tester
+            .get_risk_files()
+            .iter()
+            .map(|(path, detections)| FileResult { path: path.clone(), detections: detections.clone() })
+            .collect();
+        ScanReport {
+            roots: search.get_roots().iter().map(|root| root.to_string()).collect(),
+            database: tester.get_database_path().map(str::to_owned),
+            db_version: tester.get_signatures().version().to_owned(),
+            started_at: search.get_started_at().map(format_time),
+            finished_at: search.get_finished_at().map(format_time),
+            files_scanned: search.get_files().len(),
+            directories_scanned: search.get_dirs().len(),
+            infected_files: results.len(),
+            detections: results.iter().map(|result| result.detections.len()).sum(),
+            errors: search.get_errors().to_vec(),
+            results,
+            files: search.get_files().iter().map(|file| file.to_string()).collect(),
+        }
+    }
+
+    // Reads a report written in the JSON or NDJSON format.
+    pub fn read(path: &str) -> io::Result<ScanReport> {
+        let reader = BufReader::new(File::open(path)?);
+        let is_ndjson = Path::new(path)
+            .extension()
+            .is_some_and(|ext| ext == "ndjson" || ext == "jsonl");
+        if is_ndjson {
+            ndjson::read_report(reader)
+        } else {
+            serde_json::from_reader(reader).map_err(io::Error::from)
+        }
+    }
+
+    pub fn signature_counts(&self) -> BTreeMap<&str, usize> {
+        let mut counts = BTreeMap::new();
+        for detection in self.results.iter().flat_map(|result| &result.detections) {
+            *counts.entry(detection.signature.as_str()).or_default() += 1;
+        }
+        counts
+    }
+
+    pub fn write<W: Write>(&self, format: ReportFormat, writer: &mut W) -> io::Result<()> {
+        match format {
+            ReportFormat::Text => write_text(
+                writer,
+                self.results.iter().map(|result| (result.path.as_str(), result.detections.as_slice())),
+            ),
+            ReportFormat::Json => {
+                serde_json::to_writer_pretty(&mut *writer, self)?;
+                writeln!(writer)
+            }
+            ReportFormat::Ndjson => ndjson::write_report(self, writer),
+            ReportFormat::Csv => write_csv(self, writer),
+            ReportFormat::Sarif => sarif::write_report(self, writer),
+            ReportFormat::Junit => junit::write_report(self, writer),
+        }
+    }
+}
+
+pub fn write_text<'a, W, I>(writer: &mut W, risk_files: I) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = (&'a str, &'a [Detection])>,
+{
+    for (path, detections) in risk_files {
+        for detection in detections {
+            write!(
+                writer,
+                "Risky file: {} - Signature: {} - Offset: {} - Length: {}",
+                path, detection.signature, detection.offset, detection.length
+            )?;
+            for (field, value) in detection.metadata.iter().flat_map(SignatureMeta::fields) {
+                write!(writer, " - {}{}: {}", field[..1].to_uppercase(), &field[1..], value)?;
+            }
+            writeln!(writer)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    path: &'a str,
+    signature: &'a str,
+    offset: u64,
+    length: usize,
+    context_offset: u64,
+    context: &'a str,
+    kind: DetectionKind,
+    score: Option<u8>,
+    severity: Option<Severity>,
+    category: Option<&'a str>,
+    platform: Option<&'a str>,
+    description: Option<&'a str>,
+    reference: Option<&'a str>,
+    added: Option<&'a str>,
+}
+
+// Columns of `CsvRow`, written up front so a report without detections still
+// has its header.
+const CSV_COLUMNS: [&str; 14] = [
+    "path",
+    "signature",
+    "offset",
+    "length",
+    "context_offset",
+    "context",
+    "kind",
+    "score",
+    "severity",
+    "category",
+    "platform",
+    "description",
+    "reference",
+    "added",
+];
+
+fn write_csv<W: Write>(report: &ScanReport, writer: &mut W) -> io::Result<()> {
+    let mut csv_writer = csv::WriterBuilder::new().has_headers(false).from_writer(writer);
+    csv_writer.write_record(CSV_COLUMNS)?;
+    for result in &report.results {
+        for detection in &result.detections {
+            let meta = detection.metadata.as_ref();
+            csv_writer.serialize(CsvRow {
+                path: &result.path,
+                signature: &detection.signature,
+                offset: detection.offset,
+                length: detection.length,
+                context_offset: detection.context_offset,
+                context: &detection.context,
+                kind: detection.kind,
+                score: detection.score,
+                severity: meta.and_then(|meta| meta.severity),
+                category: meta.and_then(|meta| meta.category.as_deref()),
+                platform: meta.and_then(|meta| meta.platform.as_deref()),
+                description: meta.and_then(|meta| meta.description.as_deref()),
+                reference: meta.and_then(|meta| meta.reference.as_deref()),
+                added: meta.and_then(|meta| meta.added.as_deref()),
+            })?;
+        }
+    }
+    csv_writer.flush()
+}
+
+// Counts per signature from a text risk log, for logs written before the
+// structured formats existed.
+pub fn text_signature_counts<R: BufRead>(reader: R) -> io::Result<BTreeMap<String, usize>> {
+    let mut counts = BTreeMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        if let Some((_, rest)) = line.split_once(" - Signature: ") {
+            let name = rest.split(" - ").next().unwrap_or(rest);
+            *counts.entry(name.to_string()).or_default() += 1;
+        }
+    }
+    Ok(counts)
+}
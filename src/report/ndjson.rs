@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+
+use super::{format_time, FileResult, ScanReport};
+use crate::detection::Detection;
+use crate::rec_file_search::{RecFileSearch, ScanError, ScanSink};
+
+// One line of an NDJSON report: a `scan_started` header, any number of
+// `detection` and `error` records in the order they were found, and a final
+// `scan_finished` summary.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NdjsonRecord {
+    ScanStarted {
+        roots: Vec<String>,
+        database: Option<String>,
+        db_version: String,
+        started_at: Option<String>,
+    },
+    Detection {
+        path: String,
+        #[serde(flatten)]
+        detection: Detection,
+    },
+    Error(ScanError),
+    ScanFinished {
+        finished_at: Option<String>,
+        files_scanned: usize,
+        directories_scanned: usize,
+        infected_files: usize,
+        detections: usize,
+        errors: usize,
+    },
+}
+
+fn write_record<W: Write>(writer: &mut W, record: &NdjsonRecord) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, record)?;
+    writeln!(writer)
+}
+
+fn finished_record(report: &ScanReport) -> NdjsonRecord {
+    NdjsonRecord::ScanFinished {
+        finished_at: report.finished_at.clone(),
+        files_scanned: report.files_scanned,
+        directories_scanned: report.directories_scanned,
+        infected_files: report.infected_files,
+        detections: report.detections,
+        errors: report.errors.len(),
+    }
+}
+
+// Streams records while the scan runs. Each line is flushed as it is written so
+// consumers tailing the output see detections immediately.
+pub struct NdjsonWriter<W: Write + Send> {
+    writer: W,
+}
+
+impl<W: Write + Send> NdjsonWriter<W> {
+    pub fn new(writer: W) -> NdjsonWriter<W> {
+        NdjsonWriter { writer }
+    }
+
+    pub fn begin(&mut self, search: &RecFileSearch) -> io::Result<()> {
+        let tester = search.get_tester();
+        let record = NdjsonRecord::ScanStarted {
+            roots: search.get_roots().iter().map(|root| root.to_string()).collect(),
+            database: tester.get_database_path().map(str::to_owned),
+            db_version: tester.get_signatures().version().to_owned(),
+            started_at: Some(format_time(SystemTime::now())),
+        };
+        self.emit(&record)
+    }
+
+    pub fn finish(&mut self, report: &ScanReport) -> io::Result<()> {
+        self.emit(&finished_record(report))
+    }
+
+    fn emit(&mut self, record: &NdjsonRecord) -> io::Result<()> {
+        write_record(&mut self.writer, record)?;
+        self.writer.flush()
+    }
+}
+
+impl<W: Write + Send> ScanSink for NdjsonWriter<W> {
+    fn file_scanned(&mut self, path: &str, detections: &[Detection]) -> io::Result<()> {
+        for detection in detections {
+            self.emit(&NdjsonRecord::Detection { path: path.to_owned(), detection: detection.clone() })?;
+        }
+        Ok(())
+    }
+
+    fn scan_error(&mut self, error: &ScanError) -> io::Result<()> {
+        self.emit(&NdjsonRecord::Error(error.clone()))
+    }
+}
+
+pub(super) fn write_report<W: Write>(report: &ScanReport, writer: &mut W) -> io::Result<()> {
+    write_record(writer, &NdjsonRecord::ScanStarted {
+        roots: report.roots.clone(),
+        database: report.database.clone(),
+        db_version: report.db_version.clone(),
+        started_at: report.started_at.clone(),
+    })?;
+    for result in &report.results {
+        for detection in &result.detections {
+            write_record(writer, &NdjsonRecord::Detection { path: result.path.clone(), detection: detection.clone() })?;
+        }
+    }
+    for error in &report.errors {
+        write_record(writer, &NdjsonRecord::Error(error.clone()))?;
+    }
+    write_record(writer, &finished_record(report))
+}
+
+pub(super) fn read_report<R: BufRead>(reader: R) -> io::Result<ScanReport> {
+    let mut report = ScanReport::default();
+    let mut results: BTreeMap<String, Vec<Detection>> = BTreeMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line)? {
+            NdjsonRecord::ScanStarted { roots, database, db_version, started_at } => {
+                report.roots = roots;
+                report.database = database;
+                report.db_version = db_version;
+                report.started_at = started_at;
+            }
+            NdjsonRecord::Detection { path, detection } => results.entry(path).or_default().push(detection),
+            NdjsonRecord::Error(error) => report.errors.push(error),
+            NdjsonRecord::ScanFinished { finished_at, files_scanned, directories_scanned, .. } => {
+                report.finished_at = finished_at;
+                report.files_scanned = files_scanned;
+                report.directories_scanned = directories_scanned;
+            }
+        }
+    }
+    report.results = results
+        .into_iter()
+        .map(|(path, mut detections)| {
+            detections.sort();
+            FileResult { path, detections }
+        })
+        .collect();
+    report.infected_files = report.results.len();
+    report.detections = report.results.iter().map(|result| result.detections.len()).sum();
+    Ok(report)
+}
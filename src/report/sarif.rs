@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use serde_json::{json, Value};
+
+use super::ScanReport;
+use crate::detection::Detection;
+use crate::policy::Severity;
+
+const SARIF_SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+
+fn percent_encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b'@' | b'+' | b',' | b'=' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// Percent-encodes the characters that are not allowed verbatim in a URI path.
+// Absolute paths become `file:` URIs and relative ones stay relative. Windows
+// paths, like `C:\x` or `\\server\share` in a report written there, get `/`
+// separators: `file:///C:/x` and `file://server/share`.
+fn path_to_uri(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let drive = bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':';
+    let windows = cfg!(windows) || drive || path.starts_with("\\\\");
+    let path = if windows { path.replace('\\', "/") } else { path.to_string() };
+    if drive {
+        return format!("file:///{}:{}", &path[..1], percent_encode(&path[2..]));
+    }
+    if let Some(unc) = path.strip_prefix("//").filter(|_| windows) {
+        return format!("file://{}", percent_encode(unc));
+    }
+    if path.starts_with('/') {
+        return format!("file://{}", percent_encode(&path));
+    }
+    percent_encode(&path)
+}
+
+fn level(severity: Severity) -> &'static str {
+    match severity {
+        Severity::Critical | Severity::High => "error",
+        Severity::Medium => "warning",
+        Severity::Low | Severity::Info => "note",
+    }
+}
+
+// Described by the metadata of its signature, taken from `detection`.
+fn rule(detection: &Detection) -> Value {
+    let name = &detection.signature;
+    let category = detection.category();
+    let meta = detection.metadata.clone().unwrap_or_default();
+    let description = meta
+        .description
+        .clone()
+        .unwrap_or_else(|| format!("Content matches the {} signature {}", category, name));
+    let mut rule = json!({
+        "id": name,
+        "name": name,
+        "shortDescription": { "text": description },
+        "defaultConfiguration": { "level": level(detection.severity()) },
+        "properties": {
+            "category": category,
+            "severity": detection.severity(),
+            "tags": ["security", category],
+        },
+    });
+    if let Some(reference) = &meta.reference {
+        rule["helpUri"] = json!(reference);
+    }
+    if let Some(platform) = &meta.platform {
+        rule["properties"]["platform"] = json!(platform);
+    }
+    if let Some(added) = &meta.added {
+        rule["properties"]["added"] = json!(added);
+    }
+    rule
+}
+
+// SARIF 2.1.0 with one rule per signature that was detected and one result per
+// detection, located by byte offset and length in the scanned file.
+pub(super) fn write_report<W: Write>(report: &ScanReport, writer: &mut W) -> io::Result<()> {
+    let mut first_detections: BTreeMap<&str, &Detection> = BTreeMap::new();
+    for detection in report.results.iter().flat_map(|result| &result.detections) {
+        first_detections.entry(&detection.signature).or_insert(detection);
+    }
+    let rule_index: BTreeMap<&str, usize> =
+        first_detections.keys().enumerate().map(|(index, &name)| (name, index)).collect();
+    let rules: Vec<Value> = first_detections.values().map(|detection| rule(detection)).collect();
+
+    let mut results = Vec::new();
+    for result in &report.results {
+        for detection in &result.detections {
+            results.push(json!({
+                "ruleId": detection.signature,
+                "ruleIndex": rule_index[detection.signature.as_str()],
+                "level": level(detection.severity()),
+                "message": {
+                    "text": format!("{} matched at byte offset {}", detection.signature, detection.offset),
+                },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": path_to_uri(&result.path) },
+                        "region": {
+                            "byteOffset": detection.offset,
+                            "byteLength": detection.length,
+                        },
+                    },
+                }],
+            }));
+        }
+    }
+
+    let notifications: Vec<Value> = report
+        .errors
+        .iter()
+        .map(|error| {
+            json!({
+                "level": "error",
+                "message": { "text": error.message },
+                "locations": [{
+                    "physicalLocation": { "artifactLocation": { "uri": path_to_uri(&error.path) } },
+                }],
+            })
+        })
+        .collect();
+    let mut invocation = json!({
+        "executionSuccessful": report.errors.is_empty(),
+        "toolExecutionNotifications": notifications,
+    });
+    if let Some(started_at) = &report.started_at {
+        invocation["startTimeUtc"] = json!(started_at);
+    }
+    if let Some(finished_at) = &report.finished_at {
+        invocation["endTimeUtc"] = json!(finished_at);
+    }
+
+    let sarif = json!({
+        "$schema": SARIF_SCHEMA,
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "rules": rules,
+                },
+            },
+            "invocations": [invocation],
+            "results": results,
+            "properties": {
+                "roots": report.roots,
+                "database": report.database,
+                "dbVersion": report.db_version,
+            },
+        }],
+    });
+    serde_json::to_writer_pretty(&mut *writer, &sarif)?;
+    writeln!(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::path_to_uri;
+
+    #[test]
+    fn windows_paths_become_file_uris() {
+        assert_eq!(path_to_uri(r"C:\Users\a b\x.exe"), "file:///C:/Users/a%20b/x.exe");
+        assert_eq!(path_to_uri(r"\\server\share\x"), "file://server/share/x");
+    }
+
+    #[test]
+    fn unix_paths() {
+        assert_eq!(path_to_uri("/tmp/a#b"), "file:///tmp/a%23b");
+        assert_eq!(path_to_uri("dir/x:y"), "dir/x%3Ay");
+        if cfg!(unix) {
+            assert_eq!(path_to_uri(r"/tmp/a\b"), "file:///tmp/a%5Cb");
+        }
+    }
+}
@@ -0,0 +1,761 @@
+use std::fmt;
+use std::fs;
+use std::hash::Hasher;
+use std::io::{self, BufRead};
+
+use fnv::{FnvHashMap, FnvHashSet, FnvHasher};
+use serde::{Deserialize, Serialize};
+
+use crate::compiled::{is_compiled, read_compiled, read_fresh_compiled, CompiledSignatures, SectionReader, SectionWriter, Table};
+use crate::logical::{Condition, Expression, LogicalMatches, LogicalSignature};
+use crate::matcher::Automaton;
+use crate::metadata::{parse_database, DbFormat, SignatureMeta};
+use crate::pattern::Pattern;
+use crate::signing::Verification;
+
+// Where in a file a signature may start, written before the pattern as
+// `name=offset:hex`. Plain `name=hex` is `*` and `name=^hex` is `0`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anchor {
+    // `*`
+    Anywhere,
+    // `n` or `n,shift`: at most `shift` bytes after offset `n`.
+    Absolute { offset: u64, shift: u64 },
+    // `EOF-n` or `EOF-n,shift`: at most `shift` bytes after `n` bytes before the end.
+    FromEnd { distance: u64, shift: u64 },
+}
+
+impl Anchor {
+    pub fn parse(text: &str) -> Result<Anchor, String> {
+        let text = text.trim();
+        if text == "*" {
+            return Ok(Anchor::Anywhere);
+        }
+        let number = |s: &str| s.trim().parse::<u64>().map_err(|_| format!("invalid offset {}", text));
+        let (base, shift) = match text.split_once(',') {
+            Some((base, shift)) => (base, number(shift)?),
+            None => (text, 0),
+        };
+        match base.trim().strip_prefix("EOF-") {
+            Some(distance) => Ok(Anchor::FromEnd { distance: number(distance)?, shift }),
+            None => Ok(Anchor::Absolute { offset: number(base)?, shift }),
+        }
+    }
+
+    // Whether a match may start at `offset` in a file of `size` bytes.
+    pub fn allows(&self, offset: u64, size: u64) -> bool {
+        match *self {
+            Anchor::Anywhere => true,
+            Anchor::Absolute { offset: from, shift } => offset >= from && offset - from <= shift,
+            Anchor::FromEnd { distance, shift } => {
+                size >= distance && offset >= size - distance && offset - (size - distance) <= shift
+            }
+        }
+    }
+}
+
+impl fmt::Display for Anchor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Anchor::Anywhere => write!(f, "*"),
+            Anchor::Absolute { offset, shift: 0 } => write!(f, "{}", offset),
+            Anchor::Absolute { offset, shift } => write!(f, "{},{}", offset, shift),
+            Anchor::FromEnd { distance, shift: 0 } => write!(f, "EOF-{}", distance),
+            Anchor::FromEnd { distance, shift } => write!(f, "EOF-{},{}", distance, shift),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Signature {
+    pub name: String,
+    pub pattern: Pattern,
+    pub anchor: Anchor,
+}
+
+impl Signature {
+    // What follows `name=` in the database: `offset:hex`, or plain hex.
+    pub fn definition(&self) -> String {
+        match self.anchor {
+            Anchor::Anywhere => self.pattern.to_string(),
+            anchor => format!("{}:{}", anchor, self.pattern),
+        }
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.definition())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DbError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+// Immutable, validated form of a signature database. All signatures and the
+// sub-signatures of logical ones are searched with one Aho-Corasick automaton
+// over the atoms of their patterns; hits are then checked against the
+// signature's offset and, unless it is plain hex, verified against the full
+// pattern.
+pub struct SignatureSet {
+    version: String,
+    metadata: FnvHashMap<String, SignatureMeta>,
+    // Definitions that replaced an earlier one of the same name in the file the
+    // set was loaded from.
+    redefined: Vec<DbError>,
+    signatures: PlainSignatures,
+    logical: Vec<LogicalSignature>,
+    // `(logical, index)` of each sub-signature, numbered after the plain signatures.
+    parts: Vec<(usize, usize)>,
+    max_len: usize,
+    header_len: usize,
+    trailer_len: usize,
+    // Bytes from the start of a file that YARA conditions read directly.
+    read_len: usize,
+    automaton: Automaton,
+    // Signature or sub-signature of each atom variant in `automaton`.
+    atom_ids: Table<u32>,
+    // How far before its atom each signature may start, as `left_min, left_max`
+    // pairs by id.
+    atom_offsets: Table<u64>,
+    // Non-zero for ids whose atom hits need no verification: plain hex
+    // signatures matched anywhere.
+    unverified: Table<u8>,
+    has_floating: bool,
+}
+
+// Plain signatures, parsed from a source database or decoded from a compiled
+// one the first time a scan needs them.
+enum PlainSignatures {
+    Parsed(Vec<Signature>),
+    Compiled(CompiledSignatures),
+}
+
+impl PlainSignatures {
+    fn len(&self) -> usize {
+        match self {
+            PlainSignatures::Parsed(signatures) => signatures.len(),
+            PlainSignatures::Compiled(signatures) => signatures.len(),
+        }
+    }
+
+    fn get(&self, id: usize) -> Option<&Signature> {
+        match self {
+            PlainSignatures::Parsed(signatures) => signatures.get(id),
+            PlainSignatures::Compiled(signatures) => signatures.get(id),
+        }
+    }
+}
+
+// What a compiled database keeps of a set besides its tables.
+#[derive(Serialize, Deserialize)]
+struct SetInfo {
+    version: String,
+    max_len: usize,
+    header_len: usize,
+    trailer_len: usize,
+    read_len: usize,
+    has_floating: bool,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// `(logical, index)` of each sub-signature.
+fn parts_of(logical: &[LogicalSignature]) -> Vec<(usize, usize)> {
+    logical
+        .iter()
+        .enumerate()
+        .flat_map(|(id, signature)| (0..signature.subsignatures.len()).map(move |index| (id, index)))
+        .collect()
+}
+
+impl SignatureSet {
+    pub fn new(signatures: Vec<Signature>, logical: Vec<LogicalSignature>) -> SignatureSet {
+        let parts = parts_of(&logical);
+        let targets = signatures.iter().chain(logical.iter().flat_map(|signature| &signature.subsignatures));
+        let mut patterns = Vec::new();
+        let mut atom_ids = Vec::new();
+        let mut atom_offsets = Vec::new();
+        let mut unverified = Vec::new();
+        let mut max_len = 0;
+        let mut header_len = 0;
+        let mut trailer_len = 0;
+        let mut has_floating = false;
+        for (id, signature) in targets.enumerate() {
+            let atom = signature.pattern.atom();
+            atom_offsets.extend([atom.left_min as u64, atom.left_max as u64]);
+            for variant in atom.variants {
+                patterns.push(variant);
+                atom_ids.push(id as u32);
+            }
+            let plain = id < signatures.len();
+            unverified.push(u8::from(plain && signature.pattern.is_literal() && signature.anchor == Anchor::Anywhere));
+            max_len = max_len.max(signature.pattern.max_len());
+            match signature.anchor {
+                Anchor::Anywhere => has_floating = true,
+                Anchor::Absolute { offset, shift } => {
+                    let end = offset.saturating_add(shift).saturating_add(signature.pattern.max_len() as u64);
+                    header_len = header_len.max(usize::try_from(end).unwrap_or(usize::MAX));
+                }
+                Anchor::FromEnd { distance, .. } => {
+                    trailer_len = trailer_len.max(usize::try_from(distance).unwrap_or(usize::MAX));
+                }
+            }
+        }
+        let read_len = logical.iter().map(|signature| signature.condition.read_len()).max().unwrap_or(0);
+        let read_len = usize::try_from(read_len).unwrap_or(usize::MAX);
+        SignatureSet {
+            version: String::new(),
+            metadata: FnvHashMap::default(),
+            redefined: Vec::new(),
+            signatures: PlainSignatures::Parsed(signatures),
+            logical,
+            parts,
+            max_len,
+            header_len: header_len.max(read_len),
+            trailer_len,
+            read_len,
+            automaton: Automaton::new(&patterns),
+            atom_ids: atom_ids.into(),
+            atom_offsets: atom_offsets.into(),
+            unverified: unverified.into(),
+            has_floating,
+        }
+    }
+
+    // Writes everything a scan needs, matcher tables included, for
+    // `read_sections` to map back without parsing or building anything.
+    pub(crate) fn write_sections(&self, writer: &mut SectionWriter) -> io::Result<()> {
+        let info = SetInfo {
+            version: self.version.clone(),
+            max_len: self.max_len,
+            header_len: self.header_len,
+            trailer_len: self.trailer_len,
+            read_len: self.read_len,
+            has_floating: self.has_floating,
+        };
+        writer.json(&info)?;
+        writer.json(&self.metadata)?;
+        let mut logical = String::new();
+        for signature in &self.logical {
+            let Condition::Expression(expression) = &signature.condition else {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "YARA rules can't be compiled"));
+            };
+            let parts: Vec<String> = signature.subsignatures.iter().map(Signature::definition).collect();
+            logical.push_str(&format!("{}={};{}\n", signature.name, expression, parts.join(";")));
+        }
+        writer.bytes(logical.into_bytes());
+        CompiledSignatures::write(self.signatures(), writer);
+        writer.table(&self.atom_ids);
+        writer.table(&self.atom_offsets);
+        writer.table(&self.unverified);
+        self.automaton.write_tables(writer);
+        Ok(())
+    }
+
+    // Logical signatures are parsed again, plain ones only when a scan first
+    // needs them, and the tables are used as mapped.
+    pub(crate) fn read_sections(reader: &mut SectionReader) -> io::Result<SignatureSet> {
+        let info: SetInfo = reader.json()?;
+        let metadata = reader.json()?;
+        let text = String::from_utf8(reader.bytes()?).map_err(|_| invalid_data("invalid logical signature record"))?;
+        let mut logical = Vec::new();
+        for line in text.lines() {
+            match parse_line(line) {
+                Ok(Entry::Logical(signature)) => logical.push(signature),
+                _ => return Err(invalid_data("invalid logical signature record")),
+            }
+        }
+        let signatures = CompiledSignatures::read(reader)?;
+        let atom_ids: Table<u32> = reader.table()?;
+        let atom_offsets: Table<u64> = reader.table()?;
+        let unverified: Table<u8> = reader.table()?;
+        let automaton = Automaton::read_tables(reader)?;
+        let parts = parts_of(&logical);
+        let targets = signatures.len() + parts.len();
+        let consistent = atom_ids.len() == automaton.pattern_count()
+            && atom_ids.iter().all(|&id| (id as usize) < targets)
+            && atom_offsets.len() == 2 * targets
+            && unverified.len() == targets;
+        if !consistent {
+            return Err(invalid_data("inconsistent signature tables"));
+        }
+        Ok(SignatureSet {
+            version: info.version,
+            metadata,
+            redefined: Vec::new(),
+            signatures: PlainSignatures::Compiled(signatures),
+            logical,
+            parts,
+            max_len: info.max_len,
+            header_len: info.header_len,
+            trailer_len: info.trailer_len,
+            read_len: info.read_len,
+            automaton,
+            atom_ids,
+            atom_offsets,
+            unverified,
+            has_floating: info.has_floating,
+        })
+    }
+
+    pub fn with_version(mut self, version: String) -> SignatureSet {
+        self.version = version;
+        self
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn with_metadata(mut self, metadata: FnvHashMap<String, SignatureMeta>) -> SignatureSet {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn with_redefined(mut self, redefined: Vec<DbError>) -> SignatureSet {
+        self.redefined = redefined;
+        self
+    }
+
+    pub fn redefined(&self) -> &[DbError] {
+        &self.redefined
+    }
+
+    // Details given for a signature in a structured database, by name.
+    pub fn metadata(&self, name: &str) -> Option<&SignatureMeta> {
+        self.metadata.get(name)
+    }
+
+    // Plain and logical signatures together.
+    pub fn len(&self) -> usize {
+        self.signatures.len() + self.logical.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn signatures(&self) -> impl Iterator<Item = &Signature> {
+        (0..self.signatures.len()).filter_map(|id| self.signatures.get(id))
+    }
+
+    pub fn logical(&self) -> &[LogicalSignature] {
+        &self.logical
+    }
+
+    // Collects the sub-signature matches of one file of `size` bytes.
+    pub fn logical_matches(&self, size: u64) -> LogicalMatches<'_> {
+        LogicalMatches::new(&self.logical, size, self.read_len)
+    }
+
+    // A copy with more signatures added, e.g. compiled YARA rules or imported
+    // ClamAV databases.
+    pub fn extended(&self, signatures: Vec<Signature>, logical: Vec<LogicalSignature>) -> SignatureSet {
+        let signatures = self.signatures().cloned().chain(signatures).collect();
+        let logical = self.logical.iter().cloned().chain(logical).collect();
+        SignatureSet::new(signatures, logical)
+            .with_version(self.version.clone())
+            .with_metadata(self.metadata.clone())
+    }
+
+    // Its signatures and their metadata, for merging into another set.
+    pub(crate) fn into_entries(self) -> (Vec<Entry>, FnvHashMap<String, SignatureMeta>) {
+        let mut entries: Vec<Entry> = self.signatures().cloned().map(Entry::Plain).collect();
+        entries.extend(self.logical.into_iter().map(Entry::Logical));
+        (entries, self.metadata)
+    }
+
+    fn target(&self, id: usize) -> Option<&Signature> {
+        match id.checked_sub(self.signatures.len()) {
+            None => self.signatures.get(id),
+            Some(part) => {
+                let (logical, index) = self.parts[part];
+                Some(&self.logical[logical].subsignatures[index])
+            }
+        }
+    }
+
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    // Bytes from the start of a file that cover every offset-anchored match.
+    pub fn header_len(&self) -> usize {
+        self.header_len
+    }
+
+    // Bytes before the end of a file that cover every EOF-anchored match.
+    pub fn trailer_len(&self) -> usize {
+        self.trailer_len
+    }
+
+    // False when every signature is tied to an offset, so files only need their
+    // header and trailer read.
+    pub fn has_floating(&self) -> bool {
+        self.has_floating
+    }
+
+    // Reports `(signature, start, length)` for every match in `data`, which holds
+    // the bytes at file offset `base` of a file `file_size` bytes long.
+    // Sub-signature matches go to `logical` instead.
+    pub fn scan<'a, F: FnMut(&'a Signature, usize, usize)>(
+        &'a self,
+        data: &[u8],
+        base: u64,
+        file_size: u64,
+        logical: &mut LogicalMatches<'a>,
+        mut on_match: F,
+    ) {
+        logical.capture(data, base);
+        let window = ScanWindow { data, base, file_size };
+        // Starts already verified, since several atom hits can point at the same one.
+        let mut tried: FnvHashSet<(usize, usize)> = FnvHashSet::default();
+        self.automaton.find_overlapping(data, |pattern, atom_start| {
+            let id = self.atom_ids[pattern] as usize;
+            if id < self.signatures.len() {
+                let Some(signature) = self.signatures.get(id) else {
+                    return;
+                };
+                if self.unverified[id] != 0 {
+                    on_match(signature, atom_start, signature.pattern.max_len());
+                    return;
+                }
+                self.verify(id, atom_start, &window, &mut tried, &mut |start, length| on_match(signature, start, length));
+            } else {
+                let (part, index) = self.parts[id - self.signatures.len()];
+                self.verify(id, atom_start, &window, &mut tried, &mut |start, length| {
+                    logical.record(part, index, data, start, length, base);
+                });
+            }
+        });
+    }
+
+    // Kept out of line so the automaton loop stays small for plain hex databases.
+    #[inline(never)]
+    fn verify(
+        &self,
+        id: usize,
+        atom_start: usize,
+        window: &ScanWindow,
+        tried: &mut FnvHashSet<(usize, usize)>,
+        on_match: &mut dyn FnMut(usize, usize),
+    ) {
+        let Some(signature) = self.target(id) else {
+            return;
+        };
+        let (left_min, left_max) = (self.atom_offsets[2 * id] as usize, self.atom_offsets[2 * id + 1] as usize);
+        if atom_start < left_min {
+            return;
+        }
+        for start in atom_start.saturating_sub(left_max)..=atom_start - left_min {
+            if !signature.anchor.allows(window.base + start as u64, window.file_size) || !tried.insert((id, start)) {
+                continue;
+            }
+            if let Some(length) = signature.pattern.match_at(window.data, start) {
+                on_match(start, length);
+            }
+        }
+    }
+}
+
+struct ScanWindow<'d> {
+    data: &'d [u8],
+    base: u64,
+    file_size: u64,
+}
+
+// Family prefix of a name like `TestTrojan.6`, minus the `Test` marker of test
+// signatures and lowercased: `trojan`.
+pub fn category_from_name(name: &str) -> String {
+    let family = name.split('.').next().unwrap_or(name);
+    let family = family.strip_prefix("Test").filter(|rest| !rest.is_empty()).unwrap_or(family);
+    family.to_lowercase()
+}
+
+pub(crate) enum Entry {
+    Plain(Signature),
+    Logical(LogicalSignature),
+}
+
+impl Entry {
+    pub(crate) fn name(&self) -> &str {
+        match self {
+            Entry::Plain(signature) => &signature.name,
+            Entry::Logical(signature) => &signature.name,
+        }
+    }
+}
+
+// `offset:hex`, `^hex` or plain `hex`.
+fn parse_body(value: &str) -> Result<(Anchor, Pattern), String> {
+    let value = value.trim();
+    let (anchor, hex_str) = match (value.strip_prefix('^'), value.split_once(':')) {
+        (Some(rest), _) => (Anchor::Absolute { offset: 0, shift: 0 }, rest),
+        (None, Some((offset, rest))) => (Anchor::parse(offset)?, rest),
+        (None, None) => (Anchor::Anywhere, value),
+    };
+    if hex_str.is_empty() {
+        return Err("empty signature".to_string());
+    }
+    let pattern = Pattern::parse(hex_str).map_err(|e| format!("invalid pattern: {}", e))?;
+    Ok((anchor, pattern))
+}
+
+// Signatures in definition order, where a repeated name replaces the earlier one.
+#[derive(Default)]
+pub(crate) struct Entries {
+    entries: Vec<Entry>,
+    by_name: FnvHashMap<String, usize>,
+}
+
+impl Entries {
+    // Returns whether an entry of the same name was replaced.
+    pub(crate) fn insert(&mut self, entry: Entry) -> bool {
+        match self.by_name.get(entry.name()) {
+            Some(&existing) => {
+                self.entries[existing] = entry;
+                true
+            }
+            None => {
+                self.by_name.insert(entry.name().to_string(), self.entries.len());
+                self.entries.push(entry);
+                false
+            }
+        }
+    }
+
+    pub(crate) fn into_parts(self) -> (Vec<Signature>, Vec<LogicalSignature>) {
+        let mut signatures = Vec::new();
+        let mut logical = Vec::new();
+        for entry in self.entries {
+            match entry {
+                Entry::Plain(signature) => signatures.push(signature),
+                Entry::Logical(signature) => logical.push(signature),
+            }
+        }
+        (signatures, logical)
+    }
+}
+
+pub(crate) fn parse_line(line: &str) -> Result<Entry, String> {
+    let (name, value) = line.split_once('=').ok_or("expected name=hex")?;
+    parse_definition(name, value)
+}
+
+// The part of a line after `name=`: a pattern, or a logical expression and its
+// sub-signatures.
+pub(crate) fn parse_definition(name: &str, value: &str) -> Result<Entry, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("missing signature name".to_string());
+    }
+    let Some((expression, parts)) = value.split_once(';') else {
+        let (anchor, pattern) = parse_body(value).map_err(|e| format!("{}: {}", name, e))?;
+        return Ok(Entry::Plain(Signature { name: name.to_string(), pattern, anchor }));
+    };
+    let expression = Expression::parse(expression).map_err(|e| format!("{}: invalid expression: {}", name, e))?;
+    let mut subsignatures = Vec::new();
+    for (index, part) in parts.split(';').enumerate() {
+        let (anchor, pattern) = parse_body(part).map_err(|e| format!("{} sub-signature {}: {}", name, index, e))?;
+        subsignatures.push(Signature { name: name.to_string(), pattern, anchor });
+    }
+    LogicalSignature::new(name.to_string(), expression, subsignatures)
+        .map(Entry::Logical)
+        .map_err(|e| format!("{}: {}", name, e))
+}
+
+// Parses `name=hex` or `name=offset:hex` lines, where the hex may use the
+// wildcard syntax of `Pattern`, and logical `name=expression;sub0;sub1;...`
+// lines whose sub-signatures take the same form. Blank lines and `#` comments
+// are ignored, invalid lines are skipped and returned with their line numbers.
+// A repeated name replaces the earlier entry and is noted in `redefined`. The
+// version comes from a
+// `# version: <v>` comment, or is a fingerprint of the file contents when there
+// is none.
+pub fn parse_signatures<R: BufRead>(reader: R) -> io::Result<(SignatureSet, Vec<DbError>)> {
+    let mut entries = Entries::default();
+    let mut errors = Vec::new();
+    let mut redefined = Vec::new();
+    let mut version = None;
+    let mut fingerprint = FnvHasher::default();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        fingerprint.write(line.as_bytes());
+        fingerprint.write_u8(b'\n');
+        let trimmed = line.trim();
+        if let Some(comment) = trimmed.strip_prefix('#') {
+            if let Some(value) = comment.trim().strip_prefix("version:") {
+                version.get_or_insert_with(|| value.trim().to_string());
+            }
+            continue;
+        }
+        if trimmed.is_empty() {
+            continue;
+        }
+        match parse_line(trimmed) {
+            Ok(entry) => {
+                let name = entry.name().to_string();
+                if entries.insert(entry) {
+                    redefined.push(DbError { line: index + 1, message: redefinition(&name) });
+                }
+            }
+            Err(message) => errors.push(DbError { line: index + 1, message }),
+        }
+    }
+    let version = version.unwrap_or_else(|| format!("fnv-{:016x}", fingerprint.finish()));
+    let (signatures, logical) = entries.into_parts();
+    Ok((SignatureSet::new(signatures, logical).with_version(version).with_redefined(redefined), errors))
+}
+
+pub(crate) fn redefinition(name: &str) -> String {
+    format!("{} is defined again and replaces the earlier definition", name)
+}
+
+// Reads a database: a compiled one, the compiled form next to a source
+// database if it is up to date, or else the source itself. Whichever file is
+// read has to pass `verification`.
+pub fn read_signatures(path: &str, verification: &Verification) -> io::Result<(SignatureSet, Vec<DbError>)> {
+    if is_compiled(path) {
+        return read_compiled(path, verification);
+    }
+    if let Some(loaded) = read_fresh_compiled(path, verification) {
+        return Ok(loaded);
+    }
+    parse_source_signatures(path, &verification.read(path)?)
+}
+
+// Parses a source database in the format its extension names: `.toml` and
+// `.json` are structured databases with metadata, anything else the legacy
+// line format.
+pub fn read_source_signatures(path: &str) -> io::Result<(SignatureSet, Vec<DbError>)> {
+    parse_source_signatures(path, &fs::read(path)?)
+}
+
+pub(crate) fn parse_source_signatures(path: &str, data: &[u8]) -> io::Result<(SignatureSet, Vec<DbError>)> {
+    match DbFormat::from_path(path) {
+        DbFormat::Legacy => parse_signatures(data),
+        format => {
+            let text = std::str::from_utf8(data).map_err(|_| invalid_data("stream did not contain valid UTF-8"))?;
+            parse_database(text, format)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_signatures, Anchor, SignatureSet};
+
+    fn set(database: &str) -> SignatureSet {
+        let (signatures, errors) = parse_signatures(database.as_bytes()).unwrap();
+        assert!(errors.is_empty(), "{:?}", errors.iter().map(ToString::to_string).collect::<Vec<_>>());
+        signatures
+    }
+
+    // `(name, file offset)` of each match in `data`, the bytes at `base` of a
+    // file of `size` bytes.
+    fn scan(set: &SignatureSet, data: &[u8], base: u64, size: u64) -> Vec<(String, u64)> {
+        let mut logical = set.logical_matches(size);
+        let mut found = Vec::new();
+        set.scan(data, base, size, &mut logical, |signature, start, _| {
+            found.push((signature.name.clone(), base + start as u64));
+        });
+        found.sort();
+        found
+    }
+
+    fn found(matches: &[(&str, u64)]) -> Vec<(String, u64)> {
+        matches.iter().map(|&(name, offset)| (name.to_string(), offset)).collect()
+    }
+
+    #[test]
+    fn parses_anchors() {
+        let cases = [
+            ("*", Anchor::Anywhere),
+            ("0", Anchor::Absolute { offset: 0, shift: 0 }),
+            (" 512 ", Anchor::Absolute { offset: 512, shift: 0 }),
+            ("16,8", Anchor::Absolute { offset: 16, shift: 8 }),
+            ("EOF-4", Anchor::FromEnd { distance: 4, shift: 0 }),
+            ("EOF-10,6", Anchor::FromEnd { distance: 10, shift: 6 }),
+        ];
+        for (text, anchor) in cases {
+            assert_eq!(Anchor::parse(text), Ok(anchor), "{}", text);
+            assert_eq!(Anchor::parse(&anchor.to_string()), Ok(anchor));
+        }
+        for text in ["", "-1", "x", "EOF", "EOF+4", "EOF-", "4,", ",4", "4,-1", "1,2,3", "18446744073709551616"] {
+            assert!(Anchor::parse(text).is_err(), "{:?} parsed", text);
+        }
+    }
+
+    #[test]
+    fn anchors_limit_where_matches_start() {
+        let anchor = Anchor::Absolute { offset: 16, shift: 8 };
+        assert!(!anchor.allows(15, 100));
+        assert!(anchor.allows(16, 100) && anchor.allows(24, 100));
+        assert!(!anchor.allows(25, 100));
+        let anchor = Anchor::FromEnd { distance: 10, shift: 6 };
+        assert!(!anchor.allows(89, 100));
+        assert!(anchor.allows(90, 100) && anchor.allows(96, 100));
+        assert!(!anchor.allows(97, 100));
+        // A file shorter than the distance has no such offset.
+        assert!(!anchor.allows(0, 9));
+        assert!(anchor.allows(0, 10));
+    }
+
+    #[test]
+    fn scans_honour_anchors() {
+        let set = set("Test.Start=^aabb\nTest.At=4:aabb\nTest.Range=8,2:aabb\nTest.End=EOF-2:aabb\nTest.Tail=EOF-6,1:aabb\nTest.Any=aabb\n");
+        let mut data = [0u8; 16];
+        for start in [0, 4, 9, 14] {
+            data[start..start + 2].copy_from_slice(&[0xaa, 0xbb]);
+        }
+        let expected = found(&[
+            ("Test.Any", 0),
+            ("Test.Any", 4),
+            ("Test.Any", 9),
+            ("Test.Any", 14),
+            ("Test.At", 4),
+            ("Test.End", 14),
+            ("Test.Range", 9),
+            ("Test.Start", 0),
+        ]);
+        assert_eq!(scan(&set, &data, 0, 16), expected);
+
+        // The same bytes seen through a later window keep their file offsets.
+        let mut padded = vec![0u8; 100];
+        padded.extend_from_slice(&data);
+        let shifted = found(&[("Test.Any", 100), ("Test.Any", 104), ("Test.Any", 109), ("Test.Any", 114), ("Test.End", 114)]);
+        assert_eq!(scan(&set, &padded[100..], 100, 116), shifted);
+
+        // `EOF-6,1` covers offsets 10 and 11 of a 16 byte file.
+        data.fill(0);
+        data[11..13].copy_from_slice(&[0xaa, 0xbb]);
+        assert_eq!(scan(&set, &data, 0, 16), found(&[("Test.Any", 11), ("Test.Tail", 11)]));
+    }
+
+    #[test]
+    fn anchors_past_either_end_never_match() {
+        let anchored = set("Test.Far=1000:aabb\nTest.Before=EOF-100:aabb\nTest.Overhang=EOF-1:aabb\nTest.Zero=EOF-0:aabb\n");
+        assert_eq!(anchored.header_len(), 1002);
+        assert_eq!(anchored.trailer_len(), 100);
+        let data = [0xaa, 0xbb, 0xaa, 0xbb, 0xaa, 0xbb];
+        assert!(scan(&anchored, &data, 0, 6).is_empty());
+        // A pattern can't start one byte before the end and still fit.
+        assert!(scan(&anchored, &data[..5], 0, 5).is_empty());
+        // An offset larger than any file doesn't overflow.
+        let huge = set("Test.Huge=18446744073709551615,18446744073709551615:aabb\n");
+        assert_eq!(huge.header_len(), usize::MAX);
+        assert!(scan(&huge, &data, 0, 6).is_empty());
+    }
+}
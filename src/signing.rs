@@ -0,0 +1,306 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand_core::OsRng;
+
+// Why a database wasn't trusted, kept apart from I/O errors so callers can tell
+// the two apart with `is_untrusted`.
+#[derive(Debug)]
+struct Untrusted(String);
+
+impl fmt::Display for Untrusted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for Untrusted {}
+
+pub(crate) fn untrusted(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, Untrusted(message.into()))
+}
+
+pub fn is_untrusted(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<Untrusted>())
+}
+
+fn decode<const N: usize>(text: &str, what: &str) -> Result<[u8; N], String> {
+    hex::decode(text.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| format!("invalid {}, expected {} hex digits", what, N * 2))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+// Where the detached signature of a database goes: next to it, with `.sig`
+// appended to its name.
+pub fn signature_path(path: impl AsRef<Path>) -> PathBuf {
+    with_suffix(path.as_ref(), ".sig")
+}
+
+// The public half of the key pair `generate_key` writes to `key`.
+pub fn public_key_path(key: &Path) -> PathBuf {
+    with_suffix(key, ".pub")
+}
+
+// A short form of a public key for messages.
+fn key_id(key: &VerifyingKey) -> String {
+    hex::encode(&key.as_bytes()[..8])
+}
+
+// Creates a new Ed25519 key pair: the private key at `path`, readable only by
+// its owner, and the public key at `path.pub` as a line for a trusted keys
+// file. Returns that line.
+pub fn generate_key(path: &Path) -> io::Result<String> {
+    let key = SigningKey::generate(&mut OsRng);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", hex::encode(key.to_bytes()))?;
+    file.sync_all()?;
+    let name = path.file_stem().map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+    let line = format!("{} {}", hex::encode(key.verifying_key().as_bytes()), name);
+    fs::write(public_key_path(path), format!("{}\n", line))?;
+    Ok(line)
+}
+
+fn read_signing_key(path: &Path) -> io::Result<SigningKey> {
+    let text = fs::read_to_string(path)?;
+    let bytes = decode(&text, "private key").map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+// Signs the file at `path` with the private key at `key` and writes the
+// signature to `signature_path(path)`, replacing any earlier one. The file is
+// signed byte for byte, so any change to it afterwards needs a new signature.
+pub fn sign_database(path: &str, key: &Path) -> io::Result<PathBuf> {
+    let key = read_signing_key(key)?;
+    let signature = key.sign(&fs::read(path)?);
+    let output = signature_path(path);
+    let temporary = with_suffix(&output, ".tmp");
+    fs::write(
+        &temporary,
+        format!("{} {}\n", hex::encode(key.verifying_key().as_bytes()), hex::encode(signature.to_bytes())),
+    )?;
+    fs::rename(&temporary, &output)?;
+    Ok(output)
+}
+
+// Public keys allowed to sign databases, one per line in hex, optionally
+// followed by a name for messages. Blank lines and `#` comments are ignored.
+#[derive(Default)]
+pub struct TrustedKeys {
+    keys: Vec<(VerifyingKey, String)>,
+}
+
+impl TrustedKeys {
+    pub fn parse<R: BufRead>(reader: R) -> io::Result<TrustedKeys> {
+        let mut keys = Vec::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let (key, name) = trimmed.split_once(char::is_whitespace).unwrap_or((trimmed, ""));
+            let key = decode(key, "public key")
+                .and_then(|bytes| VerifyingKey::from_bytes(&bytes).map_err(|_| "invalid public key".to_string()))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", index + 1, e)))?;
+            let name = match name.trim() {
+                "" => key_id(&key),
+                name => name.to_string(),
+            };
+            keys.push((key, name));
+        }
+        Ok(TrustedKeys { keys })
+    }
+
+    pub fn read(path: &str) -> io::Result<TrustedKeys> {
+        let file = File::open(path)?;
+        TrustedKeys::parse(BufReader::new(file))
+    }
+
+    // Checks `data`, the contents of the database at `path`, against its
+    // detached signature. Returns the name of the key that signed it.
+    pub fn verify(&self, path: &str, data: &[u8]) -> io::Result<&str> {
+        let signature_path = signature_path(path);
+        match fs::read_to_string(&signature_path) {
+            Ok(signature) => self.verify_detached(&signature, data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(untrusted(format!("not signed, {} not found", signature_path.display())))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // The same check against the contents of a signature file.
+    pub fn verify_detached(&self, signature: &str, data: &[u8]) -> io::Result<&str> {
+        let malformed = |e: String| untrusted(format!("malformed signature: {}", e));
+        let (key, signature) =
+            signature.trim().split_once(' ').ok_or_else(|| malformed("expected key and signature".to_string()))?;
+        let key = decode::<32>(key, "public key").map_err(malformed)?;
+        let signature = Signature::from_bytes(&decode(signature, "signature").map_err(malformed)?);
+        let Some((key, name)) = self.keys.iter().find(|(trusted, _)| trusted.as_bytes() == &key) else {
+            return Err(untrusted(format!("signed with key {}, which is not trusted", hex::encode(&key[..8]))));
+        };
+        key.verify_strict(data, &signature)
+            .map_err(|_| untrusted("signature doesn't match, the database changed after it was signed"))?;
+        Ok(name)
+    }
+}
+
+// How databases are checked before they are loaded.
+#[derive(Clone)]
+pub enum Verification {
+    // Only databases signed with one of these keys load.
+    Required(Arc<TrustedKeys>),
+    // Databases load whether they are signed or not.
+    Skipped,
+}
+
+impl Verification {
+    pub fn check(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        match self {
+            Verification::Required(keys) => keys.verify(path, data).map(|_| ()),
+            Verification::Skipped => Ok(()),
+        }
+    }
+
+    // Checks a database that isn't on disk yet, given the contents of its
+    // signature file if it has one.
+    pub fn check_detached(&self, signature: Option<&str>, data: &[u8]) -> io::Result<()> {
+        match (self, signature) {
+            (Verification::Required(keys), Some(signature)) => keys.verify_detached(signature, data).map(|_| ()),
+            (Verification::Required(_), None) => Err(untrusted("not signed")),
+            (Verification::Skipped, _) => Ok(()),
+        }
+    }
+
+    // Reads a whole database and checks it, so what is parsed afterwards is
+    // exactly what was verified.
+    pub fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let data = fs::read(path)?;
+        self.check(path, &data)?;
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use super::{generate_key, is_untrusted, public_key_path, sign_database, signature_path, TrustedKeys, Verification};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("anti_virus-signing-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // A signed database and the keys that trust it.
+    fn signed(dir: &std::path::Path) -> (String, TrustedKeys) {
+        let key = dir.join("release.key");
+        let line = generate_key(&key).unwrap();
+        assert!(line.ends_with(" release"));
+        let database = dir.join("signatures.db");
+        fs::write(&database, "Test=deadbeef\n").unwrap();
+        let database = database.to_str().unwrap().to_string();
+        sign_database(&database, &key).unwrap();
+        let keys = TrustedKeys::read(public_key_path(&key).to_str().unwrap()).unwrap();
+        (database, keys)
+    }
+
+    fn assert_untrusted(result: io::Result<impl std::fmt::Debug>, reason: &str) {
+        let error = result.unwrap_err();
+        assert!(is_untrusted(&error), "{}", error);
+        assert!(error.to_string().contains(reason), "{}", error);
+    }
+
+    #[test]
+    fn signed_database_verifies() {
+        let dir = temp_dir("verifies");
+        let (database, keys) = signed(&dir);
+        let data = fs::read(&database).unwrap();
+        assert_eq!(keys.verify(&database, &data).unwrap(), "release");
+        let verification = Verification::Required(Arc::new(keys));
+        assert_eq!(verification.read(&database).unwrap(), data);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tampering_is_refused() {
+        let dir = temp_dir("tampered");
+        let (database, keys) = signed(&dir);
+        let verification = Verification::Required(Arc::new(keys));
+        fs::write(&database, "Test=deadbeee\n").unwrap();
+        assert_untrusted(verification.read(&database), "changed after it was signed");
+        fs::write(&database, "Test=deadbeef\n").unwrap();
+        verification.read(&database).unwrap();
+
+        let signature = fs::read_to_string(signature_path(&database)).unwrap();
+        let (key, value) = signature.trim().split_once(' ').unwrap();
+        let flipped = if value.starts_with('0') { "1" } else { "0" };
+        fs::write(signature_path(&database), format!("{} {}{}\n", key, flipped, &value[1..])).unwrap();
+        assert_untrusted(verification.read(&database), "changed after it was signed");
+        fs::write(signature_path(&database), format!("{} {}\n", key, &value[2..])).unwrap();
+        assert_untrusted(verification.read(&database), "malformed signature");
+        fs::remove_file(signature_path(&database)).unwrap();
+        assert_untrusted(verification.read(&database), "not signed");
+        assert!(Verification::Skipped.read(&database).is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn other_keys_are_not_trusted() {
+        let dir = temp_dir("other-key");
+        let (database, _) = signed(&dir);
+        generate_key(&dir.join("other.key")).unwrap();
+        let keys = TrustedKeys::read(public_key_path(&dir.join("other.key")).to_str().unwrap()).unwrap();
+        assert_untrusted(keys.verify(&database, &fs::read(&database).unwrap()), "not trusted");
+        assert_untrusted(Verification::Required(Arc::new(keys)).check_detached(None, b""), "not signed");
+        // An existing key is never overwritten.
+        assert_eq!(generate_key(&dir.join("other.key")).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn trusted_keys_file() {
+        let key = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+        let keys = TrustedKeys::parse(format!("# release keys\n\n{} ci key\n{}\n", key, key).as_bytes()).unwrap();
+        let names: Vec<&str> = keys.keys.iter().map(|(_, name)| name.as_str()).collect();
+        assert_eq!(names, ["ci key", "d75a980182b10ab7"]);
+        let error = TrustedKeys::parse(format!("{}\nabcd\n", key).as_bytes()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with("line 2:"), "{}", error);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_key_is_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir("mode");
+        generate_key(&dir.join("k")).unwrap();
+        assert_eq!(fs::metadata(dir.join("k")).unwrap().permissions().mode() & 0o777, 0o600);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
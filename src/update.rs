@@ -0,0 +1,620 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use fnv::FnvHashSet;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::compiled::is_compiled;
+use crate::metadata::DbFormat;
+use crate::signatures::parse_source_signatures;
+use crate::signing::{signature_path, untrusted, Verification};
+
+const MANIFEST: &str = "manifest.json";
+const STATE: &str = "state.json";
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+// What a mirror publishes in `manifest.json`:
+//
+//     {
+//         "version": 42,
+//         "file": "signatures-42.db",
+//         "sha256": "<sha256 of signatures-42.db>",
+//         "deltas": [
+//             { "from": 41, "file": "41-42.delta", "sha256": "<sha256 of 41-42.delta>" }
+//         ]
+//     }
+//
+// Versions only ever go up. The manifest itself isn't signed, so the database
+// has to state the same version, with `# version: 42` or a `version` key, which
+// its signature then covers. It may have a detached signature next to it,
+// `signatures-42.db.sig`, which is fetched and installed with it.
+#[derive(Deserialize)]
+struct Manifest {
+    version: u64,
+    file: String,
+    sha256: String,
+    #[serde(default)]
+    deltas: Vec<DeltaSpec>,
+}
+
+#[derive(Deserialize)]
+struct DeltaSpec {
+    from: u64,
+    file: String,
+    sha256: String,
+}
+
+// Where updates come from: a local directory or an `http://` URL. Everything
+// fetched is checked against the manifest's checksums and, unless signature
+// checks are off, the database's signature, so plain HTTP is enough.
+enum Mirror {
+    Directory(PathBuf),
+    Http(String),
+}
+
+impl Mirror {
+    fn parse(location: &str) -> io::Result<Mirror> {
+        if location.starts_with("http://") {
+            return Ok(Mirror::Http(location.trim_end_matches('/').to_string()));
+        }
+        if location.contains("://") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported mirror {}, expected a directory or an http:// URL", location),
+            ));
+        }
+        Ok(Mirror::Directory(PathBuf::from(location)))
+    }
+
+    fn fetch(&self, name: &str) -> io::Result<Vec<u8>> {
+        if name.is_empty() || name.contains(['/', '\\']) || name == ".." {
+            return Err(invalid(format!("invalid file name {} in the manifest", name)));
+        }
+        match self {
+            Mirror::Directory(dir) => fs::read(dir.join(name)),
+            Mirror::Http(base) => http_get(&format!("{}/{}", base, name)),
+        }
+    }
+}
+
+// Body of a `Transfer-Encoding: chunked` response.
+fn decode_chunked(mut body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|window| window == b"\r\n").ok_or_else(|| invalid("truncated chunk"))?;
+        let size = std::str::from_utf8(&body[..line_end])
+            .ok()
+            .and_then(|line| usize::from_str_radix(line.split(';').next()?.trim(), 16).ok())
+            .ok_or_else(|| invalid("invalid chunk size"))?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        if body.len() < size + 2 {
+            return Err(invalid("truncated chunk"));
+        }
+        decoded.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+// A plain HTTP/1.1 GET, enough for a mirror serving static files.
+fn http_get(url: &str) -> io::Result<Vec<u8>> {
+    let rest = &url["http://".len()..];
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let path = if path.is_empty() { "/" } else { path };
+    let address = if authority.contains(':') { authority.to_string() } else { format!("{}:80", authority) };
+    let mut stream = TcpStream::connect(address).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", url, e)))?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: anti_virus/{}\r\nConnection: close\r\n\r\n",
+        path,
+        authority,
+        env!("CARGO_PKG_VERSION")
+    )?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+
+    let head_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| invalid(format!("{}: malformed HTTP response", url)))?;
+    let head = String::from_utf8_lossy(&response[..head_end]);
+    let mut lines = head.split("\r\n");
+    let status = lines.next().and_then(|line| line.split_whitespace().nth(1)).unwrap_or("");
+    if status != "200" {
+        let kind = if status == "404" { io::ErrorKind::NotFound } else { io::ErrorKind::Other };
+        return Err(io::Error::new(kind, format!("{}: HTTP status {}", url, status)));
+    }
+    let mut chunked = false;
+    let mut length = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        } else if name.eq_ignore_ascii_case("content-length") {
+            length = value.parse::<usize>().ok();
+        }
+    }
+    let body = &response[head_end + 4..];
+    if chunked {
+        return decode_chunked(body);
+    }
+    match length {
+        Some(length) if body.len() < length => Err(invalid(format!("{}: truncated response", url))),
+        Some(length) => Ok(body[..length].to_vec()),
+        None => Ok(body.to_vec()),
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn check_sha256(name: &str, data: &[u8], expected: &str) -> io::Result<()> {
+    if !sha256_hex(data).eq_ignore_ascii_case(expected.trim()) {
+        return Err(invalid(format!("{} doesn't match its checksum in the manifest", name)));
+    }
+    Ok(())
+}
+
+// The name defined on a line of a legacy database, if it defines one.
+fn line_name(line: &[u8]) -> Option<&[u8]> {
+    let line = line.trim_ascii();
+    if line.is_empty() || line.starts_with(b"#") {
+        return None;
+    }
+    let end = line.iter().position(|&byte| byte == b'=').unwrap_or(line.len());
+    Some(line[..end].trim_ascii())
+}
+
+fn is_version_comment(line: &[u8]) -> bool {
+    line.trim_ascii().strip_prefix(b"#").is_some_and(|comment| comment.trim_ascii().starts_with(b"version:"))
+}
+
+// Applies a delta to a legacy database, bringing it to `version`, or returns
+// `None` if the delta is malformed. A delta has one change per line: `-name`
+// drops the definition of `name` and `+name=...` replaces it, or adds it if
+// there was none. The result starts with `# version: <version>`, kept lines
+// stay in place and new definitions go at the end, which is how the mirror
+// builds the full database too, so the result can be checked against the same
+// checksum.
+fn apply_delta(current: &[u8], delta: &[u8], version: u64) -> Option<Vec<u8>> {
+    let mut dropped = FnvHashSet::default();
+    let mut added = Vec::new();
+    for line in delta.split(|&byte| byte == b'\n') {
+        let line = line.trim_ascii();
+        match line.first() {
+            None | Some(b'#') => {}
+            Some(b'-') => {
+                dropped.insert(line[1..].trim_ascii());
+            }
+            Some(b'+') => {
+                let definition = line[1..].trim_ascii();
+                dropped.insert(line_name(definition)?);
+                added.push(definition);
+            }
+            Some(_) => return None,
+        }
+    }
+    let mut updated = format!("# version: {}\n", version).into_bytes();
+    for line in current.split_inclusive(|&byte| byte == b'\n') {
+        if !is_version_comment(line) && line_name(line).is_none_or(|name| !dropped.contains(name)) {
+            updated.extend_from_slice(line);
+        }
+    }
+    if !updated.ends_with(b"\n") {
+        updated.push(b'\n');
+    }
+    for definition in added {
+        updated.extend_from_slice(definition);
+        updated.push(b'\n');
+    }
+    Some(updated)
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Backup {
+    // `None` for a database that didn't come from a mirror.
+    version: Option<u64>,
+    file: String,
+    signed: bool,
+}
+
+// What `db update` knows about an installed database, kept in `state.json`
+// next to its backups.
+#[derive(Default, Serialize, Deserialize)]
+struct UpdateState {
+    mirror: Option<String>,
+    version: Option<u64>,
+    // Databases replaced by updates, oldest first.
+    previous: Vec<Backup>,
+}
+
+// Where the state and backups of the database at `db` are kept.
+pub fn versions_dir(db: &str) -> PathBuf {
+    let mut dir = Path::new(db).as_os_str().to_owned();
+    dir.push(".versions");
+    PathBuf::from(dir)
+}
+
+fn read_state(dir: &Path) -> io::Result<UpdateState> {
+    match fs::read(dir.join(STATE)) {
+        Ok(data) => serde_json::from_slice(&data).map_err(|e| invalid(format!("{}: {}", STATE, e))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(UpdateState::default()),
+        Err(e) => Err(e),
+    }
+}
+
+// Writes next to `path` and renames over it, so readers see either the old
+// contents or the new ones.
+fn replace_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let mut file = File::create(&temporary)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+fn write_state(dir: &Path, state: &UpdateState) -> io::Result<()> {
+    replace_file(&dir.join(STATE), &serde_json::to_vec_pretty(state)?)
+}
+
+fn remove_if_present(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Puts a database and its signature in place. A scanner loading in between may
+// see the new database with the old signature and refuse it, but never loads
+// an unchecked mix.
+fn install(db: &Path, data: &[u8], signature: Option<&[u8]>) -> io::Result<()> {
+    replace_file(db, data)?;
+    match signature {
+        Some(signature) => replace_file(&signature_path(db), signature),
+        None => remove_if_present(&signature_path(db)),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    UpToDate { version: u64 },
+    Installed { from: Option<u64>, to: u64, delta: bool },
+}
+
+// Brings the database at `db` up to the version published by `mirror`, or the
+// mirror it was last updated from. Takes a delta when the mirror has one from
+// the installed version and a full download otherwise. The new database has to
+// match the manifest's checksum, pass `verification` and parse before it
+// replaces the old one, and state the manifest's version. The old one is kept
+// for `rollback_database` along with the `keep - 1` before it; at least the
+// one just replaced is always kept.
+pub fn update_database(
+    db: &str,
+    mirror: Option<&str>,
+    keep: usize,
+    verification: &Verification,
+) -> io::Result<UpdateOutcome> {
+    if Path::new(db).is_file() && is_compiled(db) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "compiled databases can't be updated, update the source"));
+    }
+    let dir = versions_dir(db);
+    let mut state = read_state(&dir)?;
+    let location = mirror
+        .map(str::to_string)
+        .or_else(|| state.mirror.clone())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no mirror configured yet, pass --mirror"))?;
+    let source = Mirror::parse(&location)?;
+    let manifest: Manifest = serde_json::from_slice(&source.fetch(MANIFEST)?)
+        .map_err(|e| invalid(format!("{}: {}", MANIFEST, e)))?;
+    if let Some(installed) = state.version {
+        if manifest.version < installed {
+            return Err(invalid(format!(
+                "mirror has version {}, older than the installed version {}",
+                manifest.version, installed
+            )));
+        }
+        if manifest.version == installed {
+            return Ok(UpdateOutcome::UpToDate { version: installed });
+        }
+    }
+
+    let current = match fs::read(db) {
+        Ok(current) => Some(current),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    // Anything wrong with a delta, including a database edited since it was
+    // installed and so patching into something else, means a full download.
+    let delta = manifest.deltas.iter().find(|delta| Some(delta.from) == state.version);
+    let updated = match (delta, &current, DbFormat::from_path(db)) {
+        (Some(delta), Some(current), DbFormat::Legacy) => source
+            .fetch(&delta.file)
+            .ok()
+            .filter(|patch| check_sha256(&delta.file, patch, &delta.sha256).is_ok())
+            .and_then(|patch| apply_delta(current, &patch, manifest.version))
+            .filter(|patched| check_sha256(&manifest.file, patched, &manifest.sha256).is_ok()),
+        _ => None,
+    };
+    let from_delta = updated.is_some();
+    let data = match updated {
+        Some(data) => data,
+        None => {
+            let data = source.fetch(&manifest.file)?;
+            check_sha256(&manifest.file, &data, &manifest.sha256)?;
+            data
+        }
+    };
+    let signature = match source.fetch(&format!("{}.sig", manifest.file)) {
+        Ok(signature) => Some(signature),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    let signature_text = signature.as_deref().map(String::from_utf8_lossy);
+    verification
+        .check_detached(signature_text.as_deref(), &data)
+        .map_err(|e| untrusted(format!("{}: {}", manifest.file, e)))?;
+    let (signatures, _) = parse_source_signatures(db, &data)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", manifest.file, e)))?;
+    // Otherwise a mirror could pass off an old signed database as a new
+    // version, downgrading it and blocking the real updates after it.
+    if signatures.version() != manifest.version.to_string() {
+        return Err(invalid(format!(
+            "{} is version {}, but the manifest says {}",
+            manifest.file,
+            signatures.version(),
+            manifest.version
+        )));
+    }
+
+    fs::create_dir_all(&dir)?;
+    if let Some(current) = &current {
+        let name = Path::new(db).file_name().map_or_else(|| "database".into(), |name| name.to_string_lossy());
+        let file = format!("{}.{}", name, state.version.map_or("local".to_string(), |version| version.to_string()));
+        let old_signature = fs::read(signature_path(Path::new(db))).ok();
+        replace_file(&dir.join(&file), current)?;
+        if let Some(old_signature) = &old_signature {
+            replace_file(&signature_path(dir.join(&file)), old_signature)?;
+        }
+        state.previous.retain(|backup| backup.file != file);
+        state.previous.push(Backup { version: state.version, file, signed: old_signature.is_some() });
+    }
+    let excess = state.previous.len().saturating_sub(keep.max(1));
+    // Backups already deleted by hand are fine to lose track of.
+    for backup in state.previous.drain(..excess) {
+        remove_if_present(&dir.join(&backup.file))?;
+        if backup.signed {
+            remove_if_present(&signature_path(dir.join(&backup.file)))?;
+        }
+    }
+
+    install(Path::new(db), &data, signature.as_deref())?;
+    let from = state.version;
+    state.version = Some(manifest.version);
+    state.mirror = Some(location);
+    write_state(&dir, &state)?;
+    Ok(UpdateOutcome::Installed { from, to: manifest.version, delta: from_delta })
+}
+
+// Puts back the database the last update replaced. Returns its version, `None`
+// for one that didn't come from a mirror.
+pub fn rollback_database(db: &str) -> io::Result<Option<u64>> {
+    let dir = versions_dir(db);
+    let mut state = read_state(&dir)?;
+    let backup = state
+        .previous
+        .pop()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no earlier version to roll back to"))?;
+    let path = dir.join(&backup.file);
+    let data = fs::read(&path)?;
+    let signature = if backup.signed { Some(fs::read(signature_path(&path))?) } else { None };
+    install(Path::new(db), &data, signature.as_deref())?;
+    state.version = backup.version;
+    write_state(&dir, &state)?;
+    fs::remove_file(&path)?;
+    if backup.signed {
+        fs::remove_file(signature_path(&path))?;
+    }
+    Ok(backup.version)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use fnv::FnvHashMap;
+
+    use super::{apply_delta, decode_chunked, http_get, rollback_database, sha256_hex, update_database, UpdateOutcome};
+    use crate::signing::{is_untrusted, TrustedKeys, Verification};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("anti_virus-update-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    type Files = Arc<Mutex<FnvHashMap<String, Vec<u8>>>>;
+
+    // Serves `files` over HTTP on a local port, databases chunked and
+    // everything else with a length, and returns the mirror's URL.
+    fn serve(files: Files) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/mirror", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                let mut reader = BufReader::new(&stream);
+                reader.read_line(&mut request).unwrap();
+                let mut header = String::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    header.clear();
+                }
+                let path = request.split_whitespace().nth(1).unwrap_or("");
+                let name = path.strip_prefix("/mirror/").unwrap_or(path);
+                let file = files.lock().unwrap().get(name).cloned();
+                let response = match file {
+                    None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+                    Some(body) if name.ends_with(".db") => {
+                        let mut response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+                        for chunk in body.chunks(7) {
+                            response.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+                            response.extend_from_slice(chunk);
+                            response.extend_from_slice(b"\r\n");
+                        }
+                        response.extend_from_slice(b"0\r\n\r\n");
+                        response
+                    }
+                    Some(body) => {
+                        let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+                        response.extend_from_slice(&body);
+                        response
+                    }
+                };
+                let _ = stream.write_all(&response);
+            }
+        });
+        url
+    }
+
+    fn publish(files: &Files, version: u64, database: &str, delta: Option<(u64, &str)>) {
+        let mut files = files.lock().unwrap();
+        let file = format!("signatures-{}.db", version);
+        let deltas = match delta {
+            Some((from, delta)) => {
+                let name = format!("{}-{}.delta", from, version);
+                let entry = format!(r#"[{{"from": {}, "file": "{}", "sha256": "{}"}}]"#, from, name, sha256_hex(delta.as_bytes()));
+                files.insert(name, delta.as_bytes().to_vec());
+                entry
+            }
+            None => "[]".to_string(),
+        };
+        let manifest = format!(
+            r#"{{"version": {}, "file": "{}", "sha256": "{}", "deltas": {}}}"#,
+            version,
+            file,
+            sha256_hex(database.as_bytes()),
+            deltas
+        );
+        files.insert("manifest.json".to_string(), manifest.into_bytes());
+        files.insert(file, database.as_bytes().to_vec());
+    }
+
+    #[test]
+    fn chunked_bodies() {
+        assert_eq!(decode_chunked(b"4\r\nWiki\r\n6;ext=1\r\npedia \r\n0\r\n\r\n").unwrap(), b"Wikipedia ");
+        assert_eq!(decode_chunked(b"0\r\n\r\n").unwrap(), b"");
+        for broken in [&b"4\r\nWik"[..], b"zz\r\nWiki\r\n0\r\n\r\n", b"4\r\nWiki\r\n"] {
+            assert_eq!(decode_chunked(broken).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn deltas() {
+        let current = b"# version: 1\nA=aa\nB=bb\n# note\nC=cc";
+        let updated = apply_delta(current, b"# 1 to 2\n-B\n+C=c0c0\n+D=dd\n", 2).unwrap();
+        assert_eq!(updated, b"# version: 2\nA=aa\n# note\nC=c0c0\nD=dd\n");
+        assert!(apply_delta(current, b"B=bb\n", 2).is_none());
+        assert!(apply_delta(current, b"+#comment\n", 2).is_none());
+    }
+
+    #[test]
+    fn http_get_reads_lengths_and_chunks() {
+        let files: Files = Arc::default();
+        files.lock().unwrap().insert("a.db".to_string(), b"chunked body over several chunks".to_vec());
+        files.lock().unwrap().insert("b.txt".to_string(), b"plain".to_vec());
+        let url = serve(files);
+        assert_eq!(http_get(&format!("{}/a.db", url)).unwrap(), b"chunked body over several chunks");
+        assert_eq!(http_get(&format!("{}/b.txt", url)).unwrap(), b"plain");
+        assert_eq!(http_get(&format!("{}/missing", url)).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn updates_over_http_with_deltas_and_rolls_back() {
+        let dir = temp_dir("http");
+        let db = dir.join("signatures.db");
+        let db = db.to_str().unwrap();
+        let files: Files = Arc::default();
+        let url = serve(Arc::clone(&files));
+        let v1 = "# version: 1\nA=aaaa\nB=bbbb\n";
+        publish(&files, 1, v1, None);
+        let outcome = update_database(db, Some(&url), 3, &Verification::Skipped).unwrap();
+        assert_eq!(outcome, UpdateOutcome::Installed { from: None, to: 1, delta: false });
+        assert_eq!(fs::read_to_string(db).unwrap(), v1);
+        // Later updates remember the mirror.
+        assert_eq!(update_database(db, None, 3, &Verification::Skipped).unwrap(), UpdateOutcome::UpToDate { version: 1 });
+
+        let v2 = "# version: 2\nA=aaaa\nC=cccc\n";
+        publish(&files, 2, v2, Some((1, "-B\n+C=cccc\n")));
+        let outcome = update_database(db, None, 3, &Verification::Skipped).unwrap();
+        assert_eq!(outcome, UpdateOutcome::Installed { from: Some(1), to: 2, delta: true });
+        assert_eq!(fs::read_to_string(db).unwrap(), v2);
+
+        // A delta that doesn't produce the published database falls back to it.
+        let v3 = "# version: 3\nA=aaaa\nC=cccc\nD=dddd\n";
+        publish(&files, 3, v3, Some((2, "+D=0000\n")));
+        let outcome = update_database(db, None, 3, &Verification::Skipped).unwrap();
+        assert_eq!(outcome, UpdateOutcome::Installed { from: Some(2), to: 3, delta: false });
+        assert_eq!(fs::read_to_string(db).unwrap(), v3);
+
+        assert_eq!(rollback_database(db).unwrap(), Some(2));
+        assert_eq!(fs::read_to_string(db).unwrap(), v2);
+        assert_eq!(rollback_database(db).unwrap(), Some(1));
+        assert_eq!(fs::read_to_string(db).unwrap(), v1);
+        // The first install replaced nothing.
+        assert_eq!(rollback_database(db).unwrap_err().kind(), io::ErrorKind::NotFound);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_bad_updates() {
+        let dir = temp_dir("refused");
+        let db = dir.join("signatures.db");
+        let db = db.to_str().unwrap();
+        fs::write(db, "# version: 1\nA=aaaa\n").unwrap();
+        let files: Files = Arc::default();
+        let url = serve(Arc::clone(&files));
+
+        // The database has to state the version the manifest claims.
+        publish(&files, 5, "# version: 4\nA=aaaa\n", None);
+        let error = update_database(db, Some(&url), 3, &Verification::Skipped).unwrap_err();
+        assert!(error.to_string().contains("is version 4, but the manifest says 5"), "{}", error);
+
+        publish(&files, 5, "# version: 5\nA=aaaa\n", None);
+        files.lock().unwrap().insert("signatures-5.db".to_string(), b"# version: 5\nA=ffff\n".to_vec());
+        let error = update_database(db, Some(&url), 3, &Verification::Skipped).unwrap_err();
+        assert!(error.to_string().contains("doesn't match its checksum"), "{}", error);
+
+        publish(&files, 5, "# version: 5\nA=aaaa\n", None);
+        let keys = TrustedKeys::parse(&b"d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a\n"[..]).unwrap();
+        let error = update_database(db, Some(&url), 3, &Verification::Required(Arc::new(keys))).unwrap_err();
+        assert!(is_untrusted(&error), "{}", error);
+
+        // Nothing was installed by any of them.
+        assert_eq!(fs::read_to_string(db).unwrap(), "# version: 1\nA=aaaa\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,958 @@
+use std::fmt;
+use std::fs;
+use std::io;
+
+use crate::logical::{Condition, FileFacts, LogicalSignature};
+use crate::pattern::Pattern;
+use crate::signatures::{Anchor, DbError, Signature};
+
+// How much of a file `uint32(uint32(0x3c))` style reads can see when the
+// offset is only known at scan time.
+const DYNAMIC_READ_LEN: u64 = 4096;
+// Reads at constant offsets may reach this far into a file.
+const MAX_READ_LEN: u64 = 64 * 1024;
+
+#[derive(Clone, Debug)]
+enum Token {
+    Ident(String),
+    // `$a`, `#a`, `@a` or `!a`; the name is empty for anonymous strings and may
+    // end in `*` in `of` lists.
+    Var(char, String),
+    Text(Vec<u8>),
+    Number(i64),
+    Regex,
+    Punct(&'static str),
+    Eof,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "'{}'", name),
+            Token::Var(sigil, name) => write!(f, "'{}{}'", sigil, name),
+            Token::Text(_) => write!(f, "a string"),
+            Token::Number(n) => write!(f, "'{}'", n),
+            Token::Regex => write!(f, "a regular expression"),
+            Token::Punct(punct) => write!(f, "'{}'", punct),
+            Token::Eof => write!(f, "end of file"),
+        }
+    }
+}
+
+const PUNCTUATION: [&str; 24] = [
+    "==", "!=", "<=", ">=", "<<", ">>", "..", "<", ">", "(", ")", "[", "]", "{", "}", ":", "=", ",", "+", "-", "*", "\\",
+    "%", "&",
+];
+
+struct Lexer<'s> {
+    src: &'s str,
+    pos: usize,
+    // Start of the token last read or peeked, where errors are reported.
+    token: usize,
+}
+
+impl<'s> Lexer<'s> {
+    fn rest(&self) -> &'s str {
+        &self.src[self.pos..]
+    }
+
+    fn line(&self) -> usize {
+        self.src[..self.token].matches('\n').count() + 1
+    }
+
+    fn skip_space(&mut self) -> Result<(), String> {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if trimmed.starts_with("//") {
+                self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else if trimmed.starts_with("/*") {
+                let end = trimmed.find("*/").ok_or("unterminated comment")?;
+                self.pos += end + 2;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        self.skip_space()?;
+        self.token = self.pos;
+        let rest = self.rest();
+        let Some(c) = rest.chars().next() else {
+            return Ok(Token::Eof);
+        };
+        let word_len = |s: &str| s.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(s.len());
+        if c.is_ascii_alphabetic() || c == '_' {
+            let len = word_len(rest);
+            self.pos += len;
+            return Ok(Token::Ident(rest[..len].to_string()));
+        }
+        if c.is_ascii_digit() {
+            return self.number();
+        }
+        if c == '"' {
+            return self.text();
+        }
+        if c == '/' {
+            // Division is `\` in YARA, so a slash always starts a regular expression.
+            let mut escaped = false;
+            for (i, c) in rest.char_indices().skip(1) {
+                match c {
+                    '\\' if !escaped => escaped = true,
+                    '/' if !escaped => {
+                        self.pos += i + 1;
+                        let flags = word_len(self.rest());
+                        self.pos += flags;
+                        return Ok(Token::Regex);
+                    }
+                    '\n' => break,
+                    _ => escaped = false,
+                }
+            }
+            return Err("unterminated regular expression".to_string());
+        }
+        if matches!(c, '$' | '#' | '@' | '!') && !rest.starts_with("!=") {
+            let len = word_len(&rest[1..]);
+            let mut name = rest[1..1 + len].to_string();
+            self.pos += 1 + len;
+            if c == '$' && self.rest().starts_with('*') {
+                name.push('*');
+                self.pos += 1;
+            }
+            return Ok(Token::Var(c, name));
+        }
+        for punct in PUNCTUATION {
+            if rest.starts_with(punct) {
+                self.pos += punct.len();
+                return Ok(Token::Punct(punct));
+            }
+        }
+        Err(format!("unexpected character '{}'", c))
+    }
+
+    fn peek(&mut self) -> Result<Token, String> {
+        let pos = self.pos;
+        let token = self.next();
+        self.pos = pos;
+        token
+    }
+
+    fn number(&mut self) -> Result<Token, String> {
+        let rest = self.rest();
+        let len = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+        let text = &rest[..len];
+        self.pos += len;
+        let (digits, multiplier) = match text.strip_suffix("KB").or_else(|| text.strip_suffix("MB")) {
+            Some(digits) => (digits, if text.ends_with("KB") { 1024 } else { 1024 * 1024 }),
+            None => (text, 1),
+        };
+        let value = match digits.strip_prefix("0x") {
+            Some(hex) => i64::from_str_radix(hex, 16),
+            None => digits.parse(),
+        };
+        value
+            .ok()
+            .and_then(|value| value.checked_mul(multiplier))
+            .map(Token::Number)
+            .ok_or_else(|| format!("invalid number {}", text))
+    }
+
+    fn text(&mut self) -> Result<Token, String> {
+        let mut bytes = Vec::new();
+        let mut chars = self.rest().char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(Token::Text(bytes));
+                }
+                '\n' => break,
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => bytes.push(b'\n'),
+                    Some('t') => bytes.push(b'\t'),
+                    Some('r') => bytes.push(b'\r'),
+                    Some('"') => bytes.push(b'"'),
+                    Some('\\') => bytes.push(b'\\'),
+                    Some('x') => {
+                        let hex: String = chars.by_ref().take(2).map(|(_, c)| c).collect();
+                        let byte = u8::from_str_radix(&hex, 16).map_err(|_| format!("invalid escape \\x{}", hex))?;
+                        bytes.push(byte);
+                    }
+                    other => return Err(format!("invalid escape \\{}", other.unwrap_or(' '))),
+                },
+                c => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+
+    // The body of a hex string; the opening `{` is next.
+    fn hex_string(&mut self) -> Result<String, String> {
+        self.skip_space()?;
+        let rest = self.rest();
+        let end = rest.find('}').ok_or("unterminated hex string")?;
+        self.pos += end + 1;
+        Ok(rest[1..end].to_string())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Compare {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Arith {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    BitAnd,
+    Shl,
+    Shr,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Quantifier {
+    Any,
+    All,
+    None,
+    AtLeast(i64),
+}
+
+// A YARA condition. Values are integers, with booleans as 0 and 1; `None`
+// stands for YARA's undefined, e.g. reading past the end of the file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Expr {
+    Number(i64),
+    Filesize,
+    // `$a`, `$a at n` and `$a in (from..to)`, by string index.
+    Present(usize),
+    At(usize, Box<Expr>),
+    In(usize, Box<Expr>, Box<Expr>),
+    // `#a`, `@a[i]` and `!a[i]`.
+    Count(usize),
+    Offset(usize, Box<Expr>),
+    Length(usize, Box<Expr>),
+    // `uint32(offset)` and friends.
+    Read { width: usize, signed: bool, big_endian: bool, offset: Box<Expr> },
+    Of(Quantifier, Vec<usize>),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Compare(Box<Expr>, Compare, Box<Expr>),
+    Arith(Box<Expr>, Arith, Box<Expr>),
+    Negate(Box<Expr>),
+}
+
+struct Scope<'f> {
+    // Matches of each string, merged over its ascii and wide forms.
+    strings: Vec<Vec<(u64, u64)>>,
+    facts: &'f FileFacts<'f>,
+}
+
+fn truthy(value: Option<i64>) -> bool {
+    matches!(value, Some(value) if value != 0)
+}
+
+impl Expr {
+    fn evaluate(&self, scope: &Scope) -> Option<i64> {
+        let hits = |string: &usize| &scope.strings[*string];
+        let nth = |string: &usize, index: &Expr| {
+            let index = usize::try_from(index.evaluate(scope)?).ok()?;
+            hits(string).get(index.checked_sub(1)?).copied()
+        };
+        match self {
+            Expr::Number(n) => Some(*n),
+            Expr::Filesize => i64::try_from(scope.facts.size).ok(),
+            Expr::Present(string) => Some(!hits(string).is_empty() as i64),
+            Expr::At(string, offset) => {
+                let offset = offset.evaluate(scope)?;
+                Some(hits(string).iter().any(|&(start, _)| start as i64 == offset) as i64)
+            }
+            Expr::In(string, from, to) => {
+                let (from, to) = (from.evaluate(scope)?, to.evaluate(scope)?);
+                Some(hits(string).iter().any(|&(start, _)| (from..=to).contains(&(start as i64))) as i64)
+            }
+            Expr::Count(string) => Some(hits(string).len() as i64),
+            Expr::Offset(string, index) => nth(string, index).map(|(start, _)| start as i64),
+            Expr::Length(string, index) => nth(string, index).map(|(start, end)| (end - start) as i64),
+            Expr::Read { width, signed, big_endian, offset } => {
+                let offset = usize::try_from(offset.evaluate(scope)?).ok()?;
+                let bytes = scope.facts.header.get(offset..offset.checked_add(*width)?)?;
+                let mut buffer = [0u8; 8];
+                let value = if *big_endian {
+                    buffer[8 - width..].copy_from_slice(bytes);
+                    u64::from_be_bytes(buffer)
+                } else {
+                    buffer[..*width].copy_from_slice(bytes);
+                    u64::from_le_bytes(buffer)
+                };
+                let bits = 64 - 8 * *width as u32;
+                Some(if *signed { ((value << bits) as i64) >> bits } else { value as i64 })
+            }
+            Expr::Of(quantifier, strings) => {
+                let present = strings.iter().filter(|string| !hits(string).is_empty()).count() as i64;
+                let holds = match quantifier {
+                    Quantifier::Any => present > 0,
+                    Quantifier::All => present == strings.len() as i64,
+                    Quantifier::None => present == 0,
+                    Quantifier::AtLeast(n) => present >= *n,
+                };
+                Some(holds as i64)
+            }
+            Expr::Not(operand) => operand.evaluate(scope).map(|value| (value == 0) as i64),
+            Expr::And(operands) => Some(operands.iter().all(|operand| truthy(operand.evaluate(scope))) as i64),
+            Expr::Or(operands) => Some(operands.iter().any(|operand| truthy(operand.evaluate(scope))) as i64),
+            Expr::Compare(left, compare, right) => {
+                let (left, right) = (left.evaluate(scope)?, right.evaluate(scope)?);
+                let holds = match compare {
+                    Compare::Equal => left == right,
+                    Compare::NotEqual => left != right,
+                    Compare::Less => left < right,
+                    Compare::LessEqual => left <= right,
+                    Compare::Greater => left > right,
+                    Compare::GreaterEqual => left >= right,
+                };
+                Some(holds as i64)
+            }
+            Expr::Arith(left, arith, right) => {
+                let (left, right) = (left.evaluate(scope)?, right.evaluate(scope)?);
+                match arith {
+                    Arith::Add => left.checked_add(right),
+                    Arith::Sub => left.checked_sub(right),
+                    Arith::Mul => left.checked_mul(right),
+                    Arith::Div => left.checked_div(right),
+                    Arith::Mod => left.checked_rem(right),
+                    Arith::BitAnd => Some(left & right),
+                    Arith::Shl => u32::try_from(right).ok().and_then(|right| left.checked_shl(right)),
+                    Arith::Shr => u32::try_from(right).ok().and_then(|right| left.checked_shr(right)),
+                }
+            }
+            Expr::Negate(operand) => operand.evaluate(scope)?.checked_neg(),
+        }
+    }
+
+    fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Number(_) | Expr::Filesize | Expr::Present(_) | Expr::Count(_) | Expr::Of(..) => Vec::new(),
+            Expr::At(_, operand)
+            | Expr::Offset(_, operand)
+            | Expr::Length(_, operand)
+            | Expr::Read { offset: operand, .. }
+            | Expr::Not(operand)
+            | Expr::Negate(operand) => vec![operand],
+            Expr::In(_, left, right) | Expr::Compare(left, _, right) | Expr::Arith(left, _, right) => vec![left, right],
+            Expr::And(operands) | Expr::Or(operands) => operands.iter().collect(),
+        }
+    }
+
+    // Bytes from the start of the file the expression reads.
+    fn read_len(&self) -> u64 {
+        let own = match self {
+            Expr::Read { width, offset, .. } => match **offset {
+                Expr::Number(offset) => (offset as u64).saturating_add(*width as u64),
+                _ => DYNAMIC_READ_LEN,
+            },
+            _ => 0,
+        };
+        self.children().into_iter().map(Expr::read_len).fold(own, u64::max)
+    }
+}
+
+// A compiled YARA rule: its strings are the sub-signatures of the logical
+// signature it becomes, its condition is evaluated once the file is scanned.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    // Sub-signature indices of each string; `wide ascii` strings have two.
+    strings: Vec<Vec<usize>>,
+    condition: Expr,
+    // The condition as written, for listing.
+    source: String,
+}
+
+impl Rule {
+    pub fn holds(&self, facts: &FileFacts) -> bool {
+        let strings = self
+            .strings
+            .iter()
+            .map(|parts| {
+                let mut hits: Vec<(u64, u64)> = parts.iter().flat_map(|&part| facts.hits[part].iter().copied()).collect();
+                if parts.len() > 1 {
+                    hits.sort_unstable();
+                    hits.dedup_by_key(|&mut (start, _)| start);
+                }
+                hits
+            })
+            .collect();
+        truthy(self.condition.evaluate(&Scope { strings, facts }))
+    }
+
+    pub fn read_len(&self) -> u64 {
+        self.condition.read_len()
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+struct StringDef {
+    name: String,
+    parts: Vec<usize>,
+}
+
+struct RuleParser<'s, 'l> {
+    lexer: &'l mut Lexer<'s>,
+    strings: Vec<StringDef>,
+    subsignatures: Vec<Signature>,
+    name: String,
+}
+
+impl RuleParser<'_, '_> {
+    fn expect(&mut self, punct: &str) -> Result<(), String> {
+        match self.lexer.next()? {
+            Token::Punct(found) if found == punct => Ok(()),
+            token => Err(format!("expected '{}', found {}", punct, token)),
+        }
+    }
+
+    fn eat(&mut self, punct: &str) -> Result<bool, String> {
+        if matches!(self.lexer.peek()?, Token::Punct(found) if found == punct) {
+            self.lexer.next()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> Result<bool, String> {
+        if matches!(self.lexer.peek()?, Token::Ident(word) if word == keyword) {
+            self.lexer.next()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn section(&mut self, name: &str) -> Result<bool, String> {
+        let pos = self.lexer.pos;
+        if self.eat_keyword(name)? && self.eat(":")? {
+            return Ok(true);
+        }
+        self.lexer.pos = pos;
+        Ok(false)
+    }
+
+    fn meta(&mut self) -> Result<(), String> {
+        while let Token::Ident(key) = self.lexer.peek()? {
+            if key == "strings" || key == "condition" {
+                break;
+            }
+            self.lexer.next()?;
+            self.expect("=")?;
+            self.eat("-")?;
+            match self.lexer.next()? {
+                Token::Text(_) | Token::Number(_) => {}
+                Token::Ident(value) if value == "true" || value == "false" => {}
+                token => return Err(format!("invalid value for meta {}: {}", key, token)),
+            }
+        }
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<(), String> {
+        let Token::Var('$', name) = self.lexer.next()? else { unreachable!() };
+        if name.ends_with('*') {
+            return Err(format!("invalid string name ${}", name));
+        }
+        if !name.is_empty() && self.strings.iter().any(|string| string.name == name) {
+            return Err(format!("duplicate string ${}", name));
+        }
+        self.expect("=")?;
+        self.lexer.skip_space()?;
+        let mut patterns = Vec::new();
+        if self.lexer.rest().starts_with('{') {
+            let body = self.lexer.hex_string()?;
+            // YARA writes jumps as `[n-m]`.
+            let hex: String = body
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .map(|c| match c {
+                    '[' => '{',
+                    ']' => '}',
+                    c => c,
+                })
+                .collect();
+            if hex.contains('~') {
+                return Err(format!("${}: negated bytes are not supported", name));
+            }
+            patterns.push(Pattern::parse(&hex).map_err(|e| format!("${}: {}", name, e))?);
+        } else {
+            let text = match self.lexer.next()? {
+                Token::Text(text) => text,
+                Token::Regex => return Err(format!("${}: regular expressions are not supported", name)),
+                token => return Err(format!("${}: expected a string, found {}", name, token)),
+            };
+            if text.is_empty() {
+                return Err(format!("${}: empty string", name));
+            }
+            let (mut nocase, mut wide, mut ascii) = (false, false, false);
+            while let Token::Ident(modifier) = self.lexer.peek()? {
+                match modifier.as_str() {
+                    "nocase" => nocase = true,
+                    "wide" => wide = true,
+                    "ascii" => ascii = true,
+                    "fullword" | "private" | "xor" | "base64" | "base64wide" => {
+                        return Err(format!("${}: the {} modifier is not supported", name, modifier));
+                    }
+                    _ => break,
+                }
+                self.lexer.next()?;
+            }
+            let mut pattern = Pattern::literal(&text);
+            if nocase {
+                pattern = pattern.ignore_case();
+            }
+            if ascii || !wide {
+                patterns.push(pattern.clone());
+            }
+            if wide {
+                patterns.push(pattern.wide().map_err(|e| format!("${}: {}", name, e))?);
+            }
+        }
+        let mut parts = Vec::new();
+        for pattern in patterns {
+            parts.push(self.subsignatures.len());
+            self.subsignatures.push(Signature { name: self.name.clone(), pattern, anchor: Anchor::Anywhere });
+        }
+        self.strings.push(StringDef { name, parts });
+        Ok(())
+    }
+
+    // Everything after the rule name; returns the condition and its source text.
+    fn body(&mut self) -> Result<(Expr, String), String> {
+        if self.eat(":")? {
+            while let Token::Ident(_) = self.lexer.peek()? {
+                self.lexer.next()?;
+            }
+        }
+        self.expect("{")?;
+        if self.section("meta")? {
+            self.meta()?;
+        }
+        if self.section("strings")? {
+            while let Token::Var('$', _) = self.lexer.peek()? {
+                self.string()?;
+            }
+        }
+        if !self.section("condition")? {
+            return Err("expected 'condition:'".to_string());
+        }
+        self.lexer.skip_space()?;
+        let start = self.lexer.pos;
+        let condition = self.or()?;
+        let source = self.lexer.src[start..self.lexer.pos].split_whitespace().collect::<Vec<_>>().join(" ");
+        self.expect("}")?;
+        Ok((condition, source))
+    }
+
+    fn string_index(&self, name: &str) -> Result<usize, String> {
+        if name.is_empty() {
+            return Err("anonymous strings can only be used with `of`".to_string());
+        }
+        self.strings.iter().position(|string| string.name == name).ok_or_else(|| format!("undefined string ${}", name))
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut operands = vec![self.and()?];
+        while self.eat_keyword("or")? {
+            operands.push(self.and()?);
+        }
+        Ok(if operands.len() == 1 { operands.remove(0) } else { Expr::Or(operands) })
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut operands = vec![self.not()?];
+        while self.eat_keyword("and")? {
+            operands.push(self.not()?);
+        }
+        Ok(if operands.len() == 1 { operands.remove(0) } else { Expr::And(operands) })
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.eat_keyword("not")? {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.binary(0)?;
+        let compare = match self.lexer.peek()? {
+            Token::Punct("==") => Compare::Equal,
+            Token::Punct("!=") => Compare::NotEqual,
+            Token::Punct("<") => Compare::Less,
+            Token::Punct("<=") => Compare::LessEqual,
+            Token::Punct(">") => Compare::Greater,
+            Token::Punct(">=") => Compare::GreaterEqual,
+            _ => return Ok(left),
+        };
+        self.lexer.next()?;
+        Ok(Expr::Compare(Box::new(left), compare, Box::new(self.binary(0)?)))
+    }
+
+    // Arithmetic by precedence level: `&`, then shifts, `+ -` and `* \ %`.
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        const LEVELS: [&[(&str, Arith)]; 4] = [
+            &[("&", Arith::BitAnd)],
+            &[("<<", Arith::Shl), (">>", Arith::Shr)],
+            &[("+", Arith::Add), ("-", Arith::Sub)],
+            &[("*", Arith::Mul), ("\\", Arith::Div), ("%", Arith::Mod)],
+        ];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        loop {
+            let token = self.lexer.peek()?;
+            let Some(&(_, arith)) = LEVELS[level].iter().find(|(punct, _)| matches!(token, Token::Punct(found) if found == *punct)) else {
+                return Ok(left);
+            };
+            self.lexer.next()?;
+            left = Expr::Arith(Box::new(left), arith, Box::new(self.binary(level + 1)?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("-")? {
+            return Ok(Expr::Negate(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn index(&mut self) -> Result<Box<Expr>, String> {
+        if self.eat("[")? {
+            let index = self.binary(0)?;
+            self.expect("]")?;
+            Ok(Box::new(index))
+        } else {
+            Ok(Box::new(Expr::Number(1)))
+        }
+    }
+
+    fn of(&mut self, quantifier: Quantifier) -> Result<Expr, String> {
+        if !self.eat_keyword("of")? {
+            return Err("expected 'of'".to_string());
+        }
+        let mut strings = Vec::new();
+        if self.eat_keyword("them")? {
+            strings.extend(0..self.strings.len());
+        } else {
+            self.expect("(")?;
+            loop {
+                match self.lexer.next()? {
+                    Token::Var('$', name) => match name.strip_suffix('*') {
+                        Some(prefix) => {
+                            let matching: Vec<usize> = (0..self.strings.len())
+                                .filter(|&i| !self.strings[i].name.is_empty() && self.strings[i].name.starts_with(prefix))
+                                .collect();
+                            if matching.is_empty() {
+                                return Err(format!("no strings match ${}", name));
+                            }
+                            strings.extend(matching);
+                        }
+                        None => strings.push(self.string_index(&name)?),
+                    },
+                    token => return Err(format!("expected a string, found {}", token)),
+                }
+                if !self.eat(",")? {
+                    break;
+                }
+            }
+            self.expect(")")?;
+        }
+        if strings.is_empty() {
+            return Err("the rule has no strings".to_string());
+        }
+        strings.sort_unstable();
+        strings.dedup();
+        Ok(Expr::Of(quantifier, strings))
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.lexer.next()? {
+            Token::Number(n) => {
+                if matches!(self.lexer.peek()?, Token::Ident(word) if word == "of") {
+                    return self.of(Quantifier::AtLeast(n));
+                }
+                Ok(Expr::Number(n))
+            }
+            Token::Punct("(") => {
+                let expr = self.or()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Token::Var('$', name) => {
+                let string = self.string_index(&name)?;
+                if self.eat_keyword("at")? {
+                    return Ok(Expr::At(string, Box::new(self.binary(0)?)));
+                }
+                if self.eat_keyword("in")? {
+                    self.expect("(")?;
+                    let from = self.binary(0)?;
+                    self.expect("..")?;
+                    let to = self.binary(0)?;
+                    self.expect(")")?;
+                    return Ok(Expr::In(string, Box::new(from), Box::new(to)));
+                }
+                Ok(Expr::Present(string))
+            }
+            Token::Var('#', name) => Ok(Expr::Count(self.string_index(&name)?)),
+            Token::Var('@', name) => {
+                let string = self.string_index(&name)?;
+                Ok(Expr::Offset(string, self.index()?))
+            }
+            Token::Var('!', name) => {
+                let string = self.string_index(&name)?;
+                Ok(Expr::Length(string, self.index()?))
+            }
+            Token::Ident(word) => match word.as_str() {
+                "true" => Ok(Expr::Number(1)),
+                "false" => Ok(Expr::Number(0)),
+                "filesize" => Ok(Expr::Filesize),
+                "any" => self.of(Quantifier::Any),
+                "all" => self.of(Quantifier::All),
+                "none" => self.of(Quantifier::None),
+                _ => match read_function(&word) {
+                    Some((width, signed, big_endian)) => {
+                        self.expect("(")?;
+                        let offset = self.binary(0)?;
+                        self.expect(")")?;
+                        if let Expr::Number(offset) = offset {
+                            if offset < 0 || offset as u64 + width as u64 > MAX_READ_LEN {
+                                return Err(format!("{}({}) reads beyond the first {} bytes", word, offset, MAX_READ_LEN));
+                            }
+                        }
+                        Ok(Expr::Read { width, signed, big_endian, offset: Box::new(offset) })
+                    }
+                    None => Err(format!("unsupported identifier '{}'", word)),
+                },
+            },
+            token => Err(format!("unexpected {} in condition", token)),
+        }
+    }
+}
+
+// Width, signedness and byte order of `uint8`..`int32be`.
+fn read_function(name: &str) -> Option<(usize, bool, bool)> {
+    let (name, big_endian) = match name.strip_suffix("be") {
+        Some(name) => (name, true),
+        None => (name, false),
+    };
+    let (bits, signed) = match name.strip_prefix('u') {
+        Some(rest) => (rest.strip_prefix("int")?, false),
+        None => (name.strip_prefix("int")?, true),
+    };
+    match bits {
+        "8" => Some((1, signed, big_endian)),
+        "16" => Some((2, signed, big_endian)),
+        "32" => Some((4, signed, big_endian)),
+        _ => None,
+    }
+}
+
+fn parse_rule(lexer: &mut Lexer) -> Result<LogicalSignature, String> {
+    match lexer.next()? {
+        Token::Ident(word) if word == "rule" => {}
+        Token::Ident(word) if word == "private" || word == "global" => {
+            return Err(format!("{} rules are not supported", word));
+        }
+        Token::Ident(word) if word == "import" || word == "include" => {
+            return Err(format!("`{}` is not supported", word));
+        }
+        token => return Err(format!("expected 'rule', found {}", token)),
+    }
+    let name = match lexer.next()? {
+        Token::Ident(name) => name,
+        token => return Err(format!("expected a rule name, found {}", token)),
+    };
+    let mut parser = RuleParser { lexer, strings: Vec::new(), subsignatures: Vec::new(), name };
+    let (condition, source) = parser.body().map_err(|e| format!("{}: {}", parser.name, e))?;
+    let strings = parser.strings.into_iter().map(|string| string.parts).collect();
+    let rule = Rule { strings, condition, source };
+    Ok(LogicalSignature { name: parser.name, condition: Condition::Yara(Box::new(rule)), subsignatures: parser.subsignatures })
+}
+
+// Compiles the rules in `source`. A rule that fails to compile is skipped and
+// returned with the line of the error; parsing resumes at the next line that
+// starts a rule.
+pub fn parse_yara_rules(source: &str) -> (Vec<LogicalSignature>, Vec<DbError>) {
+    let mut rules: Vec<LogicalSignature> = Vec::new();
+    let mut errors = Vec::new();
+    let mut lexer = Lexer { src: source, pos: 0, token: 0 };
+    loop {
+        match lexer.peek() {
+            Ok(Token::Eof) => break,
+            Ok(_) => {}
+            Err(message) => {
+                errors.push(DbError { line: lexer.line(), message });
+                break;
+            }
+        }
+        let line = lexer.line();
+        match parse_rule(&mut lexer) {
+            // The rule was read completely, so parsing simply goes on after it.
+            Ok(rule) if rules.iter().any(|other| other.name == rule.name) => {
+                errors.push(DbError { line, message: format!("duplicate rule {}", rule.name) });
+            }
+            Ok(rule) => rules.push(rule),
+            Err(message) => {
+                errors.push(DbError { line: lexer.line(), message });
+                let line_end = lexer.rest().find('\n').map_or(source.len(), |i| lexer.pos + i + 1);
+                lexer.pos = next_rule(source, line_end);
+            }
+        }
+    }
+    (rules, errors)
+}
+
+// Start of the first line at or after `from` that begins a rule.
+fn next_rule(source: &str, from: usize) -> usize {
+    let mut pos = from;
+    for line in source[from..].split_inclusive('\n') {
+        let mut words = line.split_whitespace();
+        let first = words.next();
+        if first == Some("rule") || (matches!(first, Some("private" | "global")) && words.next() == Some("rule")) {
+            return pos;
+        }
+        pos += line.len();
+    }
+    source.len()
+}
+
+pub fn read_yara_rules(path: &str) -> io::Result<(Vec<LogicalSignature>, Vec<DbError>)> {
+    let source = fs::read_to_string(path)?;
+    Ok(parse_yara_rules(&source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_yara_rules;
+    use crate::logical::{Condition, FileFacts, LogicalSignature};
+
+    // Matches every sub-signature at every offset, as a scan would.
+    fn holds(rule: &LogicalSignature, data: &[u8]) -> bool {
+        let hits: Vec<Vec<(u64, u64)>> = rule
+            .subsignatures
+            .iter()
+            .map(|signature| {
+                (0..data.len())
+                    .filter_map(|start| {
+                        let length = signature.pattern.match_at(data, start)?;
+                        Some((start as u64, (start + length) as u64))
+                    })
+                    .collect()
+            })
+            .collect();
+        let Condition::Yara(rule) = &rule.condition else { panic!("not a YARA rule") };
+        rule.holds(&FileFacts { hits: &hits, size: data.len() as u64, header: data })
+    }
+
+    fn rule(source: &str) -> LogicalSignature {
+        let (mut rules, errors) = parse_yara_rules(source);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(rules.len(), 1);
+        rules.remove(0)
+    }
+
+    #[test]
+    fn strings_and_modifiers() {
+        let rule = rule(
+            r#"rule Mixed : tag {
+                meta:
+                    author = "x"
+                    score = -5
+                strings:
+                    $text = "evil" nocase
+                    $wide = "bad" wide ascii
+                    $hex = { 4d 5a [2-4] 90 }
+                condition:
+                    $text and ($wide or $hex)
+            }"#,
+        );
+        assert_eq!(rule.name, "Mixed");
+        // `wide ascii` compiles to two sub-signatures.
+        assert_eq!(rule.subsignatures.len(), 4);
+        assert!(holds(&rule, b"EvIl b\0a\0d\0"));
+        assert!(holds(&rule, b"evil MZ\x00\x00\x00\x90"));
+        assert!(holds(&rule, b"evil bad"));
+        assert!(!holds(&rule, b"evil MZ\x00\x90"));
+        assert!(!holds(&rule, b"bad MZ\x00\x00\x90"));
+    }
+
+    #[test]
+    fn counts_offsets_and_reads() {
+        let rule = rule(
+            r#"rule Header {
+                strings:
+                    $a = "ab"
+                condition:
+                    uint16(0) == 0x5a4d and #a == 2 and @a[2] > @a[1] and $a in (0..10) and filesize < 100
+            }"#,
+        );
+        assert!(holds(&rule, b"MZabxxab"));
+        assert!(!holds(&rule, b"MZab"));
+        assert!(!holds(&rule, b"ZMabab"));
+        assert!(!holds(&rule, b"MZxxxxxxxxxxab ab"));
+    }
+
+    #[test]
+    fn of_quantifiers() {
+        let rule = rule(
+            r#"rule Two {
+                strings:
+                    $x1 = "one"
+                    $x2 = "two"
+                    $y = "three"
+                condition:
+                    2 of ($x*) and not $y
+            }"#,
+        );
+        assert!(holds(&rule, b"one two"));
+        assert!(!holds(&rule, b"one"));
+        assert!(!holds(&rule, b"one two three"));
+    }
+
+    #[test]
+    fn broken_rules_are_skipped() {
+        let source = r#"
+rule Regex {
+    strings:
+        $r = /abc/
+    condition:
+        $r
+}
+rule Good { strings: $a = "x" condition: $a }
+rule Good { condition: true }
+rule Undefined { condition: $missing }
+"#;
+        let (rules, errors) = parse_yara_rules(source);
+        assert_eq!(rules.iter().map(|rule| rule.name.as_str()).collect::<Vec<_>>(), ["Good"]);
+        let lines: Vec<usize> = errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, [4, 9, 10]);
+        assert!(errors[2].message.contains("$missing"));
+        assert!(errors[0].message.contains("regular expressions are not supported"));
+        assert!(errors[1].message.contains("duplicate rule Good"));
+    }
+}